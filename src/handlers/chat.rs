@@ -1,163 +1,1225 @@
-use crate::models::{ChatRequest, ChatResponse, StreamChunk};
-use crate::services::{CacheService, OllamaClient};
+use crate::config::{LimitsConfig, OllamaConfig};
+use crate::handlers::{ApiError, ApiJson};
+use crate::middleware::{record_chat, RateLimiter, RequestId};
+use crate::models::{
+    BulkChatRequest, BulkChatResponse, BulkChatResult, ChatMessage, ChatRequest, ChatResponse,
+    ConversationResponse, OllamaOptions, OllamaResponse, Role, StreamChunk, StreamErrorCode,
+    StreamMeta, StreamRestarted,
+};
+use crate::services::single_flight::FlightGuard;
+use crate::services::{
+    is_transient, stream_error_code, AuditLog, BatchProcessor, CacheService, ConversationStore,
+    Flight, FlightResult, GenerationParams, OllamaClient, OllamaError, SharedReply,
+};
+use crate::utils::context::trim_context;
+use crate::utils::template::{render_prompt, UserWrap};
+use crate::utils::think::{TagStripper, ThinkTags};
+use crate::utils::validation::{
+    validate_messages, validate_model, validate_options, ValidationError,
+};
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Response, Sse},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{sse::Event, IntoResponse, Response, Sse},
     Json,
 };
 use futures::stream::{Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Longest per-chunk delay a request or `server.stream_delay_ms` may ask for, so a slow stream
+/// can't hold a slot forever
+pub const MAX_STREAM_DELAY_MS: u64 = 1000;
 
 pub struct AppState {
     pub cache: CacheService,
-    pub conversation_cache: CacheService,
+    pub conversations: ConversationStore,
     pub ollama: OllamaClient,
+    /// Owns the single-flight table shared with batched requests
+    pub batch: BatchProcessor,
     pub model: String,
-    pub system_prompt: String,
+    /// Default system prompt, replaceable at runtime via `/api/system-prompt`
+    pub system_prompt: SharedSystemPrompt,
+    pub allowed_models: SharedAllowedModels,
+    pub limits: LimitsConfig,
+    pub generation_limit: GenerationLimit,
+    /// Items of one `/api/chat-batch` call processed at once
+    pub bulk_concurrency: usize,
+    /// Reasoning blocks to remove from replies, when enabled
+    pub think_tags: Option<ThinkTags>,
+    /// Interval for SSE keepalive comments while waiting on Ollama
+    pub heartbeat_interval: Option<Duration>,
+    /// Default gap between streamed chunks, `server.stream_delay_ms`
+    pub stream_delay: Duration,
+    pub stream_restart: StreamRestartPolicy,
+    /// End timed-out streams with the partial reply instead of an error
+    pub return_partial_on_timeout: bool,
+    pub user_wrap: UserWrap,
+    /// `ollama.fallback_model`
+    pub fallback_model: Option<String>,
+    pub audit: AuditLog,
+    /// The limiter behind the rate limit middleware, charged again for every WebSocket request
+    pub rate_limiter: RateLimiter,
+}
+
+/// System prompt shared by every handler that falls back to the default
+pub type SharedSystemPrompt = Arc<RwLock<String>>;
+
+/// The system prompt a request runs with: its own or `default`, with placeholders rendered.
+/// Everything that computes a cache key goes through here, so the keys agree.
+pub(crate) fn resolve_system_prompt(
+    default: &str,
+    requested: Option<&str>,
+    vars: Option<&HashMap<String, String>>,
+) -> String {
+    render_prompt(requested.unwrap_or(default), vars)
+}
+
+/// `ollama.allowed_models`, replaced when the config is reloaded; empty allows any
+pub type SharedAllowedModels = Arc<RwLock<Vec<String>>>;
+
+/// How often, and how patiently, a stream that drops mid-reply is restarted
+#[derive(Debug, Clone, Copy)]
+pub struct StreamRestartPolicy {
+    attempts: u32,
+    base_delay: Duration,
+}
+
+impl StreamRestartPolicy {
+    pub fn new(config: &OllamaConfig) -> Self {
+        Self {
+            attempts: config.stream_restart_attempts,
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+        }
+    }
+}
+
+/// Caps how many chat generations run against Ollama at once
+pub struct GenerationLimit {
+    /// `None` when unlimited
+    semaphore: Option<Arc<Semaphore>>,
+    wait: Duration,
+    retry_after_seconds: u64,
+}
+
+impl GenerationLimit {
+    pub fn new(config: &OllamaConfig) -> Self {
+        Self {
+            semaphore: (config.max_concurrent_requests > 0)
+                .then(|| Arc::new(Semaphore::new(config.max_concurrent_requests))),
+            wait: Duration::from_millis(config.concurrency_wait_ms),
+            retry_after_seconds: config.retry_after_seconds,
+        }
+    }
+
+    /// Wait briefly for a slot; the permit must be held until the reply is complete
+    pub(crate) async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, ApiError> {
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(None);
+        };
+
+        match tokio::time::timeout(self.wait, semaphore.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => {
+                tracing::warn!("🚦 All generation slots busy; rejecting request");
+                Err(ApiError::Busy {
+                    detail: "too many concurrent generations, retry later".to_string(),
+                    retry_after_seconds: self.retry_after_seconds,
+                })
+            }
+        }
+    }
+}
+
+/// Where a streamed exchange is recorded once it completes: its session, if any, and the
+/// audit log
+struct ExchangeRecord {
+    store: ConversationStore,
+    session_id: Option<String>,
+    messages: Vec<ChatMessage>,
+    audit: AuditLog,
+}
+
+impl ExchangeRecord {
+    async fn complete(&self, meta: &StreamMeta, reply: &ChatMessage) {
+        self.audit(meta, &reply.content);
+        if let Some(session_id) = &self.session_id {
+            self.store
+                .record_message(session_id, &self.messages, reply.clone())
+                .await;
+        }
+    }
+
+    fn audit(&self, meta: &StreamMeta, response: &str) {
+        self.audit.record(
+            &meta.request_id,
+            &meta.model,
+            &self.messages,
+            response,
+            false,
+        );
+    }
+}
+
+/// Where to store a generated response once it is complete
+#[derive(Clone)]
+pub(crate) struct CacheTarget {
+    cache: CacheService,
+    key: String,
+    /// `CacheService::generate_scope`, for the semantic index
+    scope: String,
+    embedding: Option<Vec<f32>>,
+    /// Per-request TTL overriding the configured one
+    ttl: Option<Duration>,
+    /// When generation started, for `cache.min_generation_ms`
+    started: Instant,
+}
+
+impl CacheTarget {
+    /// Store under `key` with the configured TTL and no semantic entry, timed from now
+    pub(crate) fn new(cache: CacheService, key: String) -> Self {
+        Self {
+            cache,
+            key,
+            scope: String::new(),
+            embedding: None,
+            ttl: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Cache the reply unless it was generated faster than `cache.min_generation_ms` or cut
+    /// off at the token limit; returns whether it was stored
+    pub(crate) async fn store(&self, content: String, done_reason: Option<&str>) -> bool {
+        self.cache.clear_failure(&self.key);
+        let elapsed = self.started.elapsed();
+        let worth_caching = if !self.cache.worth_caching(elapsed) {
+            tracing::debug!("⏩ Not caching reply generated in {:?}", elapsed);
+            false
+        } else if !self.cache.caches_done_reason(done_reason) {
+            tracing::debug!("⏩ Not caching reply cut off at the token limit");
+            false
+        } else {
+            true
+        };
+        if !worth_caching {
+            // Don't leave an older reply to be served in place of this one
+            self.cache.invalidate_key(&self.key).await;
+            return false;
+        }
+
+        self.cache
+            .set_with_ttl(self.key.clone(), content, self.ttl)
+            .await;
+        if let Some(embedding) = &self.embedding {
+            self.cache
+                .add_embedding(self.key.clone(), self.scope.clone(), embedding.clone())
+                .await;
+        }
+        true
+    }
+
+    /// Remember a failure caused by Ollama being down, so identical requests fail fast
+    pub(crate) fn record_failure(&self, error: &anyhow::Error) {
+        if is_transient(error) {
+            self.cache.record_failure(&self.key, error.to_string());
+        }
+    }
+}
+
+/// Whether Ollama stopped sending output or did not finish in time
+fn is_timeout(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<OllamaError>(),
+        Some(OllamaError::Timeout(_)) | Some(OllamaError::DeadlineExceeded(_))
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatQuery {
+    q: Option<String>,
+    model: Option<String>,
 }
 
 /// Handle optimized chat request with caching
 pub async fn chat_optimized(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<ChatRequest>,
-) -> Result<Response, StatusCode> {
+    request_id: RequestId,
+    headers: HeaderMap,
+    ApiJson(request): ApiJson<ChatRequest>,
+) -> Result<Response, ApiError> {
+    let cache_mode = CacheMode::new(request.use_cache, request.regenerate, &headers);
+    respond(state, request, request_id, cache_mode).await
+}
+
+/// `GET /api/chat-optimized?q=...` for quick tests: one prompt, non-streaming
+pub async fn chat_query(
+    State(state): State<Arc<AppState>>,
+    request_id: RequestId,
+    headers: HeaderMap,
+    Query(params): Query<ChatQuery>,
+) -> Result<Response, ApiError> {
+    let prompt = params
+        .q
+        .filter(|q| !q.is_empty())
+        .ok_or_else(|| ApiError::BadRequest("q is required".to_string()))?;
+    let request = ChatRequest::from_prompt(prompt, params.model);
+    let cache_mode = CacheMode::new(request.use_cache, request.regenerate, &headers);
+    respond(state, request, request_id, cache_mode).await
+}
+
+/// Whether a chat request may be answered from the response cache, and may store its reply
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CacheMode {
+    read: bool,
+    write: bool,
+}
+
+impl CacheMode {
+    const BYPASS: Self = Self {
+        read: false,
+        write: false,
+    };
+
+    /// From the body's `use_cache` and `regenerate`, overridden by `Cache-Control: no-cache`
+    /// (generate afresh but store the reply, like `regenerate`) and `no-store` (leave the
+    /// cache alone entirely)
+    pub(crate) fn new(use_cache: bool, regenerate: bool, headers: &HeaderMap) -> Self {
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim().to_ascii_lowercase());
+
+        let mut mode = Self {
+            read: use_cache && !regenerate,
+            write: use_cache || regenerate,
+        };
+        for directive in directives {
+            match directive.as_str() {
+                "no-store" => return Self::BYPASS,
+                "no-cache" => {
+                    mode = Self {
+                        read: false,
+                        write: true,
+                    }
+                }
+                _ => {}
+            }
+        }
+        mode
+    }
+}
+
+async fn respond(
+    state: Arc<AppState>,
+    request: ChatRequest,
+    request_id: RequestId,
+    cache_mode: CacheMode,
+) -> Result<Response, ApiError> {
+    Ok(reply(state, request, request_id, cache_mode)
+        .await?
+        .into_response())
+}
+
+/// Answer a chat request, from the cache or from Ollama
+pub(crate) async fn reply(
+    state: Arc<AppState>,
+    request: ChatRequest,
+    request_id: RequestId,
+    cache_mode: CacheMode,
+) -> Result<Reply, ApiError> {
+    validate_messages(&request.messages, &state.limits)?;
+
     let model = request.model.as_ref().unwrap_or(&state.model);
-    let system_prompt = request
-        .system_prompt
-        .as_ref()
-        .unwrap_or(&state.system_prompt);
+    validate_model(model, &state.allowed_models.read().await)?;
+    // Rendered before keying the cache, so replies for different dates don't collide
+    let system_prompt = resolve_system_prompt(
+        &state.system_prompt.read().await,
+        request.system_prompt.as_deref(),
+        request.template_vars.as_ref(),
+    );
+
+    // Prepend stored history for this session
+    let mut messages = match &request.session_id {
+        Some(session_id) => {
+            let mut messages = state.conversations.history(session_id).await;
+            messages.extend_from_slice(&request.messages);
+            messages
+        }
+        None => request.messages.clone(),
+    };
+    // Wrapped before keying the cache; the session keeps the client's own messages
+    state.user_wrap.apply(&mut messages);
+    let dropped = trim_context(&mut messages, &system_prompt, &state.limits);
+    if dropped > 0 {
+        tracing::debug!("✂️  Dropped {} messages to fit the context limits", dropped);
+    }
+    let dropped = (dropped > 0).then_some(dropped);
+    let exempt = state.cache.is_exempt(&messages);
+    let cache_mode = if exempt {
+        tracing::debug!("🚫 Request matches cache.exempt_patterns; bypassing the cache");
+        CacheMode::BYPASS
+    } else {
+        cache_mode
+    };
+    let stream_delay = request
+        .stream_delay_ms
+        .map(|ms| Duration::from_millis(ms.min(MAX_STREAM_DELAY_MS)))
+        .unwrap_or(state.stream_delay);
+
+    let request_options = request.generation_options();
+    validate_options(request_options.as_ref(), &state.limits)?;
+    let options = state.ollama.resolve_options(request_options.as_ref());
+    let mut params = GenerationParams {
+        options: request_options.as_ref(),
+        format: request.format.as_deref(),
+        tools: request.tools.as_deref(),
+        keep_alive: request.keep_alive.as_deref(),
+        deadline: None,
+    };
+    let cache_key = state.cache.generate_key(
+        &messages,
+        model,
+        &system_prompt,
+        &options,
+        params.format,
+        params.tools,
+    );
+    // Semantic matches are only looked for among requests with the same settings
+    let scope =
+        state
+            .cache
+            .generate_scope(model, &system_prompt, &options, params.format, params.tools);
+    let mut embedding = None;
 
     // Check cache first
-    if request.use_cache {
-        let cache_key = CacheService::generate_key(&request.messages, model);
+    if cache_mode.read || (request.cache_only && !exempt) {
+        // Fall back to a semantically similar prompt on an exact-match miss. Text embeddings
+        // can't tell images apart, so conversations with images only match exactly.
+        let has_images = messages.iter().any(ChatMessage::has_images);
+        let semantic = state.cache.semantic_enabled() && !has_images;
+        let mut cached = state.cache.lookup(&cache_key).await;
+        if cached.is_none() && semantic {
+            let embedding_model = state.cache.embedding_model(model);
+            match state
+                .ollama
+                .generate_embedding(embedding_model, &prompt_text(&messages))
+                .await
+            {
+                Ok(prompt_embedding) => {
+                    cached = state.cache.find_similar(&scope, &prompt_embedding).await;
+                    embedding = Some(prompt_embedding);
+                }
+                Err(e) => tracing::warn!("Semantic cache lookup failed: {}", e),
+            }
+        }
+        state.cache.record_lookup(&cache_key, cached.is_some());
 
-        if let Some(cached) = state.cache.get(&cache_key).await {
+        if let Some(cached) = cached {
             tracing::info!("✅ Serving from cache");
+            record_chat(model, true);
+            state
+                .audit
+                .record(&request_id.0, model, &request.messages, &cached, true);
+
+            if let Some(session_id) = &request.session_id {
+                state
+                    .conversations
+                    .record_exchange(session_id, &request.messages, &cached)
+                    .await;
+            }
 
             if request.stream {
                 // Stream cached response
-                let stream = stream_cached_response(cached, None);
-                return Ok(Sse::new(stream).into_response());
+                let meta = stream_meta(true, model, request_id, dropped);
+                let stream = stream_cached_response(cached, meta, stream_delay);
+                return Ok(Reply::Stream(Box::pin(stream)));
             } else {
                 let response = ChatResponse {
-                    message: crate::models::ChatMessage {
-                        role: "assistant".to_string(),
+                    message: ChatMessage {
+                        role: Role::Assistant,
                         content: cached,
+                        tool_calls: None,
+                        images: None,
                     },
                     cached: Some(true),
+                    dropped_messages: dropped,
+                    fallback_used: None,
+                    messages: None,
+                    done_reason: None,
                 };
-                return Ok(Json(response).into_response());
+                return Ok(json_reply(&request, response).into());
             }
         }
     }
 
+    record_chat(model, false);
+
+    if request.cache_only {
+        tracing::debug!("Cache-only request missed; not calling Ollama");
+        return Ok(StatusCode::NO_CONTENT.into_response().into());
+    }
+
+    // Ollama failed on this exact request moments ago; don't wait on it again
+    if cache_mode.read {
+        if let Some(error) = state.cache.recent_failure(&cache_key) {
+            tracing::warn!("⛔ Failing fast on a request that just failed upstream");
+            return Err(ApiError::UpstreamUnavailable(error));
+        }
+    }
+
+    // Collapse identical in-flight requests into one upstream call
+    let flight = if cache_mode.read {
+        match state.batch.join_flight(&cache_key).await {
+            Flight::Leader(guard) => Some(guard),
+            Flight::Follower(waiter) => match waiter.wait().await {
+                Some(result) => {
+                    return shared_response(&state, &request, request_id, dropped, result).await
+                }
+                // The leader gave up without a result, so run the request ourselves
+                None => None,
+            },
+        }
+    } else {
+        None
+    };
+
+    // Only generations count against the limit; cache hits and followers are already served
+    let permit = state.generation_limit.acquire().await?;
+    // One budget for the whole reply, whether it falls back to another model or restarts
+    params.deadline = state.ollama.generation_deadline();
+
+    // A TTL of 0 still reads from the cache but never stores the reply
+    let cache_ttl = request.cache_ttl_seconds.map(Duration::from_secs);
+    let store_reply = cache_mode.write && cache_ttl != Some(Duration::ZERO);
+    let mut cache_target = store_reply.then(|| CacheTarget {
+        cache: state.cache.clone(),
+        key: cache_key.clone(),
+        scope: scope.clone(),
+        embedding,
+        ttl: cache_ttl,
+        started: Instant::now(),
+    });
+
+    // Replies from the fallback model are cached under its own key
+    let retarget = |target: &mut CacheTarget, fallback: &String| {
+        target.key = state.cache.generate_key(
+            &messages,
+            fallback,
+            &system_prompt,
+            &options,
+            params.format,
+            params.tools,
+        );
+        target.scope = state.cache.generate_scope(
+            fallback,
+            &system_prompt,
+            &options,
+            params.format,
+            params.tools,
+        );
+    };
+
     // Cache miss - fetch from Ollama
     if request.stream {
-        match state
+        let mut started = state
             .ollama
-            .chat_completion_stream(&request.messages, model, system_prompt)
-            .await
-        {
+            .chat_completion_stream(&messages, model, &system_prompt, params)
+            .await;
+        let fallback = fallback_model(&state, model, &started);
+        if let Some(fallback) = fallback {
+            started = state
+                .ollama
+                .chat_completion_stream(&messages, fallback, &system_prompt, params)
+                .await;
+            if let Some(target) = cache_target.as_mut() {
+                retarget(target, fallback);
+            }
+        }
+        let model = fallback.unwrap_or(model);
+
+        match started {
             Ok(ollama_stream) => {
-                let cache = state.cache.clone();
-                let cache_key = CacheService::generate_key(&request.messages, model);
-                let use_cache = request.use_cache;
+                // Ollama is answering again, even if this reply won't be cached
+                state.cache.clear_failure(&cache_key);
+                let ollama_stream = match state.think_tags.clone() {
+                    Some(tags) => strip_reasoning(ollama_stream, tags),
+                    None => ollama_stream,
+                };
+                // Sending the request again could repeat tool calls
+                let restart = request.tools.is_none().then(|| StreamRestart {
+                    ollama: state.ollama.clone(),
+                    messages: messages.clone(),
+                    model: model.clone(),
+                    system_prompt: system_prompt.clone(),
+                    options: request_options.clone(),
+                    format: request.format.clone(),
+                    keep_alive: request.keep_alive.clone(),
+                    think_tags: state.think_tags.clone(),
+                    attempts_left: state.stream_restart.attempts,
+                    delay: state.stream_restart.base_delay,
+                    deadline: params.deadline,
+                });
+                let upstream = Upstream {
+                    stream: ollama_stream,
+                    restart,
+                    partial_on_timeout: state.return_partial_on_timeout,
+                    _permit: permit,
+                };
+                let exchange = ExchangeRecord {
+                    store: state.conversations.clone(),
+                    session_id: request.session_id.clone(),
+                    messages: request.messages.clone(),
+                    audit: state.audit.clone(),
+                };
 
-                let stream = stream_ollama_response(ollama_stream, cache, cache_key, use_cache);
-                Ok(Sse::new(stream).into_response())
+                let mut meta = stream_meta(false, model, request_id, dropped);
+                meta.fallback_used = fallback.is_some().then_some(true);
+                let stream = stream_ollama_response(
+                    upstream,
+                    meta,
+                    cache_target,
+                    Some(exchange),
+                    flight,
+                    request.wants_json(),
+                    StreamTiming {
+                        heartbeat: state.heartbeat_interval,
+                        chunk_delay: stream_delay,
+                    },
+                );
+                Ok(Reply::Stream(Box::pin(stream)))
             }
             Err(e) => {
                 tracing::error!("Ollama streaming error: {}", e);
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
+                if let Some(target) = &cache_target {
+                    target.record_failure(&e);
+                }
+                let e = Arc::new(e);
+                if let Some(guard) = flight {
+                    guard.complete(Err(e.clone()));
+                }
+                Err(e.into())
             }
         }
     } else {
-        match state
+        let mut result = state
             .ollama
-            .chat_completion(&request.messages, model, system_prompt, false)
-            .await
-        {
-            Ok(content) => {
+            .chat_response(&messages, model, &system_prompt, params, false)
+            .await;
+        let fallback = fallback_model(&state, model, &result);
+        if let Some(fallback) = fallback {
+            result = state
+                .ollama
+                .chat_response(&messages, fallback, &system_prompt, params, false)
+                .await;
+            if let Some(target) = cache_target.as_mut() {
+                retarget(target, fallback);
+            }
+        }
+        let fallback_used = fallback.is_some();
+        let done_reason = result.as_ref().ok().and_then(|r| r.done_reason.clone());
+        if result.is_ok() {
+            // Ollama is answering again, even if this reply won't be cached
+            state.cache.clear_failure(&cache_key);
+        }
+
+        let result = result
+            .map(OllamaResponse::into_message)
+            .map(|mut message| {
+                if let Some(tags) = &state.think_tags {
+                    message.content = tags.strip(&message.content);
+                }
+                message
+            })
+            .and_then(|message| {
+                // Tool calls are returned as-is; only plain replies must be JSON
+                if request.wants_json()
+                    && !message.has_tool_calls()
+                    && !is_valid_json(&message.content)
+                {
+                    tracing::error!("Ollama returned malformed JSON for a json-format request");
+                    return Err(OllamaError::InvalidResponse(
+                        "reply is not valid JSON".to_string(),
+                    )
+                    .into());
+                }
+                Ok(message)
+            })
+            .map_err(Arc::new);
+
+        if let Some(guard) = flight {
+            guard.complete(result.clone().map(|message| SharedReply {
+                message,
+                done_reason: done_reason.clone(),
+                fallback_used,
+            }));
+        }
+
+        match result {
+            // Tool calls depend on live tool results, so they are never cached
+            Ok(message) if message.has_tool_calls() => {
+                let model = fallback.unwrap_or(model);
+                state.audit.record(
+                    &request_id.0,
+                    model,
+                    &request.messages,
+                    &message.content,
+                    false,
+                );
+                if let Some(session_id) = &request.session_id {
+                    state
+                        .conversations
+                        .record_message(session_id, &request.messages, message.clone())
+                        .await;
+                }
+
+                let response = ChatResponse {
+                    message,
+                    cached: Some(false),
+                    dropped_messages: dropped,
+                    fallback_used: fallback_used.then_some(true),
+                    messages: None,
+                    done_reason,
+                };
+                Ok(json_reply(&request, response).into())
+            }
+            Ok(ChatMessage { content, .. }) => {
                 // Cache the response
-                if request.use_cache {
-                    let cache_key = CacheService::generate_key(&request.messages, model);
-                    state.cache.set(cache_key, content.clone()).await;
+                if let Some(target) = &cache_target {
+                    target.store(content.clone(), done_reason.as_deref()).await;
+                }
+                let model = fallback.unwrap_or(model);
+                state
+                    .audit
+                    .record(&request_id.0, model, &request.messages, &content, false);
+
+                if let Some(session_id) = &request.session_id {
+                    state
+                        .conversations
+                        .record_exchange(session_id, &request.messages, &content)
+                        .await;
                 }
 
                 let response = ChatResponse {
-                    message: crate::models::ChatMessage {
-                        role: "assistant".to_string(),
+                    message: ChatMessage {
+                        role: Role::Assistant,
                         content,
+                        tool_calls: None,
+                        images: None,
                     },
                     cached: Some(false),
+                    dropped_messages: dropped,
+                    fallback_used: fallback_used.then_some(true),
+                    messages: None,
+                    done_reason,
                 };
-                Ok(Json(response).into_response())
+                Ok(json_reply(&request, response).into())
             }
             Err(e) => {
                 tracing::error!("Ollama error: {}", e);
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
+                if let Some(target) = &cache_target {
+                    target.record_failure(&e);
+                }
+                Err(e.into())
             }
         }
     }
 }
 
+/// `ollama.fallback_model`, when `result` failed because `model` could not be loaded
+fn fallback_model<'a, T>(
+    state: &'a AppState,
+    model: &str,
+    result: &anyhow::Result<T>,
+) -> Option<&'a String> {
+    let fallback = state.fallback_model.as_ref().filter(|f| *f != model)?;
+    let Err(e) = result else {
+        return None;
+    };
+    if !e.downcast_ref().is_some_and(OllamaError::is_load_failure) {
+        return None;
+    }
+    tracing::warn!(
+        "↩️  Model {} failed to load ({}); retrying with {}",
+        model,
+        e,
+        fallback
+    );
+    Some(fallback)
+}
+
+/// Answer a request with the result of an identical request that was already running
+async fn shared_response(
+    state: &AppState,
+    request: &ChatRequest,
+    request_id: RequestId,
+    dropped: Option<usize>,
+    result: FlightResult<SharedReply>,
+) -> Result<Reply, ApiError> {
+    let SharedReply {
+        message,
+        done_reason,
+        fallback_used,
+    } = result?;
+    let model = request.model.as_ref().unwrap_or(&state.model);
+    state.audit.record(
+        &request_id.0,
+        model,
+        &request.messages,
+        &message.content,
+        false,
+    );
+
+    if let Some(session_id) = &request.session_id {
+        state
+            .conversations
+            .record_message(session_id, &request.messages, message.clone())
+            .await;
+    }
+
+    if request.stream {
+        let mut meta = stream_meta(false, model, request_id, dropped);
+        meta.fallback_used = fallback_used.then_some(true);
+        let request_id = Some(meta.request_id.clone());
+        let chunks = [
+            StreamChunk {
+                content: Some(message.content),
+                done: false,
+                request_id: request_id.clone(),
+                cached: Some(false),
+                error: None,
+                error_code: None,
+                tool_calls: message.tool_calls,
+                truncated: None,
+                done_reason: None,
+            },
+            StreamChunk {
+                content: None,
+                done: true,
+                request_id,
+                cached: Some(false),
+                error: None,
+                error_code: None,
+                tool_calls: None,
+                truncated: None,
+                done_reason,
+            },
+        ];
+        let events = std::iter::once(StreamEvent::Meta(meta)).chain(chunks.map(StreamEvent::Chunk));
+        Ok(Reply::Stream(Box::pin(futures::stream::iter(events))))
+    } else {
+        let response = ChatResponse {
+            message,
+            cached: Some(false),
+            dropped_messages: dropped,
+            fallback_used: fallback_used.then_some(true),
+            messages: None,
+            done_reason,
+        };
+        Ok(json_reply(request, response).into())
+    }
+}
+
+/// A non-streaming reply, echoing the conversation when the request asked for it
+fn json_reply(request: &ChatRequest, mut response: ChatResponse) -> Response {
+    if request.include_messages {
+        response.echo_messages(&request.messages);
+    }
+    Json(response).into_response()
+}
+
+/// Flatten a conversation into the text used for semantic cache embeddings
+fn prompt_text(messages: &[ChatMessage]) -> String {
+    messages
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.content))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Check that a reply requested with `format: "json"` actually parses as JSON
+fn is_valid_json(content: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(content).is_ok()
+}
+
+/// Wait for the next heartbeat tick, or forever when heartbeats are disabled
+async fn next_heartbeat(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Metadata for a new stream, so clients know its origin before the first chunk
+fn stream_meta(
+    cached: bool,
+    model: &str,
+    RequestId(request_id): RequestId,
+    dropped_messages: Option<usize>,
+) -> StreamMeta {
+    StreamMeta {
+        cached,
+        model: model.to_string(),
+        request_id,
+        dropped_messages,
+        fallback_used: None,
+    }
+}
+
+/// One message of a streamed reply, before it is encoded for SSE or a WebSocket
+#[derive(Debug, Clone)]
+pub(crate) enum StreamEvent {
+    Meta(StreamMeta),
+    Chunk(StreamChunk),
+    Restarted(StreamRestarted),
+    /// Sent while waiting on Ollama to keep the connection open
+    Keepalive,
+}
+
+impl StreamEvent {
+    fn into_sse(self) -> Result<Event, Infallible> {
+        let event = match self {
+            StreamEvent::Meta(meta) => Event::default()
+                .event("meta")
+                .data(serde_json::to_string(&meta).unwrap()),
+            StreamEvent::Chunk(chunk) => {
+                Event::default().data(serde_json::to_string(&chunk).unwrap())
+            }
+            StreamEvent::Restarted(notice) => Event::default()
+                .event("restart")
+                .data(serde_json::to_string(&notice).unwrap()),
+            // SSE comments keep the connection alive and are ignored by clients
+            StreamEvent::Keepalive => Event::default().comment("keepalive"),
+        };
+        Ok(event)
+    }
+}
+
+pub(crate) type EventStream = std::pin::Pin<Box<dyn Stream<Item = StreamEvent> + Send>>;
+
+/// A chat reply before it is sent to the client
+pub(crate) enum Reply {
+    /// A finished response: JSON for non-streaming requests, or no content
+    Response(Response),
+    Stream(EventStream),
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Self {
+        Reply::Response(response)
+    }
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> Response {
+        match self {
+            Reply::Response(response) => response,
+            Reply::Stream(events) => Sse::new(events.map(StreamEvent::into_sse)).into_response(),
+        }
+    }
+}
+
 /// Stream cached response word by word for smooth UX
 fn stream_cached_response(
     content: String,
-    request_id: Option<String>,
-) -> impl Stream<Item = Result<axum::response::sse::Event, Infallible>> {
-    let words: Vec<String> = content.split_whitespace().map(|s| s.to_string()).collect();
-    let total = words.len();
-    let request_id_clone = request_id.clone();
+    meta: StreamMeta,
+    chunk_delay: Duration,
+) -> impl Stream<Item = StreamEvent> {
+    async_stream::stream! {
+        let request_id = Some(meta.request_id.clone());
+        yield StreamEvent::Meta(meta.clone());
 
-    futures::stream::iter(words.into_iter().enumerate().map(move |(i, word)| {
-        let chunk = StreamChunk {
-            content: Some(if i < total - 1 {
-                format!("{} ", word)
-            } else {
-                word
-            }),
-            done: false,
-            request_id: request_id_clone.clone(),
-            cached: Some(true),
-            error: None,
-        };
+        let mut pacer = Pacer::new(chunk_delay);
+        let words: Vec<&str> = content.split_whitespace().collect();
+        for (i, word) in words.iter().enumerate() {
+            pacer.wait().await;
+            let chunk = StreamChunk {
+                content: Some(if i < words.len() - 1 {
+                    format!("{} ", word)
+                } else {
+                    word.to_string()
+                }),
+                done: false,
+                request_id: request_id.clone(),
+                cached: Some(true),
+                error: None,
+                error_code: None,
+                tool_calls: None,
+                truncated: None,
+                done_reason: None,
+            };
+
+            yield StreamEvent::Chunk(chunk);
+        }
 
-        let json = serde_json::to_string(&chunk).unwrap();
-        Ok(axum::response::sse::Event::default().data(json))
-    }))
-    .chain(futures::stream::once(async move {
         let chunk = StreamChunk {
             content: None,
             done: true,
             request_id,
             cached: Some(true),
             error: None,
+            error_code: None,
+            tool_calls: None,
+            truncated: None,
+            done_reason: None,
         };
 
-        let json = serde_json::to_string(&chunk).unwrap();
-        Ok(axum::response::sse::Event::default().data(json))
+        yield StreamEvent::Chunk(chunk);
+    }
+}
+
+/// Spaces streamed chunks at least `delay` apart; time spent waiting on Ollama counts
+/// towards the gap
+struct Pacer {
+    delay: Duration,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    fn new(delay: Duration) -> Self {
+        Self { delay, next: None }
+    }
+
+    /// Wait until the next chunk may be sent
+    async fn wait(&mut self) {
+        if self.delay.is_zero() {
+            return;
+        }
+        if let Some(next) = self.next {
+            tokio::time::sleep_until(next).await;
+        }
+        self.next = Some(Instant::now() + self.delay);
+    }
+}
+
+/// Keepalive and pacing settings for one streamed reply
+#[derive(Debug, Clone, Copy, Default)]
+struct StreamTiming {
+    /// Interval for keepalive comments while waiting on Ollama
+    heartbeat: Option<Duration>,
+    /// Minimum gap between content chunks
+    chunk_delay: Duration,
+}
+
+impl StreamTiming {
+    /// When chunks are paced, keep reading Ollama's stream at its own speed in the background
+    /// and buffer what the client isn't ready for, so the pacing never counts against the
+    /// request timeout, `stream_idle_timeout_seconds` or `max_generation_seconds`. Dropping
+    /// the returned stream still drops the upstream request.
+    fn read_ahead(&self, mut stream: OllamaStream) -> OllamaStream {
+        if self.chunk_delay.is_zero() {
+            return stream;
+        }
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let next = tokio::select! {
+                    next = stream.next() => next,
+                    _ = sender.closed() => break,
+                };
+                let Some(item) = next else {
+                    break;
+                };
+                if sender.send(item).is_err() {
+                    break;
+                }
+            }
+        });
+        Box::pin(async_stream::stream! {
+            while let Some(item) = receiver.recv().await {
+                yield item;
+            }
+        })
+    }
+}
+
+pub(crate) type OllamaStream =
+    std::pin::Pin<Box<dyn Stream<Item = anyhow::Result<crate::models::OllamaResponse>> + Send>>;
+
+/// Remove reasoning blocks from Ollama's stream before they are forwarded or cached
+pub(crate) fn strip_reasoning(stream: OllamaStream, tags: ThinkTags) -> OllamaStream {
+    let mut stripper = TagStripper::new(tags);
+    Box::pin(stream.filter_map(move |item| {
+        let item = item.map(|mut response| {
+            if let Some(message) = &mut response.message {
+                message.content = stripper.push(&message.content);
+            }
+            if response.done {
+                let rest = stripper.finish();
+                if !rest.is_empty() {
+                    let message = response.message.get_or_insert_with(|| ChatMessage {
+                        role: Role::Assistant,
+                        content: String::new(),
+                        tool_calls: None,
+                        images: None,
+                    });
+                    message.content.push_str(&rest);
+                }
+            }
+            response
+        });
+
+        // Chunks swallowed entirely by a reasoning block are not forwarded
+        let swallowed = match &item {
+            Ok(response) => {
+                !response.done
+                    && response
+                        .message
+                        .as_ref()
+                        .is_some_and(|m| m.content.is_empty() && m.tool_calls.is_none())
+            }
+            Err(_) => false,
+        };
+        futures::future::ready((!swallowed).then_some(item))
     }))
 }
 
-/// Stream Ollama response and cache it
+/// Ollama's stream for one reply, and what is needed to start it over if it drops
+struct Upstream {
+    stream: OllamaStream,
+    restart: Option<StreamRestart>,
+    /// On a timeout, finish with the content so far instead of an error
+    partial_on_timeout: bool,
+    /// Generation slot, held until the reply finishes or the client goes away
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl From<OllamaStream> for Upstream {
+    fn from(stream: OllamaStream) -> Self {
+        Self {
+            stream,
+            restart: None,
+            partial_on_timeout: false,
+            _permit: None,
+        }
+    }
+}
+
+/// Everything needed to send a streamed chat request again
+struct StreamRestart {
+    ollama: OllamaClient,
+    messages: Vec<ChatMessage>,
+    model: String,
+    system_prompt: String,
+    options: Option<OllamaOptions>,
+    format: Option<String>,
+    keep_alive: Option<String>,
+    think_tags: Option<ThinkTags>,
+    attempts_left: u32,
+    /// Doubles after every attempt
+    delay: Duration,
+    /// The reply's generation deadline, which a restart does not extend
+    deadline: Option<Instant>,
+}
+
+impl StreamRestart {
+    /// After a transient failure, back off and open a fresh stream; `None` when the error is
+    /// not worth retrying or the attempts are used up
+    async fn reopen(&mut self, error: &anyhow::Error) -> Option<anyhow::Result<OllamaStream>> {
+        if !is_transient(error) || self.attempts_left == 0 {
+            return None;
+        }
+        self.attempts_left -= 1;
+        tokio::time::sleep(self.delay).await;
+        self.delay *= 2;
+
+        let params = GenerationParams {
+            options: self.options.as_ref(),
+            format: self.format.as_deref(),
+            tools: None,
+            keep_alive: self.keep_alive.as_deref(),
+            deadline: self.deadline,
+        };
+        let result = self
+            .ollama
+            .chat_completion_stream(&self.messages, &self.model, &self.system_prompt, params)
+            .await;
+        Some(result.map(|stream| match self.think_tags.clone() {
+            Some(tags) => strip_reasoning(stream, tags),
+            None => stream,
+        }))
+    }
+}
+
+/// Notes when a stream is dropped before Ollama finished, i.e. the client disconnected
+struct DisconnectGuard {
+    finished: bool,
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::info!("🔌 Client disconnected mid-stream; discarding partial response");
+        }
+    }
+}
+
+/// Stream Ollama response and cache it.
+///
+/// axum drops this stream when the client disconnects, which also drops the upstream
+/// request and stops Ollama generating. Only a genuine `done` event caches the reply,
+/// so truncated answers are never stored.
 fn stream_ollama_response(
-    mut ollama_stream: std::pin::Pin<
-        Box<dyn Stream<Item = anyhow::Result<crate::models::OllamaResponse>> + Send>,
-    >,
-    cache: CacheService,
-    cache_key: String,
-    use_cache: bool,
-) -> impl Stream<Item = Result<axum::response::sse::Event, Infallible>> {
+    mut upstream: Upstream,
+    meta: StreamMeta,
+    cache_target: Option<CacheTarget>,
+    exchange: Option<ExchangeRecord>,
+    mut flight: Option<FlightGuard<SharedReply>>,
+    validate_json: bool,
+    timing: StreamTiming,
+) -> impl Stream<Item = StreamEvent> {
     let accumulated = Arc::new(tokio::sync::Mutex::new(String::new()));
+    let mut tool_calls: Vec<serde_json::Value> = Vec::new();
+
+    upstream.stream = timing.read_ahead(upstream.stream);
+    let mut pacer = Pacer::new(timing.chunk_delay);
+    let mut heartbeat = timing.heartbeat.map(|period| {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
 
     async_stream::stream! {
-        while let Some(result) = ollama_stream.next().await {
+        let mut guard = DisconnectGuard { finished: false };
+        let request_id = Some(meta.request_id.clone());
+        yield StreamEvent::Meta(meta.clone());
+
+        loop {
+            let next = tokio::select! {
+                next = upstream.stream.next() => Some(next),
+                _ = next_heartbeat(&mut heartbeat) => None,
+            };
+
+            let Some(next) = next else {
+                yield StreamEvent::Keepalive;
+                continue;
+            };
+            // Ending before `done` means the reply was cut off, so it fails like a dropped stream
+            let result = next.unwrap_or_else(|| Err(OllamaError::stream_ended().into()));
+            if let Some(heartbeat) = &mut heartbeat {
+                heartbeat.reset();
+            }
+
             match result {
                 Ok(ollama_response) => {
                     if let Some(message) = &ollama_response.message {
+                        if let Some(calls) = &message.tool_calls {
+                            tool_calls.extend(calls.iter().cloned());
+                        }
+
+                        if !message.content.is_empty() {
+                            pacer.wait().await;
+                        }
+
                         // Accumulate content
                         let mut acc = accumulated.lock().await;
                         acc.push_str(&message.content);
@@ -165,51 +1227,1386 @@ fn stream_ollama_response(
                         let chunk = StreamChunk {
                             content: Some(message.content.clone()),
                             done: false,
-                            request_id: None,
+                            request_id: request_id.clone(),
                             cached: Some(false),
                             error: None,
+                            error_code: None,
+                            tool_calls: message.tool_calls.clone(),
+                            truncated: None,
+                            done_reason: None,
                         };
 
-                        let json = serde_json::to_string(&chunk).unwrap();
-                        yield Ok(axum::response::sse::Event::default().data(json));
+                        yield StreamEvent::Chunk(chunk);
                     }
 
                     if ollama_response.done {
-                        // Cache the complete response
-                        if use_cache {
+                        // Malformed JSON is reported to the client and never cached
+                        let has_tool_calls = !tool_calls.is_empty();
+                        if validate_json
+                            && !has_tool_calls
+                            && !is_valid_json(&accumulated.lock().await)
+                        {
+                            tracing::error!(
+                                "Ollama returned malformed JSON for a json-format request"
+                            );
+                            if let Some(flight) = flight.take() {
+                                let error = OllamaError::InvalidResponse(
+                                    "reply is not valid JSON".to_string(),
+                                );
+                                flight.complete(Err(Arc::new(error.into())));
+                            }
+                            let chunk = StreamChunk {
+                                content: None,
+                                done: true,
+                                request_id: request_id.clone(),
+                                cached: Some(false),
+                                error: Some("Ollama returned malformed JSON".to_string()),
+                                error_code: Some(StreamErrorCode::ParseError),
+                                tool_calls: None,
+                                truncated: None,
+                                done_reason: None,
+                            };
+
+                            guard.finished = true;
+                            yield StreamEvent::Chunk(chunk);
+                            break;
+                        }
+
+                        // Cache the complete response; tool calls depend on live results
+                        let done_reason = ollama_response.done_reason;
+                        if let Some(target) = cache_target.as_ref().filter(|_| !has_tool_calls) {
                             let acc = accumulated.lock().await;
-                            cache.set(cache_key.clone(), acc.clone()).await;
-                            tracing::info!("💾 Cached streaming response");
+                            if target.store(acc.clone(), done_reason.as_deref()).await {
+                                tracing::info!("💾 Cached streaming response");
+                            }
+                        }
+
+                        let reply = ChatMessage {
+                            role: Role::Assistant,
+                            content: accumulated.lock().await.clone(),
+                            tool_calls: has_tool_calls.then(|| tool_calls.clone()),
+                            images: None,
+                        };
+                        if let Some(exchange) = &exchange {
+                            exchange.complete(&meta, &reply).await;
+                        }
+                        if let Some(flight) = flight.take() {
+                            flight.complete(Ok(SharedReply {
+                                message: reply,
+                                done_reason: done_reason.clone(),
+                                fallback_used: meta.fallback_used == Some(true),
+                            }));
                         }
+                        guard.finished = true;
 
                         let chunk = StreamChunk {
                             content: None,
                             done: true,
-                            request_id: None,
+                            request_id: request_id.clone(),
                             cached: Some(false),
                             error: None,
+                            error_code: None,
+                            tool_calls: None,
+                            truncated: None,
+                            done_reason,
                         };
 
-                        let json = serde_json::to_string(&chunk).unwrap();
-                        yield Ok(axum::response::sse::Event::default().data(json));
+                        yield StreamEvent::Chunk(chunk);
                         break;
                     }
                 }
+                Err(e) if upstream.partial_on_timeout && is_timeout(&e) => {
+                    tracing::warn!("⏱️  Stream timed out ({}); returning partial reply", e);
+                    if let Some(target) = &cache_target {
+                        target.record_failure(&e);
+                    }
+                    if let Some(flight) = flight.take() {
+                        flight.complete(Err(Arc::new(e)));
+                    }
+                    guard.finished = true;
+                    let partial = accumulated.lock().await.clone();
+                    if let Some(exchange) = &exchange {
+                        exchange.audit(&meta, &partial);
+                    }
+
+                    let chunk = StreamChunk {
+                        content: Some(partial),
+                        done: true,
+                        request_id: request_id.clone(),
+                        cached: Some(false),
+                        error: None,
+                        error_code: None,
+                        tool_calls: None,
+                        truncated: Some(true),
+                        done_reason: None,
+                    };
+
+                    yield StreamEvent::Chunk(chunk);
+                    break;
+                }
                 Err(e) => {
+                    // Start the reply over; content already sent is superseded
+                    let restarted = match upstream.restart.as_mut() {
+                        Some(restart) if tool_calls.is_empty() => restart.reopen(&e).await,
+                        _ => None,
+                    };
+                    let e = match restarted {
+                        Some(Ok(stream)) => {
+                            tracing::warn!("🔁 Ollama stream failed ({}); restarting reply", e);
+                            upstream.stream = timing.read_ahead(stream);
+                            accumulated.lock().await.clear();
+                            yield StreamEvent::Restarted(StreamRestarted {
+                                request_id: meta.request_id.clone(),
+                                reason: e.to_string(),
+                            });
+                            continue;
+                        }
+                        Some(Err(reopen_error)) => reopen_error,
+                        None => e,
+                    };
+
                     tracing::error!("Stream error: {}", e);
+                    if let Some(target) = &cache_target {
+                        target.record_failure(&e);
+                    }
                     let chunk = StreamChunk {
                         content: None,
                         done: true,
-                        request_id: None,
+                        request_id: request_id.clone(),
                         cached: None,
                         error: Some(e.to_string()),
+                        error_code: Some(stream_error_code(&e)),
+                        tool_calls: None,
+                        truncated: None,
+                        done_reason: None,
                     };
+                    if let Some(flight) = flight.take() {
+                        flight.complete(Err(Arc::new(e)));
+                    }
+                    guard.finished = true;
 
-                    let json = serde_json::to_string(&chunk).unwrap();
-                    yield Ok(axum::response::sse::Event::default().data(json));
+                    yield StreamEvent::Chunk(chunk);
                     break;
                 }
             }
         }
     }
 }
+
+/// Answer independent chat requests through the batch processor, in input order.
+/// A failing item is reported in its own result and does not fail the others.
+pub async fn chat_batch(
+    State(state): State<Arc<AppState>>,
+    request_id: RequestId,
+    ApiJson(request): ApiJson<BulkChatRequest>,
+) -> Result<Json<BulkChatResponse>, ApiError> {
+    if request.requests.is_empty() {
+        return Err(ApiError::BadRequest(
+            "requests must be a non-empty array".to_string(),
+        ));
+    }
+    if request.requests.len() > state.limits.max_bulk_requests {
+        return Err(ValidationError::TooManyRequests {
+            count: request.requests.len(),
+            limit: state.limits.max_bulk_requests,
+        }
+        .into());
+    }
+
+    let default_prompt = state.system_prompt.read().await.clone();
+    let responses = futures::stream::iter(request.requests)
+        .map(|item| bulk_item(&state, item, &request_id, &default_prompt))
+        .buffered(state.bulk_concurrency.max(1))
+        .collect()
+        .await;
+
+    Ok(Json(BulkChatResponse { responses }))
+}
+
+/// One item of a `/api/chat-batch` call; every item is audited under the call's request id
+async fn bulk_item(
+    state: &AppState,
+    request: ChatRequest,
+    request_id: &RequestId,
+    default_prompt: &str,
+) -> BulkChatResult {
+    let failed = |error: String| BulkChatResult {
+        message: None,
+        cached: false,
+        dropped_messages: None,
+        error: Some(error),
+    };
+
+    if let Some(field) = unsupported_bulk_field(&request) {
+        return failed(format!("`{}` is not supported in chat-batch items", field));
+    }
+    if let Err(e) = validate_messages(&request.messages, &state.limits) {
+        return failed(e.to_string());
+    }
+
+    let model = request.model.as_deref().unwrap_or(&state.model);
+    if let Err(e) = validate_model(model, &state.allowed_models.read().await) {
+        return failed(e.to_string());
+    }
+    let system_prompt = resolve_system_prompt(
+        default_prompt,
+        request.system_prompt.as_deref(),
+        request.template_vars.as_ref(),
+    );
+    let mut messages = request.messages.clone();
+    state.user_wrap.apply(&mut messages);
+    let dropped = trim_context(&mut messages, &system_prompt, &state.limits);
+    let _permit = match state.generation_limit.acquire().await {
+        Ok(permit) => permit,
+        Err(e) => return failed(e.summary()),
+    };
+    match state
+        .batch
+        .process_reply(messages, model, &system_prompt, request.priority)
+        .await
+    {
+        Ok(reply) => {
+            state.audit.record(
+                &request_id.0,
+                model,
+                &request.messages,
+                &reply.content,
+                reply.cached,
+            );
+            BulkChatResult {
+                message: Some(ChatMessage {
+                    role: Role::Assistant,
+                    content: reply.content,
+                    tool_calls: None,
+                    images: None,
+                }),
+                cached: reply.cached,
+                dropped_messages: (dropped > 0).then_some(dropped),
+                error: None,
+            }
+        }
+        Err(e) => {
+            tracing::warn!("Bulk chat item failed: {}", e);
+            failed(e.to_string())
+        }
+    }
+}
+
+/// The first field set on a bulk item that the batch processor cannot honour
+fn unsupported_bulk_field(request: &ChatRequest) -> Option<&'static str> {
+    [
+        ("use_cache", !request.use_cache),
+        ("cache_ttl_seconds", request.cache_ttl_seconds.is_some()),
+        ("cache_only", request.cache_only),
+        ("regenerate", request.regenerate),
+        ("session_id", request.session_id.is_some()),
+        ("options", request.options.is_some()),
+        ("format", request.format.is_some()),
+        ("tools", request.tools.is_some()),
+        ("stop", request.stop.is_some()),
+        ("keep_alive", request.keep_alive.is_some()),
+        ("stream_delay_ms", request.stream_delay_ms.is_some()),
+        ("include_messages", request.include_messages),
+    ]
+    .into_iter()
+    .find_map(|(field, set)| set.then_some(field))
+}
+
+/// Fetch the stored history for a conversation session
+pub async fn get_conversation(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> Result<Json<ConversationResponse>, ApiError> {
+    let messages = state.conversations.history(&session_id).await;
+    if messages.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "No conversation found for session {}",
+            session_id
+        )));
+    }
+
+    Ok(Json(ConversationResponse {
+        session_id,
+        messages,
+    }))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::{BatchConfig, CacheConfig, ConversationConfig, RateLimitConfig};
+    use crate::services::SingleFlight;
+    use axum::{
+        body::Body,
+        http::Request,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    fn chunk(content: &str, done: bool) -> anyhow::Result<OllamaResponse> {
+        Ok(OllamaResponse {
+            message: Some(ChatMessage {
+                role: Role::Assistant,
+                content: content.to_string(),
+                tool_calls: None,
+                images: None,
+            }),
+            done,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_only_completed_streams_are_cached() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let target = |key: &str| CacheTarget {
+            cache: cache.clone(),
+            key: key.to_string(),
+            scope: "test".to_string(),
+            embedding: None,
+            ttl: None,
+            started: Instant::now(),
+        };
+
+        // Client disconnects after the first chunk while Ollama is still generating
+        let upstream =
+            futures::stream::iter([chunk("Partial", false)]).chain(futures::stream::pending());
+        let stream = stream_ollama_response(
+            Upstream::from(Box::pin(upstream) as OllamaStream),
+            stream_meta(false, "test", RequestId::generate(), None),
+            Some(target("partial")),
+            None,
+            None,
+            false,
+            StreamTiming::default(),
+        );
+        let mut stream = Box::pin(stream);
+        // The meta event, then the partial chunk
+        assert!(stream.next().await.is_some());
+        assert!(stream.next().await.is_some());
+        drop(stream);
+        assert!(!cache.contains("partial").await);
+
+        let upstream = futures::stream::iter([chunk("Complete", false), chunk("", true)]);
+        let stream = stream_ollama_response(
+            Upstream::from(Box::pin(upstream) as OllamaStream),
+            stream_meta(false, "test", RequestId::generate(), None),
+            Some(target("complete")),
+            None,
+            None,
+            false,
+            StreamTiming::default(),
+        );
+        // The meta event, both content chunks and the final `done` event
+        assert_eq!(stream.count().await, 4);
+        assert_eq!(cache.get("complete").await, Some("Complete".to_string()));
+
+        // A reply cut off at the token limit says so, and is not cached
+        let mut last = chunk("", true).unwrap();
+        last.done_reason = Some("length".to_string());
+        let upstream = futures::stream::iter([chunk("Cut", false), Ok(last)]);
+        let stream = stream_ollama_response(
+            Upstream::from(Box::pin(upstream) as OllamaStream),
+            stream_meta(false, "test", RequestId::generate(), None),
+            Some(target("length")),
+            None,
+            None,
+            false,
+            StreamTiming::default(),
+        );
+        let events: Vec<_> = stream.collect().await;
+        let Some(StreamEvent::Chunk(done)) = events.last() else {
+            panic!("stream should end with a chunk");
+        };
+        assert_eq!(done.done_reason.as_deref(), Some("length"));
+        assert!(!cache.contains("length").await);
+    }
+
+    #[tokio::test]
+    async fn test_streams_ending_without_done_fail_as_unavailable() {
+        let cache = CacheService::new(CacheConfig {
+            negative_ttl_seconds: 60,
+            ..Default::default()
+        });
+        let target = CacheTarget {
+            cache: cache.clone(),
+            key: "cut".to_string(),
+            scope: "test".to_string(),
+            embedding: None,
+            ttl: None,
+            started: Instant::now(),
+        };
+        let flights = SingleFlight::new();
+        let Flight::Leader(guard) = flights.join("cut") else {
+            panic!("first request should lead");
+        };
+        let Flight::Follower(waiter) = flights.join("cut") else {
+            panic!("second request should follow");
+        };
+
+        // Ollama closes the stream before its `done` chunk
+        let upstream = futures::stream::iter([chunk("Cut", false)]);
+        let stream = stream_ollama_response(
+            Upstream::from(Box::pin(upstream) as OllamaStream),
+            stream_meta(false, "test", RequestId::generate(), None),
+            Some(target),
+            None,
+            Some(guard),
+            false,
+            StreamTiming::default(),
+        );
+        let events: Vec<_> = stream.collect().await;
+        let Some(StreamEvent::Chunk(done)) = events.last() else {
+            panic!("stream should end with a chunk");
+        };
+        assert!(done.done);
+        assert_eq!(done.error_code, Some(StreamErrorCode::UpstreamUnavailable));
+        assert!(cache.recent_failure("cut").is_some());
+        assert!(matches!(waiter.wait().await, Some(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn test_quick_replies_are_not_cached() {
+        let cache = CacheService::new(CacheConfig {
+            min_generation_ms: 500,
+            ..Default::default()
+        });
+        let target = |key: &str, took: Duration| CacheTarget {
+            cache: cache.clone(),
+            key: key.to_string(),
+            scope: "test".to_string(),
+            embedding: None,
+            ttl: None,
+            started: Instant::now() - took,
+        };
+
+        let quick = target("quick", Duration::from_millis(20));
+        assert!(!quick.store("Hi".to_string(), Some("stop")).await);
+        assert!(!cache.contains("quick").await);
+
+        let slow = target("slow", Duration::from_secs(2));
+        assert!(slow.store("A long essay".to_string(), Some("stop")).await);
+        assert_eq!(cache.get("slow").await, Some("A long essay".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_restarts_from_scratch() {
+        // Stands in for Ollama when the request is sent again, one line per chunk
+        let ollama_app = Router::new().route(
+            "/api/chat",
+            post(|| async {
+                let lines = [
+                    "{\"message\":{\"role\":\"assistant\",\"content\":\"Hello\"}}\n",
+                    "{\"done\":true}\n",
+                ];
+                let body = futures::stream::iter(lines).then(|line| async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok::<_, Infallible>(line)
+                });
+                Body::from_stream(body)
+            }),
+        );
+        let url = spawn_ollama(ollama_app).await;
+
+        let dropped = OllamaError::Unavailable("stream error: connection reset".to_string());
+        let first = futures::stream::iter([chunk("Hel", false), Err(dropped.into())]);
+        let upstream = Upstream {
+            stream: Box::pin(first),
+            restart: Some(StreamRestart {
+                ollama: OllamaClient::new(OllamaConfig {
+                    api_url: url,
+                    max_retries: 0,
+                    ..Default::default()
+                }),
+                messages: Vec::new(),
+                model: "test".to_string(),
+                system_prompt: String::new(),
+                options: None,
+                format: None,
+                keep_alive: None,
+                think_tags: None,
+                attempts_left: 1,
+                delay: Duration::from_millis(1),
+                deadline: None,
+            }),
+            partial_on_timeout: false,
+            _permit: None,
+        };
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let target = CacheTarget {
+            cache: cache.clone(),
+            key: "restarted".to_string(),
+            scope: "test".to_string(),
+            embedding: None,
+            ttl: None,
+            started: Instant::now(),
+        };
+
+        let stream = stream_ollama_response(
+            upstream,
+            stream_meta(false, "test", RequestId::generate(), None),
+            Some(target),
+            None,
+            None,
+            false,
+            StreamTiming::default(),
+        );
+        // Meta, the partial chunk, the restart notice, the new chunk and `done`
+        assert_eq!(stream.count().await, 5);
+        // Only the restarted reply is kept
+        assert_eq!(cache.get("restarted").await, Some("Hello".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_restart_keeps_the_reply_deadline() {
+        // Never answers, so only a deadline ends the restarted request
+        let hang = post(std::future::pending::<&'static str>);
+        let url = spawn_ollama(Router::new().route("/api/chat", hang)).await;
+        let mut restart = StreamRestart {
+            ollama: OllamaClient::new(OllamaConfig {
+                api_url: url,
+                max_retries: 0,
+                max_generation_seconds: 600,
+                ..Default::default()
+            }),
+            messages: Vec::new(),
+            model: "test".to_string(),
+            system_prompt: String::new(),
+            options: None,
+            format: None,
+            keep_alive: None,
+            think_tags: None,
+            attempts_left: 1,
+            delay: Duration::from_millis(1),
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+        };
+
+        let dropped = OllamaError::Unavailable("connection reset".to_string()).into();
+        let reopened = tokio::time::timeout(Duration::from_secs(5), restart.reopen(&dropped))
+            .await
+            .expect("the restart should stop at the reply's deadline, not a fresh one");
+        let error = reopened.unwrap().err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<OllamaError>(),
+            Some(OllamaError::DeadlineExceeded(600))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_stream_returns_partial_reply() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let target = CacheTarget {
+            cache: cache.clone(),
+            key: "timed-out".to_string(),
+            scope: "test".to_string(),
+            embedding: None,
+            ttl: None,
+            started: Instant::now(),
+        };
+
+        let timeout = OllamaError::Timeout("no output for 120s".to_string());
+        let upstream = futures::stream::iter([chunk("The answer", false), Err(timeout.into())]);
+        let upstream = Upstream {
+            stream: Box::pin(upstream),
+            restart: None,
+            partial_on_timeout: true,
+            _permit: None,
+        };
+        let stream = stream_ollama_response(
+            upstream,
+            stream_meta(false, "test", RequestId::generate(), None),
+            Some(target),
+            None,
+            None,
+            false,
+            StreamTiming::default(),
+        );
+
+        let response = Reply::Stream(Box::pin(stream)).into_response();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        let last = body.lines().rfind(|l| l.starts_with("data:")).unwrap();
+        let chunk: serde_json::Value = serde_json::from_str(&last["data:".len()..]).unwrap();
+        assert_eq!(chunk["content"], "The answer");
+        assert_eq!(chunk["done"], true);
+        assert_eq!(chunk["truncated"], true);
+        assert!(chunk.get("error").is_none());
+        assert!(!cache.contains("timed-out").await);
+    }
+
+    #[test]
+    fn test_stream_errors_are_classified() {
+        let code = |error: OllamaError| stream_error_code(&error.into());
+        assert_eq!(
+            code(OllamaError::Unavailable("connection reset".to_string())),
+            StreamErrorCode::UpstreamUnavailable
+        );
+        assert_eq!(
+            code(OllamaError::Timeout("no output for 120s".to_string())),
+            StreamErrorCode::Timeout
+        );
+        assert_eq!(
+            code(OllamaError::InvalidResponse("expected value".to_string())),
+            StreamErrorCode::ParseError
+        );
+        assert_eq!(
+            code(OllamaError::ModelNotFound("llama3".to_string())),
+            StreamErrorCode::UpstreamError
+        );
+
+        let chunk = StreamChunk {
+            content: None,
+            done: true,
+            request_id: None,
+            cached: None,
+            error: Some("Ollama timed out".to_string()),
+            error_code: Some(StreamErrorCode::Timeout),
+            tool_calls: None,
+            truncated: None,
+            done_reason: None,
+        };
+        let json = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(json["error_code"], "timeout");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_chunks_are_paced() {
+        let meta = stream_meta(true, "test", RequestId::generate(), None);
+        let started = Instant::now();
+        let stream =
+            stream_cached_response("one two three".to_string(), meta, Duration::from_millis(30));
+        // Meta, three words and `done`
+        assert_eq!(stream.count().await, 5);
+        // The first word goes out at once, each later one waits a full gap
+        assert!(started.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[tokio::test]
+    async fn test_fresh_replies_are_paced_without_slowing_ollama() {
+        let drained = Arc::new(std::sync::Mutex::new(None));
+        let upstream = {
+            let drained = drained.clone();
+            async_stream::stream! {
+                for word in ["one ", "two ", "three"] {
+                    yield chunk(word, false);
+                }
+                yield chunk("", true);
+                *drained.lock().unwrap() = Some(Instant::now());
+            }
+        };
+        let timing = StreamTiming {
+            heartbeat: None,
+            chunk_delay: Duration::from_millis(30),
+        };
+        let started = Instant::now();
+        let stream = stream_ollama_response(
+            Upstream::from(Box::pin(upstream) as OllamaStream),
+            stream_meta(false, "test", RequestId::generate(), None),
+            None,
+            None,
+            None,
+            false,
+            timing,
+        );
+
+        // Meta, three words, the empty final message and `done`
+        assert_eq!(stream.count().await, 6);
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        // Ollama's stream was read to the end long before the client had it all
+        let drained = drained.lock().unwrap().unwrap();
+        assert!(drained - started < Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_cache_control_overrides_use_cache() {
+        let mode = |use_cache, cache_control: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = cache_control {
+                headers.insert(header::CACHE_CONTROL, value.parse().unwrap());
+            }
+            let mode = CacheMode::new(use_cache, false, &headers);
+            (mode.read, mode.write)
+        };
+
+        assert_eq!(mode(true, None), (true, true));
+        assert_eq!(mode(false, None), (false, false));
+        assert_eq!(mode(true, Some("no-cache")), (false, true));
+        assert_eq!(mode(false, Some("No-Cache")), (false, true));
+        assert_eq!(mode(true, Some("no-store")), (false, false));
+        assert_eq!(
+            mode(true, Some("max-age=0, no-store, no-cache")),
+            (false, false)
+        );
+        assert_eq!(mode(true, Some("max-age=60")), (true, true));
+
+        // `regenerate` acts like `no-cache`, whatever `use_cache` says
+        let regenerate = |use_cache, headers: &HeaderMap| {
+            let mode = CacheMode::new(use_cache, true, headers);
+            (mode.read, mode.write)
+        };
+        assert_eq!(regenerate(true, &HeaderMap::new()), (false, true));
+        assert_eq!(regenerate(false, &HeaderMap::new()), (false, true));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, "no-store".parse().unwrap());
+        assert_eq!(regenerate(true, &headers), (false, false));
+    }
+
+    #[tokio::test]
+    async fn test_generation_limit_rejects_when_full() {
+        let limit = GenerationLimit::new(&OllamaConfig {
+            max_concurrent_requests: 1,
+            concurrency_wait_ms: 10,
+            retry_after_seconds: 7,
+            ..Default::default()
+        });
+
+        let held = limit.acquire().await.unwrap();
+        assert!(held.is_some());
+
+        match limit.acquire().await {
+            Err(ApiError::Busy {
+                retry_after_seconds,
+                ..
+            }) => assert_eq!(retry_after_seconds, 7),
+            _ => panic!("second generation should be rejected"),
+        }
+
+        // The slot frees up once the first reply is done
+        drop(held);
+        assert!(limit.acquire().await.unwrap().is_some());
+    }
+
+    fn test_state() -> Arc<AppState> {
+        // Nothing listens here, so requests that reach Ollama fail fast
+        Arc::new(state_for("http://127.0.0.1:9"))
+    }
+
+    pub(crate) fn state_for(api_url: &str) -> AppState {
+        let cache = CacheService::new(CacheConfig::default());
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: api_url.to_string(),
+            max_retries: 0,
+            ..Default::default()
+        });
+        let batch = BatchProcessor::new(
+            cache.clone(),
+            ollama.clone(),
+            BatchConfig {
+                max_batch_size: 1,
+                batch_timeout_ms: 10,
+                enable_deduplication: true,
+                bulk_concurrency: 2,
+                max_concurrent_upstream: 1,
+            },
+        );
+
+        AppState {
+            cache: cache.clone(),
+            conversations: ConversationStore::new(cache, ConversationConfig::default()),
+            ollama,
+            batch,
+            model: "test".to_string(),
+            system_prompt: Arc::new(RwLock::new(String::new())),
+            allowed_models: Arc::new(RwLock::new(Vec::new())),
+            limits: LimitsConfig::default(),
+            generation_limit: GenerationLimit::new(&OllamaConfig::default()),
+            bulk_concurrency: 2,
+            think_tags: None,
+            heartbeat_interval: None,
+            stream_delay: Duration::ZERO,
+            stream_restart: StreamRestartPolicy::new(&OllamaConfig::default()),
+            return_partial_on_timeout: false,
+            user_wrap: UserWrap::default(),
+            fallback_model: None,
+            audit: AuditLog::default(),
+            rate_limiter: RateLimiter::new(RateLimitConfig::default()),
+        }
+    }
+
+    /// Serve `app` as a fake Ollama, returning its base URL
+    pub(crate) async fn spawn_ollama(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    /// A fake Ollama that always says `content`; one NDJSON line serves both chat paths
+    pub(crate) async fn ollama_replying(content: &'static str) -> String {
+        spawn_ollama(Router::new().route(
+            "/api/chat",
+            post(move || async move {
+                let reply = serde_json::json!({
+                    "message": {"role": "assistant", "content": content},
+                    "done": true
+                });
+                format!("{reply}\n")
+            }),
+        ))
+        .await
+    }
+
+    /// POST `body` to `chat_optimized`, returning the status and response body
+    async fn post_chat(state: Arc<AppState>, body: serde_json::Value) -> (StatusCode, String) {
+        let app = Router::new()
+            .route("/chat", post(chat_optimized))
+            .with_state(state);
+        let request = Request::post("/chat")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_flight_followers_report_how_the_reply_ended() {
+        let url = spawn_ollama(Router::new().route(
+            "/api/chat",
+            post(|| async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let reply = serde_json::json!({
+                    "message": {"role": "assistant", "content": "Rust is a"},
+                    "done": true,
+                    "done_reason": "length"
+                });
+                format!("{reply}\n")
+            }),
+        ))
+        .await;
+        let state = Arc::new(state_for(&url));
+        let body = serde_json::json!({
+            "messages": [{"role": "user", "content": "What is Rust?"}],
+            "stream": false
+        });
+
+        // The follower is answered with the leader's reply, and how it ended
+        let (first, second) = tokio::join!(
+            post_chat(state.clone(), body.clone()),
+            post_chat(state, body)
+        );
+        for (status, body) in [first, second] {
+            assert_eq!(status, StatusCode::OK);
+            let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(json["done_reason"], "length");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chat_batch_rejects_what_it_cannot_honour() {
+        let mut state = state_for(&ollama_replying("Hi there!").await);
+        state.limits.max_bulk_requests = 2;
+        state.limits.max_context_messages = 1;
+        let app = Router::new()
+            .route("/chat-batch", post(chat_batch))
+            .with_state(Arc::new(state));
+        let send = |body: serde_json::Value| {
+            let request = Request::post("/chat-batch")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let item = serde_json::json!({"messages": [{"role": "user", "content": "Hello"}]});
+
+        let mut with_options = item.clone();
+        with_options["options"] = serde_json::json!({"temperature": 0.1});
+        let response = send(serde_json::json!({"requests": [item, with_options]}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["responses"][0]["message"]["content"], "Hi there!");
+        assert_eq!(
+            body["responses"][1]["error"],
+            "`options` is not supported in chat-batch items"
+        );
+
+        // Items are trimmed to the context limits, and there is no conversation to echo
+        let long = serde_json::json!({"messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": "Hello!"},
+            {"role": "user", "content": "What is Rust?"}
+        ]});
+        let mut with_echo = item.clone();
+        with_echo["include_messages"] = serde_json::json!(true);
+        let response = send(serde_json::json!({"requests": [long, with_echo]}))
+            .await
+            .unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["responses"][0]["dropped_messages"], 2);
+        assert_eq!(
+            body["responses"][1]["error"],
+            "`include_messages` is not supported in chat-batch items"
+        );
+
+        let response = send(serde_json::json!({"requests": [item, item, item]}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_chat_batch_and_completions_wrap_but_audit_as_sent() {
+        use crate::handlers::chat_completions;
+        use crate::services::audit::tests::{read_records, temp_log};
+
+        // Replies with the last message Ollama was sent
+        let url = spawn_ollama(Router::new().route(
+            "/api/chat",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                let messages = body["messages"].as_array().unwrap();
+                let reply = serde_json::json!({
+                    "message": {"role": "assistant", "content": messages.last().unwrap()["content"]},
+                    "done": true
+                });
+                format!("{reply}\n")
+            }),
+        ))
+        .await;
+        let mut state = state_for(&url);
+        state.user_wrap = UserWrap::new(&OllamaConfig {
+            user_prefix: "<q>".to_string(),
+            user_suffix: "</q>".to_string(),
+            ..Default::default()
+        });
+        let (audit, audit_path) = temp_log().await;
+        state.audit = audit;
+        let app = Router::new()
+            .route("/chat-batch", post(chat_batch))
+            .route("/completions", post(chat_completions))
+            .with_state(Arc::new(state));
+        let post_json = |path: &'static str, body: serde_json::Value| {
+            let request = Request::post(path)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+            }
+        };
+
+        let item = serde_json::json!({"messages": [{"role": "user", "content": "Hello"}]});
+        let body = post_json("/chat-batch", serde_json::json!({"requests": [item]})).await;
+        assert_eq!(body["responses"][0]["message"]["content"], "<q>Hello</q>");
+
+        let completion = serde_json::json!({"messages": [{"role": "user", "content": "Hi"}]});
+        let body = post_json("/completions", completion).await;
+        assert_eq!(body["choices"][0]["message"]["content"], "<q>Hi</q>");
+
+        // The audit log keeps what the client sent, next to the reply to the wrapped prompt
+        let records = read_records(&audit_path, 2).await;
+        assert_eq!(records[0]["messages"][0]["content"], "Hello");
+        assert_eq!(records[0]["response"], "<q>Hello</q>");
+        assert_eq!(records[1]["messages"][0]["content"], "Hi");
+        assert_eq!(records[1]["response"], "<q>Hi</q>");
+        tokio::fs::remove_file(&audit_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_every_model_endpoint_checks_allowed_models() {
+        use crate::handlers::{chat_completions, create_embeddings, generate};
+
+        let state = test_state();
+        *state.allowed_models.write().await = vec!["llama3".to_string()];
+        let app = Router::new()
+            .route("/completions", post(chat_completions))
+            .route("/generate", post(generate))
+            .route("/embeddings", post(create_embeddings))
+            .with_state(state);
+        let status = |path: &'static str, body: serde_json::Value| {
+            let request = Request::post(path)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = app.clone().oneshot(request);
+            async { response.await.unwrap().status() }
+        };
+
+        let messages = serde_json::json!([{"role": "user", "content": "Hi"}]);
+        let body = serde_json::json!({"model": "other", "messages": messages});
+        assert_eq!(status("/completions", body).await, StatusCode::BAD_REQUEST);
+        let body = serde_json::json!({"model": "other", "prompt": "Hi"});
+        assert_eq!(status("/generate", body).await, StatusCode::BAD_REQUEST);
+        let body = serde_json::json!({"model": "other", "input": "Hi"});
+        assert_eq!(status("/embeddings", body).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_body_rejections_keep_their_status() {
+        let app = Router::new()
+            .route("/chat", post(chat_optimized))
+            .with_state(test_state());
+        let send = |content_type: &'static str, body: &'static str| {
+            let request = Request::post("/chat")
+                .header("content-type", content_type)
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = send("text/plain", r#"{"messages": []}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["code"], "unsupported_media_type");
+
+        let response = send("application/json", "{").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_non_json_reply_to_json_request_is_bad_gateway() {
+        let state = Arc::new(state_for(&ollama_replying("Sure! Here you go").await));
+        let body = serde_json::json!({
+            "messages": [{"role": "user", "content": "List three colours as JSON"}],
+            "stream": false,
+            "format": "json"
+        });
+
+        let (status, reply) = post_chat(state.clone(), body.clone()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let error: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(error["code"], "bad_gateway");
+        assert_eq!(state.cache.stats().await.total_entries, 0);
+
+        // Streaming, the reply ends with a parse error instead
+        let mut body = body;
+        body["stream"] = true.into();
+        let (status, events) = post_chat(state.clone(), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(events.contains("\"error_code\":\"parse_error\""));
+        assert_eq!(state.cache.stats().await.total_entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cache_only_never_calls_ollama() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let url = spawn_ollama(Router::new().route(
+            "/api/chat",
+            post({
+                let calls = calls.clone();
+                move || async move {
+                    calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    "{\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true}\n"
+                }
+            }),
+        ))
+        .await;
+        let state = Arc::new(state_for(&url));
+        let messages = ChatRequest::from_prompt("hello".to_string(), None).messages;
+        let options = state.ollama.resolve_options(None);
+        let key = state
+            .cache
+            .generate_key(&messages, "test", "", &options, None, None);
+        state.cache.set(key, "Hi there!".to_string()).await;
+
+        let request = |content: &str| {
+            serde_json::json!({
+                "messages": [{"role": "user", "content": content}],
+                "stream": false,
+                "cache_only": true
+            })
+        };
+        let (status, reply) = post_chat(state.clone(), request("hello")).await;
+        assert_eq!(status, StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["message"]["content"], "Hi there!");
+        assert_eq!(reply["cached"], true);
+
+        let (status, reply) = post_chat(state.clone(), request("something new")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(reply.is_empty());
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_uncached_success_clears_a_recorded_failure() {
+        let mut state = state_for(&ollama_replying("Back up").await);
+        state.cache = CacheService::new(CacheConfig {
+            negative_ttl_seconds: 60,
+            ..Default::default()
+        });
+        let state = Arc::new(state);
+        let messages = ChatRequest::from_prompt("hello".to_string(), None).messages;
+        let options = state.ollama.resolve_options(None);
+        let key = state
+            .cache
+            .generate_key(&messages, "test", "", &options, None, None);
+        let request = |use_cache: bool| {
+            serde_json::json!({
+                "messages": [{"role": "user", "content": "hello"}],
+                "stream": false,
+                "use_cache": use_cache
+            })
+        };
+
+        state
+            .cache
+            .record_failure(&key, "connection refused".to_string());
+        let (status, _) = post_chat(state.clone(), request(true)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // A request that skips the cache still reaches Ollama, and its success counts
+        let (status, _) = post_chat(state.clone(), request(false)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.cache.recent_failure(&key), None);
+        let (status, _) = post_chat(state.clone(), request(true)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests_get_descriptive_errors() {
+        let app = Router::new()
+            .route("/chat", post(chat_optimized))
+            .with_state(test_state());
+
+        for (body, expected) in [
+            (r#"{"messages": []}"#, "messages must be a non-empty array"),
+            (r#"{"messages": "hello"}"#, "messages: invalid type"),
+            (
+                r#"{"messages": [{"role": "narrator", "content": "hi"}]}"#,
+                "unknown role `narrator`",
+            ),
+        ] {
+            let request = Request::post("/chat")
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let error: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(error["code"], "bad_request");
+            let detail = error["detail"].as_str().unwrap();
+            assert!(detail.contains(expected), "{detail}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fallback_model_answers_when_primary_fails_to_load() {
+        // Fake Ollama that is out of memory for `big`
+        let ollama_app = Router::new().route(
+            "/api/chat",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                if body["model"] == "big" {
+                    let error = r#"{"error":"model requires more system memory"}"#;
+                    return (StatusCode::INTERNAL_SERVER_ERROR, error.to_string());
+                }
+                let reply = serde_json::json!({
+                    "model": body["model"],
+                    "message": {"role": "assistant", "content": "from small"},
+                    "done": true
+                });
+                (StatusCode::OK, reply.to_string())
+            }),
+        );
+        let url = spawn_ollama(ollama_app).await;
+
+        let state = Arc::new(AppState {
+            fallback_model: Some("small".to_string()),
+            ..state_for(&url)
+        });
+        let app = Router::new()
+            .route("/chat", post(chat_optimized))
+            .with_state(state.clone());
+
+        let body = serde_json::json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "model": "big",
+            "stream": false
+        });
+        let request = Request::post("/chat")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(reply["message"]["content"], "from small");
+        assert_eq!(reply["fallback_used"], true);
+
+        // Cached under the model that produced the reply
+        let messages = ChatRequest::from_prompt("hi".to_string(), None).messages;
+        let options = state.ollama.resolve_options(None);
+        let key = |model| {
+            state
+                .cache
+                .generate_key(&messages, model, "", &options, None, None)
+        };
+        assert!(state.cache.contains(&key("small")).await);
+        assert!(!state.cache.contains(&key("big")).await);
+    }
+
+    #[tokio::test]
+    async fn test_reply_echoes_messages_when_asked() {
+        let state = test_state();
+        let messages = ChatRequest::from_prompt("hello".to_string(), None).messages;
+        let options = state.ollama.resolve_options(None);
+        let key = state
+            .cache
+            .generate_key(&messages, "test", "", &options, None, None);
+        state.cache.set(key, "Hi there!".to_string()).await;
+        let app = Router::new()
+            .route("/chat", post(chat_optimized))
+            .with_state(state);
+
+        let reply = |include_messages: bool| {
+            let app = app.clone();
+            async move {
+                let body = serde_json::json!({
+                    "messages": [{"role": "user", "content": "hello"}],
+                    "stream": false,
+                    "include_messages": include_messages
+                });
+                let request = Request::post("/chat")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+            }
+        };
+
+        let echoed = reply(true).await;
+        assert_eq!(
+            echoed["messages"],
+            serde_json::json!([
+                {"role": "user", "content": "hello"},
+                {"role": "assistant", "content": "Hi there!"}
+            ])
+        );
+        assert!(reply(false).await.get("messages").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_regenerate_replaces_the_cached_reply() {
+        // Fake Ollama numbering its replies; one NDJSON line serves both paths
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ollama_app = Router::new().route(
+            "/api/chat",
+            post({
+                let calls = calls.clone();
+                move || async move {
+                    let n = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                    let reply = serde_json::json!({
+                        "message": {"role": "assistant", "content": format!("fresh {n}")},
+                        "done": true
+                    });
+                    format!("{reply}\n")
+                }
+            }),
+        );
+        let url = spawn_ollama(ollama_app).await;
+
+        let state = Arc::new(state_for(&url));
+        let messages = ChatRequest::from_prompt("hello".to_string(), None).messages;
+        let options = state.ollama.resolve_options(None);
+        let key = state
+            .cache
+            .generate_key(&messages, "test", "", &options, None, None);
+        state.cache.set(key.clone(), "stale".to_string()).await;
+        let app = Router::new()
+            .route("/chat", post(chat_optimized))
+            .with_state(state.clone());
+
+        let send = |stream: bool| {
+            let app = app.clone();
+            async move {
+                let body = serde_json::json!({
+                    "messages": [{"role": "user", "content": "hello"}],
+                    "stream": stream,
+                    "regenerate": true
+                });
+                let request = Request::post("/chat")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(bytes.to_vec()).unwrap()
+            }
+        };
+
+        let reply: serde_json::Value = serde_json::from_str(&send(false).await).unwrap();
+        assert_eq!(reply["message"]["content"], "fresh 1");
+        assert_eq!(reply["cached"], false);
+        assert_eq!(state.cache.get(&key).await, Some("fresh 1".to_string()));
+
+        let body = send(true).await;
+        assert!(body.contains("\"content\":\"fresh 2\""));
+        assert_eq!(state.cache.get(&key).await, Some("fresh 2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_query_prompt_reuses_the_cache() {
+        let state = test_state();
+        let messages = ChatRequest::from_prompt("hello".to_string(), None).messages;
+        let options = state.ollama.resolve_options(None);
+        let key = state
+            .cache
+            .generate_key(&messages, "test", "", &options, None, None);
+        state.cache.set(key, "Hi there!".to_string()).await;
+
+        let app = Router::new()
+            .route("/chat", get(chat_query))
+            .with_state(state);
+
+        let request = Request::get("/chat?q=hello").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(reply["message"]["content"], "Hi there!");
+        assert_eq!(reply["cached"], true);
+
+        let request = Request::get("/chat").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
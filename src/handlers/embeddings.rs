@@ -0,0 +1,35 @@
+use crate::handlers::{ApiError, ApiJson, AppState};
+use crate::models::{EmbeddingRequest, EmbeddingResponse};
+use crate::utils::validation::validate_model;
+use axum::{extract::State, Json};
+use futures::future::try_join_all;
+use std::sync::Arc;
+
+/// Generate embeddings for one or more prompts, returned in input order
+pub async fn create_embeddings(
+    State(state): State<Arc<AppState>>,
+    ApiJson(request): ApiJson<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, ApiError> {
+    let model = request.model.unwrap_or_else(|| state.model.clone());
+    validate_model(&model, &state.allowed_models.read().await)?;
+    let prompts = request.input.into_prompts();
+
+    if prompts.is_empty() {
+        return Err(ApiError::BadRequest(
+            "input must contain at least one prompt".to_string(),
+        ));
+    }
+
+    let embeddings = try_join_all(
+        prompts
+            .iter()
+            .map(|prompt| state.ollama.generate_embedding(&model, prompt)),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Ollama embedding error: {}", e);
+        ApiError::from(e)
+    })?;
+
+    Ok(Json(EmbeddingResponse { model, embeddings }))
+}
@@ -0,0 +1,246 @@
+use crate::models::ErrorResponse;
+use crate::services::OllamaError;
+use crate::utils::validation::ValidationError;
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Error returned by handlers, rendered as a JSON `{error, code, detail}` body
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("Bad request")]
+    BadRequest(String),
+    /// Missing or wrong admin API key
+    #[error("Unauthorized")]
+    Unauthorized(String),
+    #[error("Forbidden")]
+    Forbidden(String),
+    #[error("Not found")]
+    NotFound(String),
+    /// Existed once but is no longer available
+    #[error("Expired")]
+    Gone(String),
+    /// Request exceeds the configured size limits
+    #[error("Payload too large")]
+    PayloadTooLarge(String),
+    /// Body sent without a JSON `Content-Type`
+    #[error("Unsupported media type")]
+    UnsupportedMediaType(String),
+    #[error("Service unavailable")]
+    ServiceUnavailable(String),
+    /// Every generation slot is taken; clients should retry after the given delay
+    #[error("Server busy")]
+    Busy {
+        detail: String,
+        retry_after_seconds: u64,
+    },
+    /// The configured backend can't do what was asked
+    #[error("Not implemented")]
+    NotImplemented(String),
+    /// Ollama could not be reached
+    #[error("Upstream unavailable")]
+    UpstreamUnavailable(String),
+    /// Ollama answered with an error or an unusable response
+    #[error("Bad gateway")]
+    BadGateway(String),
+    /// The generation ran past `ollama.max_generation_seconds`
+    #[error("Timeout")]
+    Timeout(String),
+    #[error("Internal server error")]
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Gone(_) => StatusCode::GONE,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::ServiceUnavailable(_)
+            | ApiError::Busy { .. }
+            | ApiError::UpstreamUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            ApiError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable machine-readable identifier for clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Gone(_) => "expired",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::ServiceUnavailable(_) => "service_unavailable",
+            ApiError::Busy { .. } => "busy",
+            ApiError::UpstreamUnavailable(_) => "upstream_unavailable",
+            ApiError::NotImplemented(_) => "not_implemented",
+            ApiError::BadGateway(_) => "bad_gateway",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Classify Ollama failures; anything else is an internal error
+    fn from_error(error: &anyhow::Error) -> Self {
+        let detail = error.to_string();
+        match error.downcast_ref::<OllamaError>() {
+            Some(OllamaError::Unavailable(_)) | Some(OllamaError::Timeout(_)) => {
+                ApiError::UpstreamUnavailable(detail)
+            }
+            Some(OllamaError::ModelNotFound(_)) => ApiError::NotFound(detail),
+            Some(OllamaError::DeadlineExceeded(_)) => ApiError::Timeout(detail),
+            Some(OllamaError::Api { status, .. }) if status.is_client_error() => {
+                ApiError::BadRequest(detail)
+            }
+            Some(OllamaError::Api { .. }) | Some(OllamaError::InvalidResponse(_)) => {
+                ApiError::BadGateway(detail)
+            }
+            None => ApiError::Internal(detail),
+        }
+    }
+
+    /// The error and its detail on one line, for reporting it inside another response
+    pub(crate) fn summary(&self) -> String {
+        match self.detail() {
+            "" => self.to_string(),
+            detail => format!("{}: {}", self, detail),
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            ApiError::BadRequest(detail)
+            | ApiError::Unauthorized(detail)
+            | ApiError::Forbidden(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::Gone(detail)
+            | ApiError::PayloadTooLarge(detail)
+            | ApiError::UnsupportedMediaType(detail)
+            | ApiError::ServiceUnavailable(detail)
+            | ApiError::UpstreamUnavailable(detail)
+            | ApiError::NotImplemented(detail)
+            | ApiError::BadGateway(detail)
+            | ApiError::Timeout(detail)
+            | ApiError::Internal(detail)
+            | ApiError::Busy { detail, .. } => detail,
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        ApiError::from_error(&error)
+    }
+}
+
+/// Errors shared between the leader and followers of a single-flight request
+impl From<Arc<anyhow::Error>> for ApiError {
+    fn from(error: Arc<anyhow::Error>) -> Self {
+        ApiError::from_error(&error)
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(error: ValidationError) -> Self {
+        match error {
+            ValidationError::NoMessages
+            | ValidationError::ModelNotAllowed { .. }
+            | ValidationError::ContextTooLarge { .. } => ApiError::BadRequest(error.to_string()),
+            _ => ApiError::PayloadTooLarge(error.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let detail = self.detail();
+        let body = ErrorResponse {
+            error: self.to_string(),
+            code: self.code().to_string(),
+            detail: (!detail.is_empty()).then(|| detail.to_string()),
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let ApiError::Busy {
+            retry_after_seconds,
+            ..
+        } = self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_seconds));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ollama_errors_map_to_status_codes() {
+        let unavailable = anyhow::Error::from(OllamaError::Unavailable("refused".to_string()));
+        assert_eq!(
+            ApiError::from(unavailable).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let missing_model = anyhow::Error::from(OllamaError::Api {
+            status: reqwest::StatusCode::NOT_FOUND,
+            body: "model not found".to_string(),
+        });
+        assert_eq!(
+            ApiError::from(missing_model).status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let not_installed = anyhow::Error::from(OllamaError::ModelNotFound("llama3".to_string()));
+        assert_eq!(
+            ApiError::from(not_installed).status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let server_error = anyhow::Error::from(OllamaError::Api {
+            status: reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+            body: String::new(),
+        });
+        assert_eq!(
+            ApiError::from(server_error).status(),
+            StatusCode::BAD_GATEWAY
+        );
+
+        let deadline = anyhow::Error::from(OllamaError::DeadlineExceeded(60));
+        let deadline = ApiError::from(deadline);
+        assert_eq!(deadline.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(deadline.code(), "timeout");
+
+        let oversized = ValidationError::TooManyMessages {
+            count: 500,
+            limit: 100,
+        };
+        assert_eq!(
+            ApiError::from(oversized).status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let other = anyhow::anyhow!("something else");
+        assert_eq!(
+            ApiError::from(other).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use crate::handlers::ApiError;
+use axum::extract::{rejection::JsonRejection, FromRequest};
+use axum::http::StatusCode;
+
+/// `Json` extractor whose rejections use the API's error format, naming the offending field
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let detail = rejection.body_text();
+        match rejection.status() {
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ApiError::UnsupportedMediaType(detail),
+            StatusCode::PAYLOAD_TOO_LARGE => ApiError::PayloadTooLarge(detail),
+            _ => ApiError::BadRequest(detail),
+        }
+    }
+}
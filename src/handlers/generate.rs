@@ -0,0 +1,58 @@
+use crate::handlers::{ApiError, ApiJson, AppState};
+use crate::middleware::record_chat;
+use crate::models::{GenerateRequest, GenerateResponse};
+use crate::utils::validation::{validate_model, validate_options, validate_prompt};
+use axum::{extract::State, Json};
+use std::sync::Arc;
+
+/// Single-shot completion of a raw prompt, cached like chat replies
+pub async fn generate(
+    State(state): State<Arc<AppState>>,
+    ApiJson(request): ApiJson<GenerateRequest>,
+) -> Result<Json<GenerateResponse>, ApiError> {
+    validate_prompt(&request.prompt, &state.limits)?;
+    validate_options(request.options.as_ref(), &state.limits)?;
+
+    let model = request.model.unwrap_or_else(|| state.model.clone());
+    validate_model(&model, &state.allowed_models.read().await)?;
+    let options = state.ollama.resolve_options(request.options.as_ref());
+    let cache_key = state
+        .cache
+        .generate_prompt_key(&request.prompt, &model, &options);
+
+    if request.use_cache {
+        if let Some(cached) = state.cache.get(&cache_key).await {
+            tracing::info!("✅ Serving generation from cache");
+            record_chat(&model, true);
+            return Ok(Json(GenerateResponse {
+                model,
+                response: cached,
+                cached: true,
+            }));
+        }
+    }
+    record_chat(&model, false);
+
+    let _permit = state.generation_limit.acquire().await?;
+    let mut response = state
+        .ollama
+        .generate(&request.prompt, &model, request.options.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Ollama generate error: {}", e);
+            ApiError::from(e)
+        })?;
+    if let Some(tags) = &state.think_tags {
+        response = tags.strip(&response);
+    }
+
+    if request.use_cache {
+        state.cache.set(cache_key, response.clone()).await;
+    }
+
+    Ok(Json(GenerateResponse {
+        model,
+        response,
+        cached: false,
+    }))
+}
@@ -1,7 +1,19 @@
 pub mod chat;
+pub mod embeddings;
+pub mod error;
+pub mod extract;
+pub mod generate;
+pub mod openai;
 pub mod queue;
 pub mod stats;
+pub mod ws;
 
 pub use chat::*;
+pub use embeddings::*;
+pub use error::ApiError;
+pub use extract::ApiJson;
+pub use generate::*;
+pub use openai::*;
 pub use queue::*;
 pub use stats::*;
+pub use ws::*;
@@ -0,0 +1,508 @@
+use crate::handlers::chat::{resolve_system_prompt, strip_reasoning, CacheTarget};
+use crate::handlers::{ApiError, ApiJson, AppState};
+use crate::middleware::{record_chat, RequestId};
+use crate::models::{
+    ChatMessage, OpenAiChatRequest, OpenAiChoice, OpenAiChunk, OpenAiChunkChoice, OpenAiCompletion,
+    OpenAiDelta, OpenAiUsage, Role,
+};
+use crate::services::single_flight::FlightGuard;
+use crate::services::{Flight, GenerationParams, OllamaError, SharedReply};
+use crate::utils::validation::{validate_messages, validate_model};
+use axum::{
+    extract::State,
+    response::{sse::Event, IntoResponse, Response, Sse},
+    Json,
+};
+use chrono::Utc;
+use futures::stream::StreamExt;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+/// OpenAI-compatible chat completions, answered from the same cache as `/api/chat-optimized`
+pub async fn chat_completions(
+    State(state): State<Arc<AppState>>,
+    request_id: RequestId,
+    ApiJson(request): ApiJson<OpenAiChatRequest>,
+) -> Result<Response, ApiError> {
+    let options = request.options();
+    let model = request.model.unwrap_or_else(|| state.model.clone());
+    let (system_prompt, client_messages) = split_system_prompt(request.messages);
+    validate_messages(&client_messages, &state.limits)?;
+    validate_model(&model, &state.allowed_models.read().await)?;
+    // Audited as the client sent them; Ollama and the cache key see them wrapped
+    let mut messages = client_messages.clone();
+    state.user_wrap.apply(&mut messages);
+    let system_prompt = resolve_system_prompt(
+        &state.system_prompt.read().await,
+        system_prompt.as_deref(),
+        None,
+    );
+
+    let cache = state.cache.clone();
+    let resolved = state.ollama.resolve_options(options.as_ref());
+    let cache_key = cache.generate_key(&messages, &model, &system_prompt, &resolved, None, None);
+    let completion = Completion {
+        id: format!("chatcmpl-{}", request_id.0),
+        created: Utc::now().timestamp(),
+        model: model.clone(),
+    };
+
+    // Prompts matching `cache.exempt_patterns` neither read nor write the cache
+    let exempt = cache.is_exempt(&messages);
+    let cached = if exempt {
+        tracing::debug!("🚫 Request matches cache.exempt_patterns; bypassing the cache");
+        None
+    } else {
+        cache.get(&cache_key).await
+    };
+    if let Some(cached) = cached {
+        tracing::info!("✅ Serving OpenAI completion from cache");
+        record_chat(&model, true);
+        state
+            .audit
+            .record(&request_id.0, &model, &client_messages, &cached, true);
+        return Ok(completion.whole(request.stream, cached, "stop"));
+    }
+    record_chat(&model, false);
+
+    // Ollama failed on this exact request moments ago; don't wait on it again
+    if !exempt {
+        if let Some(error) = cache.recent_failure(&cache_key) {
+            tracing::warn!("⛔ Failing fast on a request that just failed upstream");
+            return Err(ApiError::UpstreamUnavailable(error));
+        }
+    }
+
+    // Collapse identical in-flight requests, including `/api/chat-optimized` ones, into one
+    // upstream call
+    let mut flight = if exempt {
+        None
+    } else {
+        match state.batch.join_flight(&cache_key).await {
+            Flight::Leader(guard) => Some(guard),
+            Flight::Follower(waiter) => match waiter.wait().await {
+                Some(result) => {
+                    let reply = result?;
+                    let content = reply.message.content;
+                    state
+                        .audit
+                        .record(&request_id.0, &model, &client_messages, &content, false);
+                    let finish_reason = finish_reason(reply.done_reason.as_deref());
+                    return Ok(completion.whole(request.stream, content, finish_reason));
+                }
+                // The leader gave up without a result, so run the request ourselves
+                None => None,
+            },
+        }
+    };
+
+    let permit = state.generation_limit.acquire().await?;
+    let cache_target = (!exempt).then(|| CacheTarget::new(cache.clone(), cache_key));
+    let params = GenerationParams {
+        options: options.as_ref(),
+        ..Default::default()
+    };
+    let fail = |e: anyhow::Error, flight: Option<FlightGuard<SharedReply>>| {
+        if let Some(target) = &cache_target {
+            target.record_failure(&e);
+        }
+        let e = Arc::new(e);
+        if let Some(guard) = flight {
+            guard.complete(Err(e.clone()));
+        }
+        ApiError::from(e)
+    };
+
+    if !request.stream {
+        let result = state
+            .ollama
+            .chat_response(&messages, &model, &system_prompt, params, false)
+            .await;
+        drop(permit);
+        let response = result.map_err(|e| {
+            tracing::error!("Ollama error: {}", e);
+            fail(e, flight.take())
+        })?;
+
+        let mut content = response.message.map(|m| m.content).unwrap_or_default();
+        if let Some(tags) = &state.think_tags {
+            content = tags.strip(&content);
+        }
+        if let Some(guard) = flight {
+            guard.complete(Ok(shared_reply(
+                content.clone(),
+                response.done_reason.clone(),
+            )));
+        }
+        if let Some(target) = &cache_target {
+            target
+                .store(content.clone(), response.done_reason.as_deref())
+                .await;
+        }
+        state
+            .audit
+            .record(&request_id.0, &model, &client_messages, &content, false);
+
+        let usage = OpenAiUsage::new(
+            response.prompt_eval_count.unwrap_or(0),
+            response.eval_count.unwrap_or(0),
+        );
+        let finish_reason = finish_reason(response.done_reason.as_deref());
+        return Ok(Json(completion.reply(content, finish_reason, usage)).into_response());
+    }
+
+    let started = state
+        .ollama
+        .chat_completion_stream(&messages, &model, &system_prompt, params)
+        .await;
+    let ollama_stream = started.map_err(|e| {
+        tracing::error!("Ollama streaming error: {}", e);
+        fail(e, flight.take())
+    })?;
+    let mut ollama_stream = match state.think_tags.clone() {
+        Some(tags) => strip_reasoning(ollama_stream, tags),
+        None => ollama_stream,
+    };
+
+    let audit = state.audit.clone();
+    let stream = async_stream::stream! {
+        // Hold the generation slot until the reply ends or the client goes away
+        let _permit = permit;
+        let mut content = String::new();
+        let mut role = Some("assistant");
+
+        loop {
+            // Ending before `done` means the reply was cut off, so it fails like a dropped stream
+            let item = ollama_stream.next().await;
+            match item.unwrap_or_else(|| Err(OllamaError::stream_ended().into())) {
+                Ok(response) => {
+                    if let Some(message) = response.message.filter(|m| !m.content.is_empty()) {
+                        content.push_str(&message.content);
+                        let delta = OpenAiDelta::content(role.take(), message.content);
+                        yield Ok::<_, Infallible>(completion.event(delta, None));
+                    }
+                    if response.done {
+                        audit.record(&request_id.0, &model, &client_messages, &content, false);
+                        if let Some(guard) = flight.take() {
+                            let done_reason = response.done_reason.clone();
+                            guard.complete(Ok(shared_reply(content.clone(), done_reason)));
+                        }
+                        let done_reason = response.done_reason.as_deref();
+                        if let Some(target) = &cache_target {
+                            target.store(std::mem::take(&mut content), done_reason).await;
+                        }
+                        let finish = Some(finish_reason(done_reason));
+                        yield Ok(completion.event(OpenAiDelta::default(), finish));
+                        yield Ok(done_event());
+                        return;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Stream error: {}", e);
+                    let error = serde_json::json!({
+                        "error": { "message": e.to_string(), "type": "server_error" }
+                    });
+                    if let Some(target) = &cache_target {
+                        target.record_failure(&e);
+                    }
+                    if let Some(guard) = flight.take() {
+                        guard.complete(Err(Arc::new(e)));
+                    }
+                    yield Ok(Event::default().data(error.to_string()));
+                    yield Ok(done_event());
+                    return;
+                }
+            }
+        }
+    };
+
+    Ok(Sse::new(stream).into_response())
+}
+
+fn assistant_message(content: String) -> ChatMessage {
+    ChatMessage {
+        role: Role::Assistant,
+        content,
+        tool_calls: None,
+        images: None,
+    }
+}
+
+/// What followers of this request are answered with; OpenAI requests never fall back
+fn shared_reply(content: String, done_reason: Option<String>) -> SharedReply {
+    SharedReply {
+        message: assistant_message(content),
+        done_reason,
+        fallback_used: false,
+    }
+}
+
+/// OpenAI's `finish_reason` for Ollama's `done_reason`
+fn finish_reason(done_reason: Option<&str>) -> &'static str {
+    match done_reason {
+        Some("length") => "length",
+        _ => "stop",
+    }
+}
+
+/// Leading system messages become the system prompt, as OpenAI clients send it inline
+fn split_system_prompt(messages: Vec<ChatMessage>) -> (Option<String>, Vec<ChatMessage>) {
+    let mut system = Vec::new();
+    let mut rest = Vec::new();
+    for message in messages {
+        if message.role == Role::System && rest.is_empty() {
+            system.push(message.content);
+        } else {
+            rest.push(message);
+        }
+    }
+    ((!system.is_empty()).then(|| system.join("\n\n")), rest)
+}
+
+/// Fields shared by every chunk of one completion
+struct Completion {
+    id: String,
+    created: i64,
+    model: String,
+}
+
+impl Completion {
+    fn reply(&self, content: String, finish_reason: &str, usage: OpenAiUsage) -> OpenAiCompletion {
+        OpenAiCompletion {
+            id: self.id.clone(),
+            object: "chat.completion".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![OpenAiChoice {
+                index: 0,
+                message: assistant_message(content),
+                finish_reason: finish_reason.to_string(),
+            }],
+            usage,
+        }
+    }
+
+    /// A reply that is already complete: one JSON body, or a stream of one content chunk
+    fn whole(&self, stream: bool, content: String, finish_reason: &str) -> Response {
+        if !stream {
+            let reply = self.reply(content, finish_reason, OpenAiUsage::default());
+            return Json(reply).into_response();
+        }
+        let events = [
+            self.event(OpenAiDelta::content(Some("assistant"), content), None),
+            self.event(OpenAiDelta::default(), Some(finish_reason)),
+            done_event(),
+        ];
+        let stream = futures::stream::iter(events.map(Ok::<_, Infallible>));
+        Sse::new(stream).into_response()
+    }
+
+    fn event(&self, delta: OpenAiDelta, finish_reason: Option<&str>) -> Event {
+        let chunk = OpenAiChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![OpenAiChunkChoice {
+                index: 0,
+                delta,
+                finish_reason: finish_reason.map(str::to_string),
+            }],
+        };
+        Event::default().data(serde_json::to_string(&chunk).unwrap())
+    }
+}
+
+/// OpenAI's end-of-stream marker
+fn done_event() -> Event {
+    Event::default().data("[DONE]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::chat::tests::{spawn_ollama, state_for};
+    use axum::{body::Body, http::Request, routing::post, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+            tool_calls: None,
+            images: None,
+        }
+    }
+
+    #[test]
+    fn test_openai_request_maps_to_chat_inputs() {
+        let request: OpenAiChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "llama3",
+            "messages": [
+                {"role": "developer", "content": "Be brief."},
+                {"role": "user", "content": "Hi"},
+                {"role": "system", "content": "Mid-conversation note"}
+            ],
+            "stream": true,
+            "temperature": 0.3,
+            "max_tokens": 64,
+            "stop": "\n"
+        }))
+        .unwrap();
+
+        let options = request.options().unwrap();
+        assert_eq!(options.temperature, Some(0.3));
+        assert_eq!(options.num_predict, Some(64));
+        assert_eq!(options.stop, Some(vec!["\n".to_string()]));
+
+        let (system_prompt, messages) = split_system_prompt(request.messages);
+        assert_eq!(system_prompt.as_deref(), Some("Be brief."));
+        let roles: Vec<_> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(roles, [Role::User, Role::System]);
+
+        let (system_prompt, _) = split_system_prompt(vec![message(Role::User, "Hi")]);
+        assert_eq!(system_prompt, None);
+    }
+
+    #[test]
+    fn test_completion_reports_ollama_token_counts() {
+        let completion = Completion {
+            id: "chatcmpl-1".to_string(),
+            created: 1_700_000_000,
+            model: "llama3".to_string(),
+        };
+        let reply = completion.reply("Hi!".to_string(), "stop", OpenAiUsage::new(12, 3));
+
+        let json = serde_json::to_value(&reply).unwrap();
+        assert_eq!(json["object"], "chat.completion");
+        assert_eq!(json["choices"][0]["message"]["content"], "Hi!");
+        assert_eq!(json["choices"][0]["finish_reason"], "stop");
+        assert_eq!(
+            json["usage"],
+            serde_json::json!({"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completions_share_flights_and_skip_truncated_replies() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let ollama = spawn_ollama(Router::new().route(
+            "/api/chat",
+            post(move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let reply = serde_json::json!({
+                        "message": {"role": "assistant", "content": "Rust is a"},
+                        "done": true,
+                        "done_reason": "length"
+                    });
+                    format!("{reply}\n")
+                }
+            }),
+        ))
+        .await;
+        let app = Router::new()
+            .route("/v1/chat/completions", post(chat_completions))
+            .with_state(Arc::new(state_for(&ollama)));
+        let send_with = |stream: bool| {
+            let body = serde_json::json!({
+                "messages": [{"role": "user", "content": "What is Rust?"}],
+                "stream": stream
+            });
+            let request = Request::post("/v1/chat/completions")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let send = || send_with(false);
+
+        // Identical requests in flight together make one upstream call, and the follower
+        // reports how the leader's reply ended
+        let (first, second) = tokio::join!(send(), send());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for response in [first.unwrap(), second.unwrap()] {
+            assert!(response.status().is_success());
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(json["choices"][0]["finish_reason"], "length");
+        }
+
+        // A reply cut off at the token limit was not cached
+        let response = send().await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["choices"][0]["finish_reason"], "length");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Streamed, the final chunk says so too, and the reply still isn't cached
+        let response = send_with(true).await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.contains(r#""finish_reason":"length""#), "{body}");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_streams_ending_without_done_report_an_error() {
+        let ollama = spawn_ollama(Router::new().route(
+            "/api/chat",
+            post(|| async { "{\"message\":{\"role\":\"assistant\",\"content\":\"Cut\"}}\n" }),
+        ))
+        .await;
+        let app = Router::new()
+            .route("/v1/chat/completions", post(chat_completions))
+            .with_state(Arc::new(state_for(&ollama)));
+        let body = serde_json::json!({
+            "messages": [{"role": "user", "content": "What is Rust?"}],
+            "stream": true
+        });
+        let request = Request::post("/v1/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.contains(r#""type":"server_error""#), "{body}");
+        assert!(body.trim_end().ends_with("data: [DONE]"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_templated_default_prompt_keys_like_chat_optimized() {
+        let ollama = crate::handlers::chat::tests::ollama_replying("Hi there!").await;
+        let state = Arc::new(state_for(&ollama));
+        *state.system_prompt.write().await = "Today is {{date}}.".to_string();
+        let messages = vec![message(Role::User, "Hello")];
+        let app = Router::new()
+            .route("/v1/chat/completions", post(chat_completions))
+            .with_state(state.clone());
+        let body = serde_json::json!({"messages": messages, "stream": false});
+        let request = Request::post("/v1/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        assert!(app.oneshot(request).await.unwrap().status().is_success());
+
+        // Stored under the rendered prompt, as `/api/chat-optimized` would look it up
+        let prompt = resolve_system_prompt("Today is {{date}}.", None, None);
+        let options = state.ollama.resolve_options(None);
+        let key = state
+            .cache
+            .generate_key(&messages, "test", &prompt, &options, None, None);
+        assert_eq!(state.cache.get(&key).await.as_deref(), Some("Hi there!"));
+    }
+}
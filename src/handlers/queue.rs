@@ -1,12 +1,32 @@
-use crate::models::{QueueRequest, QueueResponse, QueueStatusResponse};
+use crate::config::LimitsConfig;
+use crate::handlers::{ApiError, ApiJson, SharedAllowedModels};
+use crate::models::{
+    QueueRequest, QueueResponse, QueueStatus, QueueStatusResponse, StreamChunk, StreamErrorCode,
+};
+use crate::services::queue::{QueueError, RequestOutcome, RequestState};
 use crate::services::QueueService;
+use crate::utils::validation::{validate_messages, validate_model};
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
     Json,
 };
+use futures::stream::Stream;
 use serde::Deserialize;
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// How often `/api/chat-queue/stream` reports the position of a request still waiting
+const POSITION_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct QueueState {
+    pub queue: Arc<QueueService>,
+    pub limits: LimitsConfig,
+    pub allowed_models: SharedAllowedModels,
+}
 
 #[derive(Deserialize)]
 pub struct StatusQuery {
@@ -16,69 +36,387 @@ pub struct StatusQuery {
 
 /// Add request to queue
 pub async fn enqueue_request(
-    State(queue): State<Arc<QueueService>>,
-    Json(request): Json<QueueRequest>,
-) -> Result<Json<QueueResponse>, StatusCode> {
+    State(state): State<Arc<QueueState>>,
+    ApiJson(request): ApiJson<QueueRequest>,
+) -> Result<Json<QueueResponse>, ApiError> {
+    validate_messages(&request.messages, &state.limits)?;
+
+    let queue = &state.queue;
     let model = request
         .model
         .unwrap_or_else(|| "deepseek-r1:8b".to_string());
+    validate_model(&model, &state.allowed_models.read().await)?;
     let system_prompt = request
         .system_prompt
         .unwrap_or_else(|| "Format all responses in markdown.".to_string());
 
-    let request_id = queue.enqueue(request.messages, model, system_prompt).await;
+    let priority = queue.resolve_priority(request.priority);
+    let request_id = queue
+        .enqueue(request.messages, model, system_prompt, priority)
+        .await
+        .map_err(|e| {
+            tracing::warn!("Rejected queue request: {}", e);
+            match e {
+                QueueError::Full {
+                    retry_after_seconds,
+                    ..
+                } => ApiError::Busy {
+                    detail: e.to_string(),
+                    retry_after_seconds,
+                },
+                QueueError::Draining => ApiError::ServiceUnavailable(e.to_string()),
+            }
+        })?;
 
     // Get initial status
     let status = queue
         .get_status(&request_id)
         .await
-        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        .ok_or_else(|| ApiError::Internal("Queued request disappeared".to_string()))?;
 
     Ok(Json(QueueResponse { request_id, status }))
 }
 
 /// Get queue status
 pub async fn get_queue_status(
-    State(queue): State<Arc<QueueService>>,
+    State(state): State<Arc<QueueState>>,
     Query(params): Query<StatusQuery>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
+) -> Result<Json<serde_json::Value>, ApiError> {
     if let Some(request_id) = params.request_id {
         // Get status for specific request
-        match queue.get_status(&request_id).await {
-            Some(status) => Ok(Json(serde_json::json!(QueueStatusResponse {
-                request_id,
-                completed: false,
-                status: Some(status),
-            }))),
-            None => Ok(Json(serde_json::json!(QueueStatusResponse {
-                request_id,
-                completed: true,
-                status: None,
-            }))),
+        let mut response = QueueStatusResponse {
+            request_id: request_id.clone(),
+            completed: false,
+            status: None,
+            response: None,
+            completed_at: None,
+            error: None,
+            error_code: None,
+            cancelled: false,
+        };
+
+        let queue = &state.queue;
+        match queue.get_state(&request_id).await {
+            RequestState::Queued(status) => response.status = Some(status),
+            RequestState::Processing => {
+                let (queue_length, _) = queue.get_queue_info().await;
+                response.status = Some(QueueStatus {
+                    queue_position: 0,
+                    queue_length,
+                    estimated_wait_time: 0,
+                    is_processing: true,
+                });
+            }
+            RequestState::Completed(completed) => {
+                response.completed = true;
+                response.completed_at =
+                    chrono::DateTime::from_timestamp_millis(completed.completed_at)
+                        .map(|t| t.to_rfc3339());
+                match completed.outcome {
+                    RequestOutcome::Success(chat_response) => {
+                        response.response = Some(chat_response)
+                    }
+                    RequestOutcome::Failed { error, code } => {
+                        response.error = Some(error);
+                        response.error_code = Some(code);
+                    }
+                    RequestOutcome::Cancelled => response.cancelled = true,
+                }
+            }
+            RequestState::Expired => {
+                return Err(ApiError::Gone(format!(
+                    "Result for request {} has expired",
+                    request_id
+                )));
+            }
+            RequestState::Unknown => {
+                return Err(ApiError::NotFound(format!(
+                    "Unknown request {}",
+                    request_id
+                )));
+            }
         }
+
+        Ok(Json(serde_json::json!(response)))
     } else {
         // Get general queue info
-        let (queue_length, is_processing) = queue.get_queue_info().await;
+        let (queue_length, is_processing) = state.queue.get_queue_info().await;
+        let wait_times = state.queue.wait_times().await;
         Ok(Json(serde_json::json!({
             "queue_length": queue_length,
             "is_processing": is_processing,
+            "oldest_wait_ms": wait_times.map(|(oldest, _)| oldest),
+            "newest_wait_ms": wait_times.map(|(_, newest)| newest),
+            "deduplicated_requests": state.queue.deduplicated_requests(),
         })))
     }
 }
 
+/// Stream a queued request: `position` events while it waits, then its reply as `StreamChunk`s
+/// once the worker picks it up. Content events carry the byte offset reached as their `id`, so
+/// a reconnect with `Last-Event-Id` resumes after what the client already has.
+pub async fn stream_queued_request(
+    State(state): State<Arc<QueueState>>,
+    headers: HeaderMap,
+    Query(params): Query<StatusQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let request_id = params
+        .request_id
+        .ok_or_else(|| ApiError::BadRequest("requestId is required".to_string()))?;
+    match state.queue.get_state(&request_id).await {
+        RequestState::Expired => {
+            return Err(ApiError::Gone(format!(
+                "Result for request {} has expired",
+                request_id
+            )))
+        }
+        RequestState::Unknown => {
+            return Err(ApiError::NotFound(format!(
+                "Unknown request {}",
+                request_id
+            )))
+        }
+        _ => {}
+    }
+
+    let resume_from = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+
+    let queue = state.queue.clone();
+    let stream = async_stream::stream! {
+        let mut ticker = tokio::time::interval(POSITION_UPDATE_INTERVAL);
+        // Bytes of the reply already sent, so the final result only fills in what is missing
+        let mut sent = resume_from;
+
+        loop {
+            match queue.get_state(&request_id).await {
+                RequestState::Queued(status) => {
+                    let json = serde_json::to_string(&status).unwrap();
+                    yield Ok(Event::default().event("position").data(json));
+                    ticker.tick().await;
+                }
+                RequestState::Processing => {
+                    let Some((so_far, mut tokens)) = queue.subscribe(&request_id).await else {
+                        ticker.tick().await;
+                        continue;
+                    };
+                    // An offset past the reply or inside a character falls back to what we have
+                    sent = so_far.floor_char_boundary(sent);
+                    if so_far.len() > sent {
+                        let backlog = so_far[sent..].to_string();
+                        sent = so_far.len();
+                        yield Ok(content_event(&request_id, backlog, sent));
+                    }
+
+                    // A subscriber that falls behind skips ahead to the final result
+                    let mut lagged = false;
+                    loop {
+                        match tokens.recv().await {
+                            Ok(token) if !lagged => {
+                                sent += token.len();
+                                yield Ok(content_event(&request_id, token, sent));
+                            }
+                            Ok(_) => {}
+                            Err(RecvError::Lagged(_)) => lagged = true,
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                }
+                RequestState::Completed(completed) => {
+                    let (error, error_code) = match completed.outcome {
+                        RequestOutcome::Success(response) => {
+                            let content = response.message.content;
+                            let sent = content.floor_char_boundary(sent);
+                            if let Some(rest) = content.get(sent..).filter(|r| !r.is_empty()) {
+                                let rest = rest.to_string();
+                                yield Ok(content_event(&request_id, rest, content.len()));
+                            }
+                            (None, None)
+                        }
+                        RequestOutcome::Failed { error, code } => (Some(error), Some(code)),
+                        RequestOutcome::Cancelled => (
+                            Some("Request cancelled".to_string()),
+                            Some(StreamErrorCode::Cancelled),
+                        ),
+                    };
+                    yield Ok(done_event(&request_id, error, error_code));
+                    break;
+                }
+                RequestState::Expired | RequestState::Unknown => {
+                    let error = format!("Request {} is no longer available", request_id);
+                    yield Ok(done_event(&request_id, Some(error), None));
+                    break;
+                }
+            }
+        }
+    };
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// A reply chunk whose id is the byte offset into the reply it ends at
+fn content_event(request_id: &str, content: String, offset: usize) -> Event {
+    let chunk = StreamChunk {
+        content: Some(content),
+        done: false,
+        request_id: Some(request_id.to_string()),
+        cached: Some(false),
+        error: None,
+        error_code: None,
+        tool_calls: None,
+        truncated: None,
+        done_reason: None,
+    };
+    Event::default()
+        .id(offset.to_string())
+        .data(serde_json::to_string(&chunk).unwrap())
+}
+
+fn done_event(
+    request_id: &str,
+    error: Option<String>,
+    error_code: Option<StreamErrorCode>,
+) -> Event {
+    let chunk = StreamChunk {
+        content: None,
+        done: true,
+        request_id: Some(request_id.to_string()),
+        cached: Some(false),
+        error,
+        error_code,
+        tool_calls: None,
+        truncated: None,
+        done_reason: None,
+    };
+    Event::default().data(serde_json::to_string(&chunk).unwrap())
+}
+
+/// Cancel everything queued or being processed, e.g. during an incident
+pub async fn cancel_all_requests(State(state): State<Arc<QueueState>>) -> Json<serde_json::Value> {
+    let cancelled = state.queue.clear().await;
+    Json(serde_json::json!({ "cancelled": cancelled }))
+}
+
 /// Cancel request
 pub async fn cancel_request(
-    State(queue): State<Arc<QueueService>>,
+    State(state): State<Arc<QueueState>>,
     Query(params): Query<StatusQuery>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
+) -> Result<Json<serde_json::Value>, ApiError> {
     let request_id = params
         .request_id
-        .ok_or(StatusCode::BAD_REQUEST)?;
+        .ok_or_else(|| ApiError::BadRequest("requestId is required".to_string()))?;
 
-    let cancelled = queue.cancel(&request_id).await;
+    let cancelled = state.queue.cancel(&request_id).await;
 
     Ok(Json(serde_json::json!({
         "request_id": request_id,
         "cancelled": cancelled,
     })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QueueConfig;
+    use crate::models::{ChatMessage, ChatResponse, Role};
+    use axum::response::IntoResponse;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_reconnect_resumes_after_last_event_id() {
+        let queue = Arc::new(QueueService::new(QueueConfig::default()));
+        let id = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+        queue.dequeue().await.unwrap();
+        let response = ChatResponse {
+            message: ChatMessage {
+                role: Role::Assistant,
+                content: "Hello, wörld".to_string(),
+                tool_calls: None,
+                images: None,
+            },
+            cached: Some(false),
+            dropped_messages: None,
+            fallback_used: None,
+            messages: None,
+            done_reason: None,
+        };
+        queue.complete(&id, RequestOutcome::Success(response)).await;
+
+        let state = Arc::new(QueueState {
+            queue,
+            limits: LimitsConfig::default(),
+            allowed_models: Arc::new(RwLock::new(Vec::new())),
+        });
+        let resume = |last_event_id: &'static str| {
+            let (state, id) = (state.clone(), id.clone());
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert("last-event-id", last_event_id.parse().unwrap());
+                let query = StatusQuery {
+                    request_id: Some(id),
+                };
+                let sse = stream_queued_request(State(state), headers, Query(query))
+                    .await
+                    .unwrap();
+                let bytes = axum::body::to_bytes(sse.into_response().into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(bytes.to_vec()).unwrap()
+            }
+        };
+
+        let body = resume("7").await;
+        assert!(body.contains(r#""content":"wörld""#), "{body}");
+        assert!(body.contains("id: 13\n"), "{body}");
+
+        // An offset inside `ö` backs up to the start of the character
+        let body = resume("9").await;
+        assert!(body.contains(r#""content":"örld""#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_failed_requests_report_an_error_code() {
+        let queue = Arc::new(QueueService::new(QueueConfig::default()));
+        let id = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+        queue.dequeue().await.unwrap();
+        let outcome = RequestOutcome::Failed {
+            error: "Ollama timed out: no output for 120s".to_string(),
+            code: StreamErrorCode::Timeout,
+        };
+        queue.complete(&id, outcome).await;
+        let state = Arc::new(QueueState {
+            queue,
+            limits: LimitsConfig::default(),
+            allowed_models: Arc::new(RwLock::new(Vec::new())),
+        });
+
+        let query = StatusQuery {
+            request_id: Some(id.clone()),
+        };
+        let Json(status) = get_queue_status(State(state.clone()), Query(query))
+            .await
+            .unwrap();
+        assert_eq!(status["error_code"], "timeout");
+
+        let query = StatusQuery {
+            request_id: Some(id),
+        };
+        let sse = stream_queued_request(State(state), HeaderMap::new(), Query(query))
+            .await
+            .unwrap();
+        let bytes = axum::body::to_bytes(sse.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.contains(r#""error_code":"timeout""#), "{body}");
+    }
+}
@@ -1,20 +1,85 @@
-use crate::models::{ActionResponse, CacheAction, SystemStats};
-use crate::services::{BatchProcessor, CacheService, QueueService};
-use axum::{extract::State, http::StatusCode, Json};
+use crate::config::Config;
+use crate::handlers::chat::resolve_system_prompt;
+use crate::handlers::{ApiError, ApiJson, SharedSystemPrompt};
+use crate::models::{
+    ActionResponse, CacheAction, CacheActions, CacheEntrySummary, ChatMessage, DeletionOutcome,
+    OllamaModelInfo, PullModelRequest, PullProgress, SystemPromptRequest, SystemPromptResponse,
+    SystemStats, WarmPrompt,
+};
+use crate::services::{BatchProcessor, CacheService, OllamaClient, QueueService};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive},
+    response::{IntoResponse, Response, Sse},
+    Json,
+};
 use chrono::Utc;
+use futures::stream::{Stream, StreamExt};
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+/// How long `/health` waits for Ollama before reporting it unreachable
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `/health` reuses its last upstream check, so frequent probes don't each hit Ollama
+const HEALTH_CHECK_CACHE: Duration = Duration::from_secs(2);
+
+/// Entries listed by `/api/cache-entries` unless `limit` says otherwise, and the most it allows
+const DEFAULT_CACHE_ENTRIES: usize = 10;
+const MAX_CACHE_ENTRIES: usize = 100;
 
 pub struct StatsState {
     pub response_cache: CacheService,
     pub conversation_cache: CacheService,
     pub batch_processor: BatchProcessor,
     pub queue: Arc<QueueService>,
+    pub ollama: OllamaClient,
+    pub model: String,
+    pub system_prompt: SharedSystemPrompt,
+    pub config: SharedConfig,
+    /// Set once startup warm-up has finished
+    pub ready: Arc<AtomicBool>,
+    pub upstream_health: UpstreamHealth,
+}
+
+/// The last upstream check made by `/health`, as `(ollama_reachable, model_available)`
+#[derive(Default)]
+pub struct UpstreamHealth(Mutex<Option<(Instant, (bool, bool))>>);
+
+impl UpstreamHealth {
+    /// The cached result while it is fresh, otherwise the result of `check`. Probes arriving
+    /// during a check wait for it rather than starting their own.
+    async fn get_or_check<F>(&self, check: F) -> (bool, bool)
+    where
+        F: Future<Output = (bool, bool)>,
+    {
+        let mut last = self.0.lock().await;
+        if let Some((checked_at, result)) = *last {
+            if checked_at.elapsed() < HEALTH_CHECK_CACHE {
+                return result;
+            }
+        }
+        let result = check.await;
+        *last = Some((Instant::now(), result));
+        result
+    }
 }
 
+/// Configuration in effect: reloaded settings are updated in place, the rest keep their
+/// startup values
+pub type SharedConfig = Arc<RwLock<Config>>;
+
 /// Get system statistics
 pub async fn get_stats(
     State(state): State<Arc<StatsState>>,
-) -> Result<Json<SystemStats>, StatusCode> {
+) -> Result<Json<SystemStats>, ApiError> {
     let response_cache_stats = state.response_cache.stats().await;
     let conversation_cache_stats = state.conversation_cache.stats().await;
     let batch_stats = state.batch_processor.stats().await;
@@ -27,65 +92,628 @@ pub async fn get_stats(
         batch_processor: batch_stats,
         queue_length,
         is_processing,
+        ollama_backends: state.ollama.backend_status(),
     };
 
     Ok(Json(stats))
 }
 
-/// Manage cache (clear, warm, etc.)
+#[derive(Deserialize)]
+pub struct CacheEntriesQuery {
+    limit: Option<usize>,
+}
+
+/// Most-served response cache entries, for cache analytics
+pub async fn get_cache_entries(
+    State(state): State<Arc<StatsState>>,
+    Query(params): Query<CacheEntriesQuery>,
+) -> Result<Json<Vec<CacheEntrySummary>>, ApiError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_CACHE_ENTRIES)
+        .min(MAX_CACHE_ENTRIES);
+    let unsupported = || ApiError::NotImplemented("the cache backend does not count hits".into());
+    let entries = state.response_cache.top_entries(limit).await;
+    Ok(Json(entries.ok_or_else(unsupported)?))
+}
+
+/// The resolved configuration after file and environment overrides, with secrets redacted
+pub async fn get_config(State(state): State<Arc<StatsState>>) -> Json<Config> {
+    Json(state.config.read().await.clone())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManageCacheQuery {
+    /// Run the remaining actions of a list after one fails
+    #[serde(default)]
+    continue_on_error: bool,
+}
+
+/// Manage cache (clear, warm, etc.). A list of actions is answered with a list of results.
 pub async fn manage_cache(
     State(state): State<Arc<StatsState>>,
-    Json(action): Json<CacheAction>,
-) -> Result<Json<ActionResponse>, StatusCode> {
+    Query(params): Query<ManageCacheQuery>,
+    ApiJson(actions): ApiJson<CacheActions>,
+) -> Result<Response, ApiError> {
+    match actions {
+        CacheActions::One(action) => Ok(Json(run_action(&state, action).await?).into_response()),
+        CacheActions::Many(actions) => {
+            let results = run_actions(actions, params.continue_on_error, |action| {
+                run_action(&state, action)
+            })
+            .await;
+            Ok(Json(results).into_response())
+        }
+    }
+}
+
+/// Run actions in order, recording failures as unsuccessful results. Unless
+/// `continue_on_error` is set, the actions after a failure are skipped.
+async fn run_actions<F, Fut>(
+    actions: Vec<CacheAction>,
+    continue_on_error: bool,
+    mut run: F,
+) -> Vec<ActionResponse>
+where
+    F: FnMut(CacheAction) -> Fut,
+    Fut: Future<Output = Result<ActionResponse, ApiError>>,
+{
+    let mut results = Vec::with_capacity(actions.len());
+    let mut failed = false;
+    for action in actions {
+        if failed && !continue_on_error {
+            results.push(ActionResponse {
+                success: false,
+                message: format!("Skipped {} after an earlier action failed", action.action),
+                data: None,
+            });
+            continue;
+        }
+
+        let name = action.action.clone();
+        let result = run(action).await.unwrap_or_else(|e| {
+            tracing::warn!("Cache action {} failed: {}", name, e.summary());
+            ActionResponse {
+                success: false,
+                message: e.summary(),
+                data: Some(serde_json::json!({ "code": e.code() })),
+            }
+        });
+        failed |= !result.success;
+        results.push(result);
+    }
+    results
+}
+
+async fn run_action(state: &StatsState, action: CacheAction) -> Result<ActionResponse, ApiError> {
     match action.action.as_str() {
         "clear" => {
             state.response_cache.clear().await;
             state.conversation_cache.clear().await;
-            Ok(Json(ActionResponse {
+            Ok(ActionResponse {
                 success: true,
                 message: "All caches cleared".to_string(),
-            }))
+                data: None,
+            })
         }
         "clear_response_cache" => {
             state.response_cache.clear().await;
-            Ok(Json(ActionResponse {
+            Ok(ActionResponse {
                 success: true,
                 message: "Response cache cleared".to_string(),
-            }))
+                data: None,
+            })
         }
         "clear_conversation_cache" => {
             state.conversation_cache.clear().await;
-            Ok(Json(ActionResponse {
+            Ok(ActionResponse {
                 success: true,
                 message: "Conversation cache cleared".to_string(),
-            }))
+                data: None,
+            })
         }
         "warm_model" => {
             // Extract model from data if provided
             let model = action
                 .data
                 .and_then(|d| d.get("model").and_then(|m| m.as_str()).map(|s| s.to_string()))
-                .unwrap_or_else(|| "deepseek-r1:8b".to_string());
+                .unwrap_or_else(|| state.model.clone());
 
             match state.batch_processor.warm_model(&model).await {
-                Ok(_) => Ok(Json(ActionResponse {
+                Ok(_) => Ok(ActionResponse {
                     success: true,
                     message: format!("Model {} warmed", model),
-                })),
+                    data: None,
+                }),
                 Err(e) => {
                     tracing::error!("Failed to warm model: {}", e);
-                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                    Err(e.into())
+                }
+            }
+        }
+        "warm_cache" => {
+            // Prompts come from a JSON file (`{"path": ...}`) or inline (`{"prompts": [...]}`)
+            let data = action.data.unwrap_or_default();
+            let path = data.get("path").and_then(|p| p.as_str());
+            let prompts: Vec<WarmPrompt> = match (path, data.get("prompts")) {
+                (Some(path), _) => BatchProcessor::load_warm_prompts(path).await.map_err(|e| {
+                    ApiError::BadRequest(format!("Failed to read prompts from {}: {}", path, e))
+                })?,
+                (None, Some(prompts)) => serde_json::from_value(prompts.clone())
+                    .map_err(|e| ApiError::BadRequest(format!("Invalid prompts: {}", e)))?,
+                (None, None) => {
+                    return Err(ApiError::BadRequest(
+                        "warm_cache requires a `path` or `prompts` field in data".to_string(),
+                    ))
+                }
+            };
+
+            let system_prompt =
+                resolve_system_prompt(&state.system_prompt.read().await, None, None);
+            let report = state
+                .batch_processor
+                .warm_cache(prompts, &state.model, &system_prompt)
+                .await;
+            Ok(ActionResponse {
+                success: report.failed == 0,
+                message: format!(
+                    "Warmed {} prompts ({} already cached, {} exempt, {} failed)",
+                    report.warmed, report.already_cached, report.exempt, report.failed
+                ),
+                data: None,
+            })
+        }
+        "inspect" => {
+            let key = cache_key_from_data(state, action.data).await?;
+            let entry = state.response_cache.get_by_key(&key).await;
+            Ok(ActionResponse {
+                success: true,
+                message: if entry.is_some() {
+                    format!("Cache entry {} found", key)
+                } else {
+                    format!("No cache entry for {}", key)
+                },
+                data: Some(serde_json::json!({
+                    "key": key,
+                    "exists": entry.is_some(),
+                    "entry": entry,
+                })),
+            })
+        }
+        "invalidate" => {
+            let key = cache_key_from_data(state, action.data).await?;
+            let existed = state.response_cache.invalidate_key(&key).await;
+            Ok(ActionResponse {
+                success: true,
+                message: if existed {
+                    format!("Cache entry {} invalidated", key)
+                } else {
+                    format!("No cache entry for {}", key)
+                },
+                data: Some(serde_json::json!({
+                    "key": key,
+                    "existed": existed,
+                })),
+            })
+        }
+        other => Err(ApiError::BadRequest(format!(
+            "Unknown cache action: {}",
+            other
+        ))),
+    }
+}
+
+/// Response cache key from `{"key"}`, or computed from `{"messages", "model", "system_prompt"}`
+async fn cache_key_from_data(
+    state: &StatsState,
+    data: Option<serde_json::Value>,
+) -> Result<String, ApiError> {
+    let data = data.unwrap_or_default();
+    if let Some(key) = data.get("key").and_then(|k| k.as_str()) {
+        return Ok(key.to_string());
+    }
+
+    let Some(messages) = data.get("messages") else {
+        return Err(ApiError::BadRequest(
+            "a `key` or `messages` field is required in data".to_string(),
+        ));
+    };
+    let messages: Vec<ChatMessage> = serde_json::from_value(messages.clone())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid messages: {}", e)))?;
+    let model = data
+        .get("model")
+        .and_then(|m| m.as_str())
+        .unwrap_or(&state.model);
+    let system_prompt = resolve_system_prompt(
+        &state.system_prompt.read().await,
+        data.get("system_prompt").and_then(|p| p.as_str()),
+        None,
+    );
+
+    Ok(state
+        .batch_processor
+        .client_cache_key(&messages, model, &system_prompt))
+}
+
+/// Current default system prompt
+pub async fn get_system_prompt(State(state): State<Arc<StatsState>>) -> Json<SystemPromptResponse> {
+    Json(SystemPromptResponse {
+        system_prompt: state.system_prompt.read().await.clone(),
+    })
+}
+
+/// Replace the default system prompt; requests without their own prompt use it from now on
+pub async fn update_system_prompt(
+    State(state): State<Arc<StatsState>>,
+    ApiJson(request): ApiJson<SystemPromptRequest>,
+) -> Json<SystemPromptResponse> {
+    *state.system_prompt.write().await = request.system_prompt.clone();
+    tracing::info!("📝 Default system prompt updated");
+
+    Json(SystemPromptResponse {
+        system_prompt: request.system_prompt,
+    })
+}
+
+/// List models available on the Ollama server, sorted by name
+pub async fn list_models(
+    State(state): State<Arc<StatsState>>,
+) -> Result<Json<Vec<OllamaModelInfo>>, ApiError> {
+    match state.ollama.list_models().await {
+        Ok(mut models) => {
+            models.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(Json(models))
+        }
+        Err(e) => {
+            tracing::error!("Failed to list models: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Download a model onto the Ollama backends, streaming progress as SSE
+pub async fn pull_model(
+    State(state): State<Arc<StatsState>>,
+    ApiJson(request): ApiJson<PullModelRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let model = request.model.trim().to_string();
+    if model.is_empty() {
+        return Err(ApiError::BadRequest("model is required".to_string()));
+    }
+
+    let mut progress = state.ollama.pull_model(&model);
+    let stream = async_stream::stream! {
+        let mut finished = PullProgress {
+            backend: None,
+            status: "success".to_string(),
+            digest: None,
+            percent: None,
+            done: true,
+            error: None,
+        };
+
+        while let Some(update) = progress.next().await {
+            match update {
+                Ok(update) => yield Ok(pull_event(&update)),
+                Err(e) => {
+                    tracing::error!("Failed to pull model {}: {}", model, e);
+                    finished.status = "error".to_string();
+                    finished.error = Some(e.to_string());
+                    break;
                 }
             }
         }
-        _ => Err(StatusCode::BAD_REQUEST),
+
+        if finished.error.is_none() {
+            tracing::info!("✅ Model {} pulled", model);
+        }
+        yield Ok(pull_event(&finished));
+    };
+
+    // Verifying large layers can go quiet for a while, so keep the connection alive
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Remove a model from the Ollama backends
+pub async fn delete_model(
+    State(state): State<Arc<StatsState>>,
+    Path(model): Path<String>,
+) -> Result<Json<ActionResponse>, ApiError> {
+    match state.ollama.delete_model(&model).await {
+        Ok(backends) => {
+            let deleted = backends
+                .iter()
+                .filter(|b| b.outcome == DeletionOutcome::Deleted)
+                .count();
+            let failed = backends
+                .iter()
+                .any(|b| b.outcome == DeletionOutcome::Failed);
+            tracing::info!("🗑️  Model {} deleted from {} backends", model, deleted);
+            let message = if failed {
+                format!(
+                    "Model {} deleted from {} of {} backends",
+                    model,
+                    deleted,
+                    backends.len()
+                )
+            } else {
+                format!("Model {} deleted", model)
+            };
+            Ok(Json(ActionResponse {
+                success: !failed,
+                message,
+                data: Some(serde_json::json!({ "backends": backends })),
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to delete model {}: {}", model, e);
+            Err(e.into())
+        }
     }
 }
 
-/// Health check endpoint
-pub async fn health() -> Json<serde_json::Value> {
+#[derive(Deserialize)]
+pub struct QueueListQuery {
+    /// Include each request's messages and system prompt, for debugging
+    #[serde(default)]
+    include_content: bool,
+}
+
+/// List the requests waiting in the queue
+pub async fn list_queue(
+    State(state): State<Arc<StatsState>>,
+    Query(params): Query<QueueListQuery>,
+) -> Json<serde_json::Value> {
+    let requests = state.queue.list(params.include_content).await;
+    Json(serde_json::json!({
+        "queue_length": requests.len(),
+        "requests": requests,
+    }))
+}
+
+/// Cancel a queued or in-flight request by id
+pub async fn cancel_queued(
+    State(state): State<Arc<StatsState>>,
+    Path(request_id): Path<String>,
+) -> Result<Json<ActionResponse>, ApiError> {
+    if !state.queue.cancel(&request_id).await {
+        return Err(ApiError::NotFound(format!(
+            "Request {} is not queued or in progress",
+            request_id
+        )));
+    }
+    tracing::info!("❌ Admin cancelled request {}", request_id);
+    Ok(Json(ActionResponse {
+        success: true,
+        message: format!("Request {} cancelled", request_id),
+        data: None,
+    }))
+}
+
+fn pull_event(progress: &PullProgress) -> Event {
+    let json = serde_json::to_string(progress).unwrap();
+    Event::default().data(json)
+}
+
+/// Export metrics in Prometheus text format
+pub async fn metrics(State(handle): State<PrometheusHandle>) -> String {
+    handle.render()
+}
+
+/// Liveness probe: answers as long as the process is serving requests
+pub async fn liveness() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "alive" }))
+}
+
+/// Readiness probe: `503` until startup warm-up has finished, and while no Ollama backend
+/// passes its periodic health check
+pub async fn readiness(
+    State(state): State<Arc<StatsState>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let warmed_up = state.ready.load(Ordering::Relaxed);
+    let ollama_reachable = state
+        .ollama
+        .backend_status()
+        .iter()
+        .any(|backend| backend.healthy);
+    let ready = warmed_up && ollama_reachable;
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "warmed_up": warmed_up,
+        "ollama_reachable": ollama_reachable,
+    });
+    (status, Json(body))
+}
+
+/// Health check endpoint, reporting whether Ollama is reachable and has the configured model
+pub async fn health(State(state): State<Arc<StatsState>>) -> Json<serde_json::Value> {
+    let check = async {
+        let check = state.ollama.model_available(&state.model);
+        match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check).await {
+            Ok(Ok(available)) => (true, available),
+            Ok(Err(_)) | Err(_) => (false, false),
+        }
+    };
+    let (ollama_reachable, model_available) = state.upstream_health.get_or_check(check).await;
+
+    let status = if ollama_reachable && model_available {
+        "healthy"
+    } else {
+        "degraded"
+    };
+
     Json(serde_json::json!({
-        "status": "healthy",
+        "status": status,
         "timestamp": Utc::now().to_rfc3339(),
+        "ollama_reachable": ollama_reachable,
+        "model": state.model,
+        "model_available": model_available,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_action_lists_stop_at_the_first_failure_unless_asked() {
+        let actions: CacheActions = serde_json::from_value(serde_json::json!([
+            {"action": "clear_conversation_cache"},
+            {"action": "bogus"},
+            {"action": "warm_model", "data": {"model": "llama3"}}
+        ]))
+        .unwrap();
+        let CacheActions::Many(actions) = actions else {
+            panic!("expected a list of actions");
+        };
+
+        let run = |action: CacheAction| async move {
+            match action.action.as_str() {
+                "bogus" => Err(ApiError::BadRequest(
+                    "Unknown cache action: bogus".to_string(),
+                )),
+                name => Ok(ActionResponse {
+                    success: true,
+                    message: format!("{} done", name),
+                    data: None,
+                }),
+            }
+        };
+
+        let results = run_actions(actions.clone(), false, run).await;
+        let succeeded: Vec<_> = results.iter().map(|r| r.success).collect();
+        assert_eq!(succeeded, [true, false, false]);
+        assert_eq!(
+            results[1].message,
+            "Bad request: Unknown cache action: bogus"
+        );
+        assert_eq!(
+            results[1].data,
+            Some(serde_json::json!({"code": "bad_request"}))
+        );
+        assert!(results[2].message.starts_with("Skipped warm_model"));
+
+        let results = run_actions(actions, true, run).await;
+        let succeeded: Vec<_> = results.iter().map(|r| r.success).collect();
+        assert_eq!(succeeded, [true, false, true]);
+
+        // A single action is still accepted as before
+        let action = serde_json::json!({"action": "clear"});
+        assert!(matches!(
+            serde_json::from_value(action).unwrap(),
+            CacheActions::One(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_health_is_reused_briefly() {
+        let health = UpstreamHealth::default();
+        let checks = std::sync::atomic::AtomicUsize::new(0);
+        let check = || async {
+            checks.fetch_add(1, Ordering::SeqCst);
+            (true, false)
+        };
+
+        assert_eq!(health.get_or_check(check()).await, (true, false));
+        assert_eq!(health.get_or_check(check()).await, (true, false));
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+        // Once stale, the next probe checks again
+        let stale = Instant::now() - HEALTH_CHECK_CACHE;
+        health.0.lock().await.as_mut().unwrap().0 = stale;
+        health.get_or_check(check()).await;
+        assert_eq!(checks.load(Ordering::SeqCst), 2);
+    }
+
+    /// Stats state over the shipped config, talking to the Ollama servers at `api_urls`
+    fn stats_state(api_urls: Vec<String>) -> Arc<StatsState> {
+        let mut config: Config = config::Config::builder()
+            .add_source(config::File::from_str(
+                include_str!("../../config.toml"),
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        config.ollama.api_urls = api_urls;
+        config.ollama.max_retries = 0;
+        let cache = CacheService::new(config.cache.clone());
+        let ollama = OllamaClient::new(config.ollama.clone());
+        Arc::new(StatsState {
+            response_cache: cache.clone(),
+            conversation_cache: cache.clone(),
+            batch_processor: BatchProcessor::new(cache, ollama.clone(), config.batch.clone()),
+            queue: Arc::new(QueueService::new(config.queue.clone())),
+            ollama,
+            model: config.ollama.model.clone(),
+            system_prompt: Arc::new(RwLock::new(config.ollama.system_prompt.clone())),
+            config: Arc::new(RwLock::new(config)),
+            ready: Arc::new(AtomicBool::new(true)),
+            upstream_health: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_delete_model_reports_each_backend_behind_the_admin_key() {
+        use crate::config::Secret;
+        use crate::middleware::require_admin;
+        use axum::body::Body;
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let mut urls = Vec::new();
+        for status in [StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR] {
+            let ollama_app = axum::Router::new().route(
+                "/api/delete",
+                axum::routing::delete(move || async move { status }),
+            );
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            urls.push(format!("http://{}", listener.local_addr().unwrap()));
+            tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+        }
+        let app = axum::Router::new()
+            .route("/api/models/*name", axum::routing::delete(delete_model))
+            .with_state(stats_state(urls.clone()))
+            .layer(axum::middleware::from_fn_with_state(
+                Some(Secret::from("s3cret")),
+                require_admin,
+            ));
+        let send = |key: Option<&str>| {
+            let mut request = Request::delete("/api/models/llama3.2:1b");
+            if let Some(key) = key {
+                request = request.header("authorization", format!("Bearer {key}"));
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(send(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            send(Some("wrong")).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let response = send(Some("s3cret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["success"], false);
+        assert_eq!(
+            body["message"],
+            "Model llama3.2:1b deleted from 1 of 2 backends"
+        );
+        let backends = &body["data"]["backends"];
+        assert_eq!(backends[0]["url"], urls[0].as_str());
+        assert_eq!(backends[0]["outcome"], "deleted");
+        assert_eq!(backends[1]["outcome"], "failed");
+        assert!(backends[1]["error"].as_str().unwrap().contains("500"));
+    }
+}
@@ -0,0 +1,361 @@
+use crate::handlers::chat::{reply, CacheMode, EventStream, Reply, StreamEvent};
+use crate::handlers::{ApiError, AppState};
+use crate::middleware::{ClientIp, RequestId};
+use crate::models::{ChatRequest, StreamChunk, StreamErrorCode};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::HeaderMap,
+    response::Response,
+    Extension,
+};
+use futures::stream::StreamExt;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Requests held on one socket while a reply streams; further ones are refused
+const MAX_PENDING_REQUESTS: usize = 8;
+
+/// `GET /ws/chat`: streaming chat over a WebSocket, for clients that can't use SSE. Every
+/// text frame is a `ChatRequest`, answered with the same frames the SSE stream sends.
+pub async fn chat_ws(
+    State(state): State<Arc<AppState>>,
+    client_ip: Option<Extension<ClientIp>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // Keyed as the rate limit middleware keys the upgrade request
+    let ip = client_ip
+        .map(|Extension(ClientIp(ip))| ip)
+        .or(peer.map(|ConnectInfo(addr)| addr.ip()));
+    upgrade.on_upgrade(move |socket| serve_chat(socket, state, headers, ip))
+}
+
+/// Answer requests one at a time until the client closes the socket; requests sent while a
+/// reply is streaming are answered after it. Each request is charged to the client's rate
+/// limit, like an HTTP request.
+async fn serve_chat(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    headers: HeaderMap,
+    ip: Option<IpAddr>,
+) {
+    tracing::debug!("🔌 WebSocket chat connected");
+    let mut pending = VecDeque::new();
+
+    loop {
+        let text = match pending.pop_front() {
+            Some(text) => text,
+            None => match socket.recv().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        let parsed = match check_rate_limit(&state, ip) {
+            Some(refusal) => Err(refusal),
+            None => serde_json::from_str::<ChatRequest>(&text).map_err(|e| StreamChunk {
+                done: true,
+                error: Some(format!("invalid request: {e}")),
+                ..Default::default()
+            }),
+        };
+        let events = match parsed {
+            Ok(mut request) => {
+                request.stream = true;
+                let cache_mode = CacheMode::new(request.use_cache, request.regenerate, &headers);
+                match reply(state.clone(), request, RequestId::generate(), cache_mode).await {
+                    Ok(Reply::Stream(events)) => events,
+                    // Only a cache-only request that missed ends without a stream
+                    Ok(Reply::Response(_)) => single_chunk(StreamChunk {
+                        done: true,
+                        ..Default::default()
+                    }),
+                    Err(e) => single_chunk(error_chunk(&e)),
+                }
+            }
+            Err(chunk) => single_chunk(chunk),
+        };
+
+        if !forward(&mut socket, events, &mut pending).await {
+            break;
+        }
+    }
+    tracing::debug!("🔌 WebSocket chat closed");
+}
+
+/// Send a reply's events until it ends. Returns false when the client went away; dropping
+/// `events` then stops Ollama generating, as a disconnect does for SSE.
+async fn forward(
+    socket: &mut WebSocket,
+    mut events: EventStream,
+    pending: &mut VecDeque<String>,
+) -> bool {
+    loop {
+        let next = tokio::select! {
+            event = events.next() => Ok(event),
+            message = socket.recv() => Err(message),
+        };
+
+        match next {
+            Ok(Some(event)) => {
+                if socket.send(ws_message(event)).await.is_err() {
+                    return false;
+                }
+            }
+            Ok(None) => return true,
+            Err(Some(Ok(Message::Text(text)))) if pending.len() < MAX_PENDING_REQUESTS => {
+                pending.push_back(text)
+            }
+            Err(Some(Ok(Message::Text(_)))) => {
+                // Refused straight away, between the frames of the reply in progress
+                let error = format!("more than {MAX_PENDING_REQUESTS} requests are waiting");
+                let refusal = StreamEvent::Chunk(refused(error));
+                if socket.send(ws_message(refusal)).await.is_err() {
+                    return false;
+                }
+            }
+            Err(Some(Ok(Message::Close(_)))) | Err(Some(Err(_))) | Err(None) => {
+                tracing::info!("🔌 WebSocket closed mid-stream; cancelling generation");
+                return false;
+            }
+            Err(Some(Ok(_))) => {}
+        }
+    }
+}
+
+/// Chunks are sent as they are; meta and restart notices carry the SSE event name in `event`
+fn ws_message(event: StreamEvent) -> Message {
+    let (name, mut json) = match event {
+        StreamEvent::Chunk(chunk) => return Message::Text(serde_json::to_string(&chunk).unwrap()),
+        StreamEvent::Keepalive => return Message::Ping(Vec::new()),
+        StreamEvent::Meta(meta) => ("meta", serde_json::to_value(meta).unwrap()),
+        StreamEvent::Restarted(notice) => ("restart", serde_json::to_value(notice).unwrap()),
+    };
+    json["event"] = name.into();
+    Message::Text(json.to_string())
+}
+
+/// Take a token from the client's rate limit bucket, returning the refusal when it's empty
+fn check_rate_limit(state: &AppState, ip: Option<IpAddr>) -> Option<StreamChunk> {
+    let ip = ip?;
+    let retry_after = state.rate_limiter.check(ip).err()?;
+    tracing::warn!("🚦 Rate limit exceeded for {} on a WebSocket", ip);
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    Some(refused(format!("Rate limit exceeded; retry in {seconds}s")))
+}
+
+/// A final chunk for a request that was refused without reaching Ollama
+fn refused(error: String) -> StreamChunk {
+    StreamChunk {
+        done: true,
+        error: Some(error),
+        error_code: Some(StreamErrorCode::RateLimited),
+        ..Default::default()
+    }
+}
+
+fn single_chunk(chunk: StreamChunk) -> EventStream {
+    Box::pin(futures::stream::iter([StreamEvent::Chunk(chunk)]))
+}
+
+/// A request rejected before streaming started, reported as a final chunk
+fn error_chunk(error: &ApiError) -> StreamChunk {
+    let error_code = match error {
+        ApiError::UpstreamUnavailable(_) => Some(StreamErrorCode::UpstreamUnavailable),
+        ApiError::Timeout(_) => Some(StreamErrorCode::Timeout),
+        ApiError::BadGateway(_) | ApiError::NotFound(_) => Some(StreamErrorCode::UpstreamError),
+        _ => None,
+    };
+    StreamChunk {
+        done: true,
+        error: Some(error.summary()),
+        error_code,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitConfig;
+    use crate::handlers::chat::tests::{ollama_replying, spawn_ollama, state_for};
+    use crate::middleware::RateLimiter;
+    use axum::{body::Body, routing::get, routing::post, Router};
+    use futures::SinkExt;
+    use std::convert::Infallible;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    /// Sets the flag when Ollama's response body is dropped, i.e. the request was cancelled
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    type ClientSocket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// Serve `chat_ws` for a client resolved to 10.0.0.1 and connect to it
+    async fn connect(state: AppState) -> ClientSocket {
+        let app = Router::new()
+            .route("/ws/chat", get(chat_ws))
+            .layer(Extension(ClientIp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))))
+            .with_state(Arc::new(state));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws/chat"))
+            .await
+            .unwrap();
+        socket
+    }
+
+    fn request(content: &str) -> ClientMessage {
+        let request = serde_json::json!({
+            "messages": [{"role": "user", "content": content}],
+            "use_cache": false
+        });
+        ClientMessage::Text(request.to_string())
+    }
+
+    /// Read frames up to and including the next final chunk
+    async fn read_reply(socket: &mut ClientSocket) -> Vec<serde_json::Value> {
+        let mut frames = Vec::new();
+        while let Some(Ok(ClientMessage::Text(text))) = socket.next().await {
+            let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+            let done = frame["done"] == true;
+            frames.push(frame);
+            if done {
+                break;
+            }
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_each_websocket_request_is_rate_limited() {
+        let state = AppState {
+            rate_limiter: RateLimiter::new(RateLimitConfig {
+                enabled: true,
+                requests_per_minute: 1,
+                burst: 1,
+            }),
+            ..state_for(&ollama_replying("Hello").await)
+        };
+        let mut socket = connect(state).await;
+
+        // Opening the socket is free, so a burst of one still covers the first request
+        socket.send(request("Hi")).await.unwrap();
+        let frames = read_reply(&mut socket).await;
+        assert!(frames.iter().any(|f| f["content"] == "Hello"));
+        assert!(frames.iter().all(|f| f.get("error").is_none()));
+
+        // The bucket is empty, so the next request on the same socket is refused
+        socket.send(request("Hi again")).await.unwrap();
+        let frames = read_reply(&mut socket).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0]["error_code"], "rate_limited");
+        assert!(frames[0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Rate limit exceeded"));
+    }
+
+    #[tokio::test]
+    async fn test_requests_beyond_the_pending_cap_are_refused() {
+        // Fake Ollama that never finishes a reply
+        let ollama = spawn_ollama(Router::new().route(
+            "/api/chat",
+            post(|| async {
+                Body::from_stream(futures::stream::pending::<Result<String, Infallible>>())
+            }),
+        ))
+        .await;
+        let mut socket = connect(state_for(&ollama)).await;
+
+        socket.send(request("Tell me a long story")).await.unwrap();
+        for i in 0..=MAX_PENDING_REQUESTS {
+            socket
+                .send(request(&format!("Question {i}")))
+                .await
+                .unwrap();
+        }
+
+        // Only the request past the cap is answered while the first reply streams
+        let frames = tokio::time::timeout(Duration::from_secs(5), read_reply(&mut socket))
+            .await
+            .unwrap();
+        let refusal = frames.last().unwrap();
+        assert_eq!(refusal["error_code"], "rate_limited");
+        assert!(refusal["error"].as_str().unwrap().contains("waiting"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_streams_replies_and_cancels_on_close() {
+        // Fake Ollama: "Hello" for the first request; the second never finishes
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let answered = Arc::new(AtomicBool::new(false));
+        let ollama_app = Router::new().route(
+            "/api/chat",
+            post({
+                let cancelled = cancelled.clone();
+                move || {
+                    let (answered, cancelled) = (answered.clone(), cancelled.clone());
+                    async move {
+                        let hello =
+                            "{\"message\":{\"role\":\"assistant\",\"content\":\"Hello\"}}\n";
+                        if !answered.swap(true, Ordering::SeqCst) {
+                            let lines = [hello, "{\"done\":true}\n"];
+                            return Body::from_stream(
+                                futures::stream::iter(lines).map(Ok::<_, Infallible>),
+                            );
+                        }
+                        let flag = DropFlag(cancelled);
+                        let lines =
+                            futures::stream::iter([hello]).chain(futures::stream::pending());
+                        Body::from_stream(lines.map(move |line| {
+                            let _ = &flag;
+                            Ok::<_, Infallible>(line)
+                        }))
+                    }
+                }
+            }),
+        );
+        let mut socket = connect(state_for(&spawn_ollama(ollama_app).await)).await;
+
+        socket.send(request("Hi")).await.unwrap();
+        let frames = read_reply(&mut socket).await;
+        assert_eq!(frames[0]["event"], "meta");
+        assert_eq!(frames[1]["content"], "Hello");
+        assert_eq!(frames.last().unwrap()["done"], true);
+        assert!(frames.iter().all(|f| f.get("error").is_none()));
+
+        // Closing mid-reply drops the upstream request
+        socket.send(request("Tell me a long story")).await.unwrap();
+        let meta = socket.next().await.unwrap().unwrap();
+        assert!(meta.to_text().unwrap().contains("\"meta\""));
+        let chunk = socket.next().await.unwrap().unwrap();
+        assert!(chunk.to_text().unwrap().contains("Hello"));
+        socket.close(None).await.unwrap();
+
+        for _ in 0..100 {
+            if cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(cancelled.load(Ordering::SeqCst));
+    }
+}
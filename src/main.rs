@@ -1,26 +1,45 @@
 mod config;
 mod handlers;
+mod middleware;
 mod models;
 mod services;
 mod utils;
 
 use crate::config::Config;
 use crate::handlers::{
-    chat::{chat_optimized, AppState},
-    queue::{cancel_request, enqueue_request, get_queue_status},
-    stats::{get_stats, health, manage_cache, StatsState},
+    cancel_all_requests, cancel_queued, cancel_request, chat_batch, chat_completions,
+    chat_optimized, chat_query, chat_ws, create_embeddings, delete_model, enqueue_request,
+    generate, get_cache_entries, get_config, get_conversation, get_queue_status, get_stats,
+    get_system_prompt, health, list_models, list_queue, liveness, manage_cache, metrics,
+    pull_model, readiness, stream_queued_request, update_system_prompt, AppState, GenerationLimit,
+    QueueState, SharedAllowedModels, SharedConfig, SharedSystemPrompt, StatsState,
+    StreamRestartPolicy, MAX_STREAM_DELAY_MS,
 };
-use crate::services::{BatchProcessor, CacheService, OllamaClient, QueueService};
+use crate::middleware::{
+    access_log, client_ip, cors_layer, rate_limit, request_id, require_admin, RateLimiter,
+    TrustedProxies,
+};
+use crate::services::{
+    AuditLog, BatchProcessor, CacheService, ConversationStore, OllamaClient, QueueService,
+    QueueWorker,
+};
+use crate::utils::template::UserWrap;
+use crate::utils::think::ThinkTags;
+use anyhow::Context;
 use axum::{
+    http::HeaderName,
     routing::{delete, get, post},
     Router,
 };
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tower_http::compression::CompressionLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     // Initialize tracing
     tracing_subscriber::registry()
         .with(
@@ -34,19 +53,72 @@ async fn main() -> anyhow::Result<()> {
     let config = Config::load()?;
     tracing::info!("Configuration loaded successfully");
 
+    // Size the runtime from `server.workers`, so it has to be built after the config is read
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.server.workers.max(1))
+        .enable_all()
+        .build()
+        .context("failed to start the async runtime")?
+        .block_on(run(config))
+}
+
+async fn run(config: Config) -> anyhow::Result<()> {
+    // Configure CORS; invalid entries stop startup here
+    let cors = cors_layer(&config.cors)?;
+    let request_id_header = HeaderName::try_from(config.server.request_id_header.as_str())
+        .context("invalid server.request_id_header")?;
+
+    // Install the Prometheus recorder before any metrics are emitted
+    let metrics_handle = utils::metrics::install()?;
+
     // Initialize services
-    let response_cache = CacheService::new(config.cache.clone());
-    let conversation_cache = CacheService::new(config.conversation_cache.clone());
+    let response_cache = CacheService::new(config.cache.clone()).with_label("response");
+    let conversation_cache =
+        CacheService::new(config.conversation_cache.clone()).with_label("conversation");
     let ollama_client = OllamaClient::new(config.ollama.clone());
     let queue_service = Arc::new(QueueService::new(config.queue.clone()));
 
+    // Restore persisted cache entries
+    for cache in [&response_cache, &conversation_cache] {
+        if let Err(e) = cache.check_backend().await {
+            tracing::error!("❌ Cache backend unavailable, caching will miss: {}", e);
+        }
+        if let Err(e) = cache.load_from_disk().await {
+            tracing::warn!("Failed to load persisted cache: {}", e);
+        }
+        cache.spawn_persistence_task();
+    }
+
+    // Restore requests that were still queued when the server last stopped
+    if let Err(e) = queue_service.load_from_disk().await {
+        tracing::warn!("Failed to load persisted queue: {}", e);
+    }
+
     // Check Ollama connectivity
+    let ollama_urls = config.ollama.backend_urls().join(", ");
     match ollama_client.health_check().await {
-        Ok(true) => tracing::info!("✅ Ollama is available at {}", config.ollama.api_url),
+        Ok(true) => tracing::info!("✅ Ollama is available at {}", ollama_urls),
         Ok(false) | Err(_) => {
-            tracing::warn!("⚠️  Ollama may not be available at {}", config.ollama.api_url);
+            tracing::warn!("⚠️  Ollama may not be available at {}", ollama_urls);
         }
     }
+    ollama_client.spawn_health_monitor();
+    ollama_client.spawn_keep_warm(config.ollama.model.clone());
+
+    // Catch a missing model now rather than on the first real request
+    match ollama_client.model_available(&config.ollama.model).await {
+        Ok(true) => tracing::info!("✅ Model {} is available", config.ollama.model),
+        Ok(false) => tracing::error!(
+            "❌ Model {} is not installed on Ollama; run `ollama pull {}` or POST /api/models/pull",
+            config.ollama.model,
+            config.ollama.model
+        ),
+        Err(e) => tracing::warn!(
+            "⚠️  Could not check for model {}: {}",
+            config.ollama.model,
+            e
+        ),
+    }
 
     // Initialize batch processor
     let batch_processor = BatchProcessor::new(
@@ -55,53 +127,177 @@ async fn main() -> anyhow::Result<()> {
         config.batch.clone(),
     );
 
-    // Warm model on startup
-    tracing::info!("🔥 Warming model...");
-    if let Err(e) = batch_processor.warm_model(&config.ollama.model).await {
-        tracing::warn!("Failed to warm model: {}", e);
-    }
+    let audit = AuditLog::open(&config.audit).await?;
+    let user_wrap = UserWrap::new(&config.ollama);
+
+    // Start the queue worker
+    QueueWorker::new(
+        queue_service.clone(),
+        ollama_client.clone(),
+        config.queue.clone(),
+        user_wrap.clone(),
+        audit.clone(),
+    )
+    .spawn();
+
+    // Default system prompt, replaceable at runtime
+    let system_prompt = Arc::new(RwLock::new(config.ollama.system_prompt.clone()));
+    let allowed_models = Arc::new(RwLock::new(config.ollama.allowed_models.clone()));
+    let effective_config = Arc::new(RwLock::new(config.clone()));
+    let stream_delay_ms = config.server.stream_delay_ms.min(MAX_STREAM_DELAY_MS);
+
+    // Rate limit the routes that reach Ollama
+    let rate_limiter = RateLimiter::new(config.rate_limit.clone());
 
     // Create shared state for chat handler
     let app_state = Arc::new(AppState {
         cache: response_cache.clone(),
-        conversation_cache: conversation_cache.clone(),
-        ollama: ollama_client,
+        conversations: ConversationStore::new(
+            conversation_cache.clone(),
+            config.conversation.clone(),
+        ),
+        ollama: ollama_client.clone(),
+        batch: batch_processor.clone(),
         model: config.ollama.model.clone(),
-        system_prompt: config.ollama.system_prompt.clone(),
+        system_prompt: system_prompt.clone(),
+        allowed_models: allowed_models.clone(),
+        limits: config.limits.clone(),
+        generation_limit: GenerationLimit::new(&config.ollama),
+        bulk_concurrency: config.batch.bulk_concurrency,
+        think_tags: ThinkTags::from_config(&config.ollama),
+        heartbeat_interval: (config.server.sse_heartbeat_seconds > 0)
+            .then(|| Duration::from_secs(config.server.sse_heartbeat_seconds)),
+        stream_delay: Duration::from_millis(stream_delay_ms),
+        stream_restart: StreamRestartPolicy::new(&config.ollama),
+        return_partial_on_timeout: config.ollama.return_partial_on_timeout,
+        user_wrap,
+        fallback_model: config.ollama.fallback_model.clone(),
+        audit,
+        rate_limiter: rate_limiter.clone(),
     });
 
     // Create shared state for stats handler
+    let ready = Arc::new(AtomicBool::new(false));
     let stats_state = Arc::new(StatsState {
-        response_cache,
-        conversation_cache,
-        batch_processor,
+        response_cache: response_cache.clone(),
+        conversation_cache: conversation_cache.clone(),
+        batch_processor: batch_processor.clone(),
         queue: queue_service.clone(),
+        ollama: ollama_client,
+        model: config.ollama.model.clone(),
+        system_prompt: system_prompt.clone(),
+        config: effective_config.clone(),
+        ready: ready.clone(),
+        upstream_health: Default::default(),
     });
 
-    // Configure CORS
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    // Warm the model while already serving; `/health/ready` answers 503 until it is done
+    let warm_model = config.ollama.model.clone();
+    tokio::spawn(async move {
+        tracing::info!("🔥 Warming model...");
+        if let Err(e) = batch_processor.warm_model(&warm_model).await {
+            tracing::warn!("Failed to warm model: {}", e);
+        }
+        ready.store(true, Ordering::Relaxed);
+        tracing::info!("✅ Ready to serve traffic");
+    });
 
-    // Build router
-    let app = Router::new()
-        // Health check
-        .route("/health", get(health))
-        // Chat endpoints
-        .route("/api/chat-optimized", post(chat_optimized))
-        .with_state(app_state)
-        // Queue endpoints
+    // WebSocket chat charges the rate limit per request, not per connection
+    let ws_routes = Router::new()
+        .route("/ws/chat", get(chat_ws))
+        .with_state(app_state.clone());
+
+    // Chat endpoints
+    let chat_routes = Router::new()
+        .route("/api/chat-optimized", post(chat_optimized).get(chat_query))
+        .route("/api/chat-batch", post(chat_batch))
+        .route("/api/generate", post(generate))
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/api/embeddings", post(create_embeddings))
+        .route("/api/conversations/:id", get(get_conversation))
+        .with_state(app_state);
+
+    // Queue endpoints
+    let queue_routes = Router::new()
         .route("/api/chat-queue", post(enqueue_request))
         .route("/api/chat-queue", get(get_queue_status))
         .route("/api/chat-queue", delete(cancel_request))
-        .with_state(queue_service)
-        // Stats endpoints
+        .route("/api/chat-queue/stream", get(stream_queued_request))
+        .route("/api/chat-queue/all", delete(cancel_all_requests))
+        .with_state(Arc::new(QueueState {
+            queue: queue_service.clone(),
+            limits: config.limits.clone(),
+            allowed_models: allowed_models.clone(),
+        }));
+
+    // Stats endpoints
+    let stats_routes = Router::new()
         .route("/api/cache-stats", get(get_stats))
         .route("/api/cache-stats", post(manage_cache))
-        .with_state(stats_state)
-        // Add CORS
-        .layer(cors);
+        .route("/api/cache-entries", get(get_cache_entries))
+        .route("/api/models", get(list_models))
+        .route("/api/models/pull", post(pull_model))
+        .route("/api/system-prompt", get(get_system_prompt))
+        .with_state(stats_state.clone());
+
+    // Admin endpoints, behind `server.admin_api_key`
+    let admin_routes = Router::new()
+        .route("/api/config", get(get_config))
+        .route("/api/system-prompt", post(update_system_prompt))
+        .route("/api/models/*name", delete(delete_model))
+        .route("/api/queue/list", get(list_queue))
+        .route("/api/queue/list/:id", delete(cancel_queued))
+        .with_state(stats_state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            config.server.admin_api_key.clone(),
+            require_admin,
+        ));
+
+    // Reapply the reloadable settings on SIGHUP
+    spawn_reload_handler(Reloadable {
+        config: effective_config,
+        file_system_prompt: config.ollama.system_prompt.clone(),
+        system_prompt,
+        allowed_models,
+        response_cache: response_cache.clone(),
+        conversation_cache: conversation_cache.clone(),
+        rate_limiter: rate_limiter.clone(),
+    });
+    let rate_limit_layer = axum::middleware::from_fn_with_state(rate_limiter, rate_limit);
+    let limited_routes = chat_routes.merge(queue_routes).layer(rate_limit_layer);
+
+    // Build router
+    let app = Router::new()
+        // Health check
+        .route("/health", get(health).with_state(stats_state.clone()))
+        .route("/health/live", get(liveness))
+        .route("/health/ready", get(readiness).with_state(stats_state))
+        .route("/metrics", get(metrics).with_state(metrics_handle))
+        .merge(limited_routes)
+        .merge(ws_routes)
+        .merge(stats_routes)
+        .merge(admin_routes);
+
+    // The default predicate skips `text/event-stream`, so SSE stays unbuffered
+    let app = if config.server.compression {
+        app.layer(CompressionLayer::new())
+    } else {
+        app
+    };
+    // Add CORS
+    let app = app.layer(cors);
+    // Outermost, so latency covers every other layer
+    let app = app.layer(access_log());
+    // Outside the access log and rate limiter, which both use the resolved address
+    let app = app.layer(axum::middleware::from_fn_with_state(
+        TrustedProxies::new(config.server.trusted_proxies.clone()),
+        client_ip,
+    ));
+    // Outside the access log, so its span carries the id
+    let app = app.layer(axum::middleware::from_fn_with_state(
+        request_id_header,
+        request_id,
+    ));
 
     // Start server
     let addr = format!("{}:{}", config.server.host, config.server.port);
@@ -110,14 +306,155 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("🚀 Server listening on http://{}", addr);
     tracing::info!("📊 Endpoints:");
     tracing::info!("  - POST   /api/chat-optimized");
+    tracing::info!("  - POST   /api/chat-batch");
+    tracing::info!("  - GET    /ws/chat");
+    tracing::info!("  - POST   /api/generate");
+    tracing::info!("  - POST   /api/embeddings");
+    tracing::info!("  - GET    /api/conversations/:id");
     tracing::info!("  - POST   /api/chat-queue");
     tracing::info!("  - GET    /api/chat-queue");
     tracing::info!("  - DELETE /api/chat-queue");
+    tracing::info!("  - GET    /api/chat-queue/stream");
+    tracing::info!("  - DELETE /api/chat-queue/all");
     tracing::info!("  - GET    /api/cache-stats");
     tracing::info!("  - POST   /api/cache-stats");
+    tracing::info!("  - GET    /api/cache-entries");
+    tracing::info!("  - GET    /api/models");
+    tracing::info!("  - POST   /api/models/pull");
+    tracing::info!("  - GET    /api/system-prompt");
+    tracing::info!("  - POST   /api/system-prompt");
+    tracing::info!("  - GET    /api/config");
+    tracing::info!("  - GET    /api/queue/list");
+    tracing::info!("  - DELETE /api/queue/list/:id");
+    tracing::info!("  - GET    /metrics");
     tracing::info!("  - GET    /health");
+    tracing::info!("  - GET    /health/live");
+    tracing::info!("  - GET    /health/ready");
+
+    // In-flight SSE streams are allowed to complete before the server exits
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_seconds);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal(queue_service, shutdown_timeout))
+    .await?;
 
-    axum::serve(listener, app).await?;
+    // Save caches before exiting
+    for cache in [&response_cache, &conversation_cache] {
+        if let Err(e) = cache.save_to_disk().await {
+            tracing::warn!("Failed to persist cache on shutdown: {}", e);
+        }
+    }
 
     Ok(())
 }
+
+/// Settings a SIGHUP reloads from the config file without a restart
+struct Reloadable {
+    /// Settings in effect; the rest keep their startup values and changes to them are only
+    /// logged
+    config: SharedConfig,
+    /// Prompt from the last loaded file, so one set through `/api/system-prompt` is only
+    /// replaced when the file's prompt changes
+    file_system_prompt: String,
+    system_prompt: SharedSystemPrompt,
+    allowed_models: SharedAllowedModels,
+    response_cache: CacheService,
+    conversation_cache: CacheService,
+    rate_limiter: RateLimiter,
+}
+
+impl Reloadable {
+    /// Load and validate the config again, keeping the current settings if that fails
+    async fn reload(&mut self) {
+        let config = match Config::load() {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("❌ Config reload failed, keeping current settings: {:#}", e);
+                return;
+            }
+        };
+
+        let mut effective = self.config.write().await;
+        for setting in effective.restart_required(&config) {
+            tracing::warn!("⚠️  {} changed; restart to apply it", setting);
+        }
+
+        if config.ollama.system_prompt != self.file_system_prompt {
+            *self.system_prompt.write().await = config.ollama.system_prompt.clone();
+            self.file_system_prompt = config.ollama.system_prompt.clone();
+        }
+        *self.allowed_models.write().await = config.ollama.allowed_models.clone();
+        self.response_cache.set_ttl(config.cache.ttl_seconds);
+        self.conversation_cache
+            .set_ttl(config.conversation_cache.ttl_seconds);
+        self.rate_limiter.set_config(config.rate_limit.clone());
+
+        effective.ollama.system_prompt = config.ollama.system_prompt;
+        effective.ollama.allowed_models = config.ollama.allowed_models;
+        effective.cache.ttl_seconds = config.cache.ttl_seconds;
+        effective.conversation_cache.ttl_seconds = config.conversation_cache.ttl_seconds;
+        effective.rate_limit = config.rate_limit;
+
+        tracing::info!("🔄 Configuration reloaded");
+    }
+}
+
+#[cfg(unix)]
+fn spawn_reload_handler(mut reloadable: Reloadable) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                tracing::warn!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            tracing::info!("🔄 SIGHUP received, reloading configuration");
+            reloadable.reload().await;
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn spawn_reload_handler(_reloadable: Reloadable) {}
+
+/// Resolve on Ctrl+C or SIGTERM once the queue has been drained
+async fn shutdown_signal(queue: Arc<QueueService>, timeout: Duration) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("🛑 Shutdown signal received, draining queue...");
+    let remaining = queue.drain(timeout).await;
+    if remaining > 0 {
+        tracing::warn!("⚠️  {} requests were still queued at shutdown", remaining);
+    } else {
+        tracing::info!("✅ Queue drained");
+    }
+}
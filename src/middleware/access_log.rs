@@ -0,0 +1,124 @@
+use super::{ClientIp, RequestId};
+use axum::http::{Request, Response};
+use std::time::Duration;
+use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
+use tower_http::trace::{MakeSpan, OnResponse, TraceLayer};
+use tracing::{field, Span};
+
+/// Target for access log lines, so they can be tuned separately through `RUST_LOG`
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+pub type AccessLogLayer =
+    TraceLayer<SharedClassifier<ServerErrorsAsFailures>, AccessSpan, (), AccessLog>;
+
+/// One access log line per request with method, path, status and latency
+pub fn access_log() -> AccessLogLayer {
+    TraceLayer::new_for_http()
+        .make_span_with(AccessSpan)
+        .on_request(())
+        .on_response(AccessLog)
+}
+
+/// Opens the request span, tagged with the [`RequestId`] and [`ClientIp`] when their
+/// middleware runs first; handlers fill in `model` and `cache_hit` via [`record_chat`]
+#[derive(Clone, Copy, Debug)]
+pub struct AccessSpan;
+
+impl<B> MakeSpan<B> for AccessSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let span = tracing::info_span!(
+            target: ACCESS_LOG_TARGET,
+            "request",
+            method = %request.method(),
+            path = %request.uri().path(),
+            request_id = field::Empty,
+            client_ip = field::Empty,
+            model = field::Empty,
+            cache_hit = field::Empty,
+        );
+        if let Some(RequestId(id)) = request.extensions().get::<RequestId>() {
+            span.record("request_id", id.as_str());
+        }
+        if let Some(ClientIp(ip)) = request.extensions().get::<ClientIp>() {
+            span.record("client_ip", field::display(ip));
+        }
+        span
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AccessLog;
+
+impl<B> OnResponse<B> for AccessLog {
+    fn on_response(self, response: &Response<B>, latency: Duration, _span: &Span) {
+        tracing::info!(
+            target: ACCESS_LOG_TARGET,
+            status = response.status().as_u16(),
+            latency_ms = latency.as_millis() as u64,
+            "📨 Request completed"
+        );
+    }
+}
+
+/// Attach the chat outcome to the current request span
+pub fn record_chat(model: &str, cache_hit: bool) {
+    let span = Span::current();
+    span.record("model", model);
+    span.record("cache_hit", cache_hit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Collects formatted log output for assertions
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_includes_chat_fields() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route(
+                "/chat",
+                get(|| async {
+                    record_chat("llama3", true);
+                    "ok"
+                }),
+            )
+            .layer(access_log());
+        let request = Request::builder().uri("/chat").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("method=GET"), "{output}");
+        assert!(output.contains("path=/chat"), "{output}");
+        assert!(output.contains("model=\"llama3\""), "{output}");
+        assert!(output.contains("cache_hit=true"), "{output}");
+        assert!(output.contains("status=200"), "{output}");
+        assert!(output.contains("latency_ms="), "{output}");
+    }
+}
@@ -0,0 +1,83 @@
+use crate::config::Secret;
+use crate::handlers::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Only let requests carrying `Authorization: Bearer <server.admin_api_key>` through; with no
+/// key configured the admin endpoints are disabled
+pub async fn require_admin(
+    State(key): State<Option<Secret>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = key else {
+        return ApiError::Forbidden(
+            "admin endpoints are disabled; set server.admin_api_key".into(),
+        )
+        .into_response();
+    };
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), key.expose().as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            tracing::warn!("🔒 Rejected admin request to {}", request.uri().path());
+            ApiError::Unauthorized("missing or invalid admin API key".to_string()).into_response()
+        }
+    }
+}
+
+/// Compare without returning early, so timing doesn't reveal how much of the key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn status(key: Option<Secret>, authorization: Option<&str>) -> StatusCode {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(key, require_admin));
+        let mut request = Request::builder().uri("/");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_require_the_configured_key() {
+        let key = Some(Secret::from("s3cret"));
+        assert_eq!(
+            status(key.clone(), Some("Bearer s3cret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(key.clone(), Some("Bearer wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(key, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(None, Some("Bearer s3cret")).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// The client's address as resolved by [`client_ip`], for rate limiting and logging
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+/// `server.trusted_proxies`: peers allowed to report the client's address
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpNet>) -> Self {
+        Self(Arc::new(proxies))
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The socket address, unless it is a trusted proxy; then the last `X-Forwarded-For` hop
+    /// that isn't one, since anything left of it could have been made up by the client
+    pub fn resolve(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        let peer = peer.ip();
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            // Past an unreadable hop, nothing can be vouched for
+            let Ok(ip) = hop.trim().parse() else {
+                break;
+            };
+            client = ip;
+            if !self.contains(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Resolve the client IP once per request and store it as a [`ClientIp`] extension
+pub async fn client_ip(
+    State(trusted): State<TrustedProxies>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ip = trusted.resolve(request.headers(), peer);
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_for_is_only_trusted_from_proxies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let trusted = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.7, 10.0.0.2".parse().unwrap(),
+        );
+
+        // Straight from a client, the header is ignored
+        let client: SocketAddr = "192.0.2.9:5000".parse().unwrap();
+        assert_eq!(trusted.resolve(&headers, client), ip("192.0.2.9"));
+        assert_eq!(
+            TrustedProxies::default().resolve(&headers, client),
+            ip("192.0.2.9")
+        );
+
+        // Through the proxies, the nearest untrusted hop; the client could have sent the rest
+        let proxy: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert_eq!(trusted.resolve(&headers, proxy), ip("203.0.113.7"));
+
+        let only_proxies = HeaderMap::from_iter([(
+            axum::http::header::HeaderName::from_static("x-forwarded-for"),
+            "10.0.0.3".parse().unwrap(),
+        )]);
+        assert_eq!(trusted.resolve(&only_proxies, proxy), ip("10.0.0.3"));
+        assert_eq!(trusted.resolve(&HeaderMap::new(), proxy), ip("10.0.0.1"));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::CorsConfig;
+use anyhow::{Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+
+/// Build the CORS layer from `[cors]`; a `"*"` entry allows anything for that list
+pub fn cors_layer(config: &CorsConfig) -> Result<CorsLayer> {
+    let origins = if is_wildcard(&config.allowed_origins) {
+        AllowOrigin::from(Any)
+    } else {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("invalid CORS origin {:?}", origin))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    let methods = if is_wildcard(&config.allowed_methods) {
+        AllowMethods::from(Any)
+    } else {
+        let methods = config
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .with_context(|| format!("invalid CORS method {:?}", method))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowMethods::list(methods)
+    };
+
+    let headers = if is_wildcard(&config.allowed_headers) {
+        AllowHeaders::from(Any)
+    } else {
+        let headers = config
+            .allowed_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("invalid CORS header {:?}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowHeaders::list(headers)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers))
+}
+
+fn is_wildcard(entries: &[String]) -> bool {
+    entries.iter().any(|entry| entry == "*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn config(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["content-type".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_configured_origins_are_allowed() {
+        let layer = cors_layer(&config(&["https://app.example.com"])).unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(layer);
+
+        for (origin, allowed) in [
+            ("https://app.example.com", true),
+            ("https://evil.example.com", false),
+        ] {
+            let request = Request::builder()
+                .uri("/")
+                .header("origin", origin)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let header = response.headers().get("access-control-allow-origin");
+            assert_eq!(header.is_some(), allowed, "{origin}");
+        }
+
+        assert!(cors_layer(&config(&["*"])).is_ok());
+        assert!(cors_layer(&config(&["https://bad\norigin"])).is_err());
+    }
+}
@@ -0,0 +1,13 @@
+pub mod access_log;
+pub mod admin_auth;
+pub mod client_ip;
+pub mod cors;
+pub mod rate_limit;
+pub mod request_id;
+
+pub use access_log::{access_log, record_chat};
+pub use admin_auth::require_admin;
+pub use client_ip::{client_ip, ClientIp, TrustedProxies};
+pub use cors::cors_layer;
+pub use rate_limit::{rate_limit, RateLimiter};
+pub use request_id::{request_id, RequestId};
@@ -0,0 +1,129 @@
+use super::ClientIp;
+use crate::config::RateLimitConfig;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Buckets idle for longer than this are dropped when the table grows large
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(600);
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token-bucket rate limiter keyed on client IP
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+    /// Replaced when the config is reloaded
+    config: Arc<RwLock<RateLimitConfig>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    /// Apply new limits; existing buckets refill at the new rate from now on
+    pub fn set_config(&self, config: RateLimitConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Take a token for `ip`, or return how long until one is available
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let config = self.config.read().unwrap().clone();
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let capacity = config.burst.max(1) as f64;
+        let per_second = config.requests_per_minute.max(1) as f64 / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, b| now.duration_since(b.last_refill) < IDLE_BUCKET_TTL);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// Reject requests over the per-IP limit with 429 and a `Retry-After` header. Keyed on the
+/// [`ClientIp`] when the `client_ip` middleware ran, otherwise on the socket address.
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(peer.ip(), |ClientIp(ip)| *ip);
+
+    match limiter.check(ip) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            tracing::warn!("🚦 Rate limit exceeded for {}", ip);
+            let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            let mut response =
+                (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded").into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_reject() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            enabled: true,
+            requests_per_minute: 60,
+            burst: 2,
+        });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(limiter.check(ip).is_ok());
+        assert!(limiter.check(ip).is_ok());
+        let retry_after = limiter.check(ip).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+
+        // Buckets are independent per client
+        assert!(limiter.check(other).is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::convert::Infallible;
+use uuid::Uuid;
+
+/// Client-supplied ids longer than this are replaced with a generated one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Correlates logs, stream chunks and the response of one request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+}
+
+/// The id assigned by [`request_id`], or a fresh one when the middleware is not installed
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(RequestId::generate))
+    }
+}
+
+/// Take the request id from `header`, generating one if absent, and echo it in the response
+pub async fn request_id(
+    State(header): State<HeaderName>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let id = request
+        .headers()
+        .get(&header)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(|id| RequestId(id.to_string()))
+        .unwrap_or_else(RequestId::generate);
+    let value = HeaderValue::from_str(&id.0).ok();
+    request.extensions_mut().insert(id);
+
+    let mut response = next.run(request).await;
+    if let Some(value) = value {
+        response.headers_mut().insert(header, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_request_id_is_propagated_and_echoed() {
+        let header = HeaderName::from_static("x-request-id");
+        let app = Router::new()
+            .route("/", get(|RequestId(id): RequestId| async move { id }))
+            .layer(axum::middleware::from_fn_with_state(
+                header.clone(),
+                request_id,
+            ));
+
+        let request = Request::builder()
+            .uri("/")
+            .header(&header, "trace-123")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[&header], "trace-123");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "trace-123");
+
+        // Without the header an id is generated and echoed
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let generated = response.headers()[&header].to_str().unwrap();
+        assert!(Uuid::parse_str(generated).is_ok());
+    }
+}
@@ -1,10 +1,86 @@
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Who wrote a message; sent to Ollama as its lowercase name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Case and surrounding whitespace are ignored; `developer` is OpenAI's newer name for the
+/// system role
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "system" | "developer" => Ok(Role::System),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            "tool" => Ok(Role::Tool),
+            _ => Err(format!(
+                "unknown role `{s}`; expected system, user, assistant or tool"
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let role = String::deserialize(deserializer)?;
+        role.parse().map_err(serde::de::Error::custom)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
-    pub role: String,
+    pub role: Role,
+    #[serde(default)]
     pub content: String,
+    /// Tool invocations requested by the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<serde_json::Value>>,
+    /// Base64-encoded images for multimodal models such as llava
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+}
+
+impl ChatMessage {
+    /// Whether the model asked for tools to be called instead of answering
+    pub fn has_tool_calls(&self) -> bool {
+        self.tool_calls
+            .as_ref()
+            .is_some_and(|calls| !calls.is_empty())
+    }
+
+    pub fn has_images(&self) -> bool {
+        self.images
+            .as_ref()
+            .is_some_and(|images| !images.is_empty())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -20,6 +96,90 @@ pub struct ChatRequest {
     pub priority: i32,
     #[serde(default = "default_true")]
     pub use_cache: bool,
+    /// Expire this reply after this many seconds, at most the configured TTL; 0 skips caching
+    #[serde(default)]
+    pub cache_ttl_seconds: Option<u64>,
+    /// Only answer from cache; a miss returns 204 No Content instead of calling Ollama
+    #[serde(default)]
+    pub cache_only: bool,
+    /// Generate afresh even when a reply is cached, and replace the cached one with it
+    #[serde(default)]
+    pub regenerate: bool,
+    /// When set, prior turns for this session are prepended and the exchange is stored
+    #[serde(default)]
+    pub session_id: Option<String>,
+    /// Sampling options; unset fields fall back to the configured defaults
+    #[serde(default)]
+    pub options: Option<OllamaOptions>,
+    /// Output format passed through to Ollama; `"json"` forces and validates JSON replies
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Tool definitions the model may call, passed through to Ollama
+    #[serde(default)]
+    pub tools: Option<Vec<serde_json::Value>>,
+    /// Sequences that halt generation, sent as Ollama's `options.stop`
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    /// How long Ollama keeps the model loaded afterwards (e.g. `"5m"`, `"0"`), overriding
+    /// `ollama.keep_alive` and `ollama.keep_alive_by_model`
+    #[serde(default)]
+    pub keep_alive: Option<String>,
+    /// Values for `{{name}}` placeholders in the system prompt, alongside `date` and `time`
+    #[serde(default)]
+    pub template_vars: Option<HashMap<String, String>>,
+    /// Minimum gap between streamed chunks in ms, overriding `server.stream_delay_ms`
+    #[serde(default)]
+    pub stream_delay_ms: Option<u64>,
+    /// Echo the request's messages plus the reply in a non-streaming response
+    #[serde(default)]
+    pub include_messages: bool,
+}
+
+impl ChatRequest {
+    /// Non-streaming request for a single user prompt, with every other field at its default
+    pub fn from_prompt(prompt: String, model: Option<String>) -> Self {
+        Self {
+            messages: vec![ChatMessage {
+                role: Role::User,
+                content: prompt,
+                tool_calls: None,
+                images: None,
+            }],
+            model,
+            system_prompt: None,
+            stream: false,
+            priority: 0,
+            use_cache: true,
+            cache_ttl_seconds: None,
+            cache_only: false,
+            regenerate: false,
+            session_id: None,
+            options: None,
+            format: None,
+            tools: None,
+            stop: None,
+            keep_alive: None,
+            template_vars: None,
+            stream_delay_ms: None,
+            include_messages: false,
+        }
+    }
+
+    /// Whether the reply must be valid JSON
+    pub fn wants_json(&self) -> bool {
+        self.format.as_deref() == Some("json")
+    }
+
+    /// Per-request generation options, with `stop` folded in
+    pub fn generation_options(&self) -> Option<OllamaOptions> {
+        match &self.stop {
+            Some(stop) => Some(OllamaOptions {
+                stop: Some(stop.clone()),
+                ..self.options.clone().unwrap_or_default()
+            }),
+            None => self.options.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -27,9 +187,36 @@ pub struct ChatResponse {
     pub message: ChatMessage,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cached: Option<bool>,
+    /// How many of the oldest messages were left out to fit the context limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped_messages: Option<usize>,
+    /// Set when the requested model failed to load and `ollama.fallback_model` answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_used: Option<bool>,
+    /// The request's messages followed by the reply, with `include_messages`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub messages: Option<Vec<ChatMessage>>,
+    /// Why Ollama stopped: `"stop"`, or `"length"` when the reply hit the token limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ChatResponse {
+    /// Fill in `messages` with `input` followed by the reply
+    pub fn echo_messages(&mut self, input: &[ChatMessage]) {
+        let mut messages = input.to_vec();
+        messages.push(self.message.clone());
+        self.messages = Some(messages);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationResponse {
+    pub session_id: String,
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct StreamChunk {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
@@ -40,6 +227,59 @@ pub struct StreamChunk {
     pub cached: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    /// Class of `error`, so clients can decide whether to retry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<StreamErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<serde_json::Value>>,
+    /// Set on the final chunk when a timeout cut the reply short; `content` then holds the
+    /// whole partial reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<bool>,
+    /// Why Ollama stopped, on the final chunk of a fresh reply: `"stop"`, or `"length"` when
+    /// the reply hit the token limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+}
+
+/// Why a stream ended with an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamErrorCode {
+    /// Ollama could not be reached or the connection dropped; worth retrying
+    UpstreamUnavailable,
+    /// Ollama stopped sending output or did not answer in time; worth retrying, unless the
+    /// generation ran past `ollama.max_generation_seconds`
+    Timeout,
+    /// Ollama rejected the request, e.g. for an unknown model
+    UpstreamError,
+    /// The request was cancelled before it finished
+    Cancelled,
+    /// Ollama's output could not be parsed, or was not the JSON the request asked for
+    ParseError,
+    /// A WebSocket request was refused by the rate limit, or because too many were waiting on
+    /// the socket; worth retrying later
+    RateLimited,
+}
+
+/// Sent as an `event: restart` SSE event when a dropped stream starts over; content received
+/// before it should be discarded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamRestarted {
+    pub request_id: String,
+    pub reason: String,
+}
+
+/// Sent as an `event: meta` SSE event before any content chunks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamMeta {
+    pub cached: bool,
+    pub model: String,
+    pub request_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped_messages: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_used: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,6 +289,94 @@ pub struct QueueRequest {
     pub model: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub system_prompt: Option<String>,
+    /// Clamped to `0..=queue.max_priority`; `queue.default_priority` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmbeddingRequest {
+    pub input: EmbeddingInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+/// A single prompt or a batch of prompts to embed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_prompts(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::Single(prompt) => vec![prompt],
+            EmbeddingInput::Batch(prompts) => prompts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingResponse {
+    pub model: String,
+    pub embeddings: Vec<Vec<f32>>,
+}
+
+/// Independent chat requests answered in one call to `/api/chat-batch`
+#[derive(Debug, Clone, Deserialize)]
+pub struct BulkChatRequest {
+    pub requests: Vec<ChatRequest>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkChatResponse {
+    /// One result per request, in input order
+    pub responses: Vec<BulkChatResult>,
+}
+
+/// Reply to one item of a bulk request; failed items carry `error` instead of `message`
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkChatResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<ChatMessage>,
+    pub cached: bool,
+    /// How many of the oldest messages were left out to fit the context limits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped_messages: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SystemPromptRequest {
+    pub system_prompt: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemPromptResponse {
+    pub system_prompt: String,
+}
+
+/// Single-shot completion request for `/api/generate`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GenerateRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default = "default_true")]
+    pub use_cache: bool,
+    /// Sampling options; unset fields fall back to the configured defaults
+    #[serde(default)]
+    pub options: Option<OllamaOptions>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GenerateResponse {
+    pub model: String,
+    pub response: String,
+    pub cached: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -65,12 +393,38 @@ pub struct QueueStatus {
     pub is_processing: bool,
 }
 
+/// One waiting request in `GET /api/queue/list`; its content is left out unless asked for
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedRequestSummary {
+    pub request_id: String,
+    pub model: String,
+    pub priority: i32,
+    pub queue_position: usize,
+    pub enqueued_at: Option<String>,
+    pub message_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages: Option<Vec<ChatMessage>>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct QueueStatusResponse {
     pub request_id: String,
     pub completed: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<QueueStatus>,
+    /// The generated reply once the request has completed successfully
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<ChatResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Classifies `error`, as on a stream's final chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<StreamErrorCode>,
+    pub cancelled: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -80,6 +434,33 @@ pub struct CacheStats {
     pub hit_rate: f64,
     pub miss_rate: f64,
     pub memory_usage_percent: f64,
+    /// Entries dropped to stay within `max_size_mb`. The `evictions_*` counts are always 0 on
+    /// the Redis backend, which evicts entries on its own.
+    pub evictions_size: u64,
+    /// Entries dropped when their TTL ran out
+    pub evictions_ttl: u64,
+    /// Entries removed by invalidation or clearing
+    pub evictions_explicit: u64,
+}
+
+/// A single response cache entry, as shown by the `inspect` cache action
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntryInfo {
+    pub key: String,
+    pub content: String,
+    pub created_at: Option<String>,
+    pub expires_in_seconds: u64,
+    pub hit_count: u64,
+}
+
+/// A frequently served cache entry, as listed by `GET /api/cache-entries`
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntrySummary {
+    /// Key prefix only; full keys are not exposed
+    pub key: String,
+    pub hit_count: u64,
+    pub created_at: Option<String>,
+    pub size_bytes: usize,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -91,6 +472,23 @@ pub struct BatchStats {
     pub average_batch_size: f64,
     pub cache_hit_rate: u32,
     pub deduplication_rate: u32,
+    /// Moving average of Ollama call latency, weighted towards recent calls
+    pub avg_latency_ms: f64,
+    pub p50_latency_ms: f64,
+    pub p95_latency_ms: f64,
+    /// Moving average of cache-hit latency, tracked apart so hits don't hide slow generations
+    pub cache_hit_latency_ms: f64,
+    /// Batched Ollama calls running now, at most `batch.max_concurrent_upstream`
+    pub upstream_in_flight: u64,
+    pub per_model: HashMap<String, ModelStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelStats {
+    pub total_requests: u64,
+    pub cache_hits: u64,
+    pub cache_hit_rate: f64,
+    pub average_latency_ms: f64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -101,6 +499,15 @@ pub struct SystemStats {
     pub batch_processor: BatchStats,
     pub queue_length: usize,
     pub is_processing: bool,
+    pub ollama_backends: Vec<BackendStatus>,
+}
+
+/// Health and load of one Ollama backend
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendStatus {
+    pub url: String,
+    pub healthy: bool,
+    pub in_flight: usize,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -110,10 +517,40 @@ pub struct CacheAction {
     pub data: Option<serde_json::Value>,
 }
 
+/// Body of `POST /api/cache-stats`: one action, or several run in order
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum CacheActions {
+    One(CacheAction),
+    Many(Vec<CacheAction>),
+}
+
+/// A prompt to pre-populate the response cache with
+#[derive(Debug, Clone, Deserialize)]
+pub struct WarmPrompt {
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ActionResponse {
     pub success: bool,
     pub message: String,
+    /// Action-specific details, e.g. the entry returned by `inspect`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+/// JSON body returned for failed API requests
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
 }
 
 // Ollama API types
@@ -124,15 +561,215 @@ pub struct OllamaRequest {
     pub stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaGenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
+pub struct OllamaGenerateResponse {
+    #[serde(default)]
+    pub response: String,
+}
+
+/// Generation options sent as Ollama's `options` object; `None` fields use Ollama's defaults
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// Context window in tokens; larger windows cost more memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+}
+
+impl OllamaOptions {
+    /// Fill unset fields from `defaults`
+    pub fn or(&self, defaults: &OllamaOptions) -> OllamaOptions {
+        OllamaOptions {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            num_predict: self.num_predict.or(defaults.num_predict),
+            seed: self.seed.or(defaults.seed),
+            stop: self.stop.clone().or_else(|| defaults.stop.clone()),
+            num_ctx: self.num_ctx.or(defaults.num_ctx),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == OllamaOptions::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct OllamaResponse {
     pub message: Option<ChatMessage>,
     #[serde(default)]
     pub done: bool,
+    /// Why generation stopped (`"stop"`, `"length"`), on the final response
+    #[serde(default)]
+    pub done_reason: Option<String>,
+    /// Prompt tokens evaluated, on the final response
+    #[serde(default)]
+    pub prompt_eval_count: Option<u32>,
+    /// Tokens generated, on the final response
+    #[serde(default)]
+    pub eval_count: Option<u32>,
+}
+
+impl OllamaResponse {
+    /// The assistant's message, empty if Ollama sent none
+    pub fn into_message(self) -> ChatMessage {
+        self.message.unwrap_or_else(|| ChatMessage {
+            role: Role::Assistant,
+            content: String::new(),
+            tool_calls: None,
+            images: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaEmbeddingRequest {
+    pub model: String,
+    pub prompt: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OllamaEmbeddingResponse {
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaModelInfo {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub modified_at: String,
+    #[serde(default)]
+    pub digest: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OllamaTagsResponse {
+    #[serde(default)]
+    pub models: Vec<OllamaModelInfo>,
+}
+
+/// Body of `POST /api/models/pull`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullModelRequest {
+    pub model: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaPullRequest {
+    pub model: String,
+    pub stream: bool,
+}
+
+/// Body of Ollama's `DELETE /api/delete`
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaDeleteRequest {
+    pub name: String,
+}
+
+/// What deleting a model did on one Ollama backend
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelDeletion {
+    pub url: String,
+    pub outcome: DeletionOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionOutcome {
+    Deleted,
+    /// The backend didn't have the model
+    NotFound,
+    /// The backend could not be reached or refused; `error` says why
+    Failed,
+}
+
+/// One line of Ollama's `/api/pull` progress stream
+#[derive(Debug, Clone, Deserialize)]
+pub struct OllamaPullResponse {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub total: Option<u64>,
+    #[serde(default)]
+    pub completed: Option<u64>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Pull progress for one backend, sent to clients as an SSE event
+#[derive(Debug, Clone, Serialize)]
+pub struct PullProgress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Download progress of the current layer, 0-100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 fn default_true() -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_are_parsed_leniently() {
+        let message: ChatMessage =
+            serde_json::from_str(r#"{"role": " User ", "content": "hi"}"#).unwrap();
+        assert_eq!(message.role, Role::User);
+        assert_eq!(serde_json::to_value(&message).unwrap()["role"], "user");
+        assert_eq!("Developer".parse(), Ok(Role::System));
+
+        let error = serde_json::from_str::<ChatMessage>(r#"{"role": "narrator"}"#).unwrap_err();
+        assert!(
+            error.to_string().contains("unknown role `narrator`"),
+            "{error}"
+        );
+    }
+}
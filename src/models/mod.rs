@@ -1,3 +1,5 @@
 pub mod chat;
+pub mod openai;
 
 pub use chat::*;
+pub use openai::*;
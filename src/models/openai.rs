@@ -0,0 +1,121 @@
+use crate::models::{ChatMessage, OllamaOptions};
+use serde::{Deserialize, Serialize};
+
+/// OpenAI-style `POST /v1/chat/completions` body; fields without an Ollama equivalent are
+/// ignored
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpenAiChatRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<i32>,
+    #[serde(default)]
+    pub seed: Option<i64>,
+    #[serde(default)]
+    pub stop: Option<StopSequences>,
+}
+
+/// OpenAI accepts a single stop string or a list
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OpenAiChatRequest {
+    /// Sampling settings as Ollama options, `None` when the request sets none
+    pub fn options(&self) -> Option<OllamaOptions> {
+        let options = OllamaOptions {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            num_predict: self.max_tokens,
+            seed: self.seed,
+            stop: self.stop.clone().map(|stop| match stop {
+                StopSequences::One(stop) => vec![stop],
+                StopSequences::Many(stops) => stops,
+            }),
+            ..Default::default()
+        };
+        (!options.is_empty()).then_some(options)
+    }
+}
+
+/// Non-streaming `chat.completion` response
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenAiCompletion {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<OpenAiChoice>,
+    pub usage: OpenAiUsage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenAiChoice {
+    pub index: u32,
+    pub message: ChatMessage,
+    pub finish_reason: String,
+}
+
+/// Token counts from Ollama's `prompt_eval_count` and `eval_count`; zero for cached replies
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct OpenAiUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl OpenAiUsage {
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+/// One `chat.completion.chunk` of a streamed completion
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenAiChunk {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<OpenAiChunkChoice>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenAiChunkChoice {
+    pub index: u32,
+    pub delta: OpenAiDelta,
+    /// `"stop"` on the last chunk, `null` before
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OpenAiDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl OpenAiDelta {
+    /// A content delta; `role` is only sent with the first one
+    pub fn content(role: Option<&str>, content: String) -> Self {
+        Self {
+            role: role.map(str::to_string),
+            content: Some(content),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::config::AuditConfig;
+use crate::models::ChatMessage;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// One answered chat request, as written to the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub response: String,
+    pub cached: bool,
+}
+
+/// Appends an [`AuditRecord`] per chat reply to `audit.path` as JSON lines. Records go through
+/// a bounded channel to a background writer, so requests never wait on the disk.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    /// `None` when auditing is disabled
+    sender: Option<mpsc::Sender<AuditRecord>>,
+}
+
+impl AuditLog {
+    /// Open the log for appending and start its writer; a no-op log when disabled
+    pub async fn open(config: &AuditConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(Self::default());
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .await
+            .with_context(|| format!("failed to open audit log {}", config.path))?;
+        let (sender, receiver) = mpsc::channel(config.buffer_size);
+        tokio::spawn(write_records(file, receiver));
+        tracing::info!("📝 Auditing chat requests to {}", config.path);
+        Ok(Self {
+            sender: Some(sender),
+        })
+    }
+
+    /// Queue a record without waiting; it is dropped with a warning if the writer has fallen
+    /// `audit.buffer_size` records behind
+    pub fn record(
+        &self,
+        request_id: &str,
+        model: &str,
+        messages: &[ChatMessage],
+        response: &str,
+        cached: bool,
+    ) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp: Utc::now(),
+            request_id: request_id.to_string(),
+            model: model.to_string(),
+            messages: messages.to_vec(),
+            response: response.to_string(),
+            cached,
+        };
+        match sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(record)) => {
+                tracing::warn!(
+                    "📝 Audit log is falling behind; dropped record for {}",
+                    record.request_id
+                );
+            }
+            Err(TrySendError::Closed(record)) => {
+                tracing::error!(
+                    "📝 Audit writer stopped; dropped record for {}",
+                    record.request_id
+                );
+            }
+        }
+    }
+}
+
+async fn write_records(file: File, mut receiver: mpsc::Receiver<AuditRecord>) {
+    let mut writer = BufWriter::new(file);
+    while let Some(record) = receiver.recv().await {
+        let mut line = serde_json::to_vec(&record).expect("audit records serialize");
+        line.push(b'\n');
+        if let Err(e) = writer.write_all(&line).await {
+            tracing::error!("Failed to write audit record {}: {}", record.request_id, e);
+        }
+        // Flush once caught up, so a quiet server doesn't hold records in the buffer
+        if receiver.is_empty() {
+            if let Err(e) = writer.flush().await {
+                tracing::error!("Failed to flush audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::models::Role;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// A log writing to a fresh temp file, and that file's path
+    pub(crate) async fn temp_log() -> (AuditLog, PathBuf) {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let config = AuditConfig {
+            enabled: true,
+            path: path.to_string_lossy().into_owned(),
+            buffer_size: 8,
+        };
+        (AuditLog::open(&config).await.unwrap(), path)
+    }
+
+    /// Wait for `count` records to reach the file, then parse them
+    pub(crate) async fn read_records(path: &Path, count: usize) -> Vec<serde_json::Value> {
+        let mut lines = Vec::new();
+        for _ in 0..50 {
+            let contents = tokio::fs::read_to_string(path).await.unwrap();
+            lines = contents.lines().map(str::to_string).collect();
+            if lines.len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(lines.len(), count);
+        let records = lines.iter().map(|line| serde_json::from_str(line).unwrap());
+        records.collect()
+    }
+
+    #[tokio::test]
+    async fn test_records_are_appended_as_json_lines() {
+        let (log, path) = temp_log().await;
+
+        let messages = vec![ChatMessage {
+            role: Role::User,
+            content: "What is Rust?".to_string(),
+            tool_calls: None,
+            images: None,
+        }];
+        log.record("req-1", "llama3", &messages, "A language.", false);
+        log.record("req-2", "llama3", &messages, "A language.", true);
+
+        let records = read_records(&path, 2).await;
+        let first = &records[0];
+        assert_eq!(first["request_id"], "req-1");
+        assert_eq!(first["model"], "llama3");
+        assert_eq!(first["messages"][0]["content"], "What is Rust?");
+        assert_eq!(first["response"], "A language.");
+        assert_eq!(first["cached"], false);
+        assert!(first["timestamp"].is_string());
+        assert_eq!(records[1]["cached"], true);
+
+        // Disabled, nothing is opened or written
+        let disabled = AuditLog::open(&AuditConfig::default()).await.unwrap();
+        disabled.record("req-3", "llama3", &messages, "ignored", false);
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use crate::models::BackendStatus;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// One Ollama server in the rotation
+#[derive(Debug)]
+struct Backend {
+    url: String,
+    healthy: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+/// Spreads requests across Ollama servers, preferring healthy ones with the fewest requests in flight
+#[derive(Debug)]
+pub struct BackendPool {
+    backends: Vec<Backend>,
+    next: AtomicUsize,
+}
+
+/// A backend checked out for one request; releases its in-flight slot on drop
+#[derive(Debug)]
+pub struct BackendLease {
+    pool: Arc<BackendPool>,
+    index: usize,
+}
+
+impl BackendPool {
+    pub fn new(urls: Vec<String>) -> Self {
+        assert!(!urls.is_empty(), "at least one Ollama backend is required");
+
+        let backends = urls
+            .into_iter()
+            .map(|url| Backend {
+                url: url.trim_end_matches('/').to_string(),
+                healthy: AtomicBool::new(true),
+                in_flight: AtomicUsize::new(0),
+            })
+            .collect();
+
+        Self {
+            backends,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick the healthy backend with the fewest in-flight requests, rotating between ties.
+    /// Falls back to every backend when none are healthy so requests can still be attempted.
+    pub fn acquire(self: &Arc<Self>) -> BackendLease {
+        let count = self.backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        let rotation = (0..count).map(|offset| (start + offset) % count);
+
+        let least_loaded = |healthy_only: bool| {
+            rotation
+                .clone()
+                .filter(|&i| !healthy_only || self.backends[i].healthy.load(Ordering::Relaxed))
+                .min_by_key(|&i| self.backends[i].in_flight.load(Ordering::Relaxed))
+        };
+        let index = least_loaded(true).unwrap_or_else(|| least_loaded(false).unwrap_or(start));
+
+        self.backends[index]
+            .in_flight
+            .fetch_add(1, Ordering::Relaxed);
+        BackendLease {
+            pool: self.clone(),
+            index,
+        }
+    }
+
+    /// Record the result of a health check, logging transitions
+    pub fn set_healthy(&self, index: usize, healthy: bool) {
+        let backend = &self.backends[index];
+        if backend.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                tracing::info!("✅ Ollama backend {} is back in rotation", backend.url);
+            } else {
+                tracing::warn!("⚠️  Ollama backend {} removed from rotation", backend.url);
+            }
+        }
+    }
+
+    pub fn urls(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.backends
+            .iter()
+            .enumerate()
+            .map(|(i, backend)| (i, backend.url.as_str()))
+    }
+
+    pub fn status(&self) -> Vec<BackendStatus> {
+        self.backends
+            .iter()
+            .map(|backend| BackendStatus {
+                url: backend.url.clone(),
+                healthy: backend.healthy.load(Ordering::Relaxed),
+                in_flight: backend.in_flight.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl BackendLease {
+    pub fn url(&self) -> &str {
+        &self.pool.backends[self.index].url
+    }
+
+    /// Take this backend out of rotation until the next successful health check
+    pub fn mark_unhealthy(&self) {
+        self.pool.set_healthy(self.index, false);
+    }
+}
+
+impl Drop for BackendLease {
+    fn drop(&mut self) {
+        self.pool.backends[self.index]
+            .in_flight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire_prefers_least_loaded_healthy_backend() {
+        let pool = Arc::new(BackendPool::new(vec![
+            "http://a:11434".to_string(),
+            "http://b:11434".to_string(),
+            "http://c:11434".to_string(),
+        ]));
+
+        // Each concurrent request lands on a different backend
+        let first = pool.acquire();
+        let second = pool.acquire();
+        assert_ne!(first.url(), second.url());
+
+        // Unhealthy backends are skipped while others are available
+        pool.set_healthy(2, false);
+        let third = pool.acquire();
+        assert_ne!(third.url(), "http://c:11434");
+
+        drop(first);
+        drop(second);
+        drop(third);
+        assert!(pool.status().iter().all(|b| b.in_flight == 0));
+
+        // With nothing healthy, requests still go somewhere
+        pool.set_healthy(0, false);
+        pool.set_healthy(1, false);
+        let _fallback = pool.acquire();
+    }
+}
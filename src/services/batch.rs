@@ -1,121 +1,526 @@
 use crate::config::BatchConfig;
-use crate::models::{BatchStats, ChatMessage};
-use crate::services::{CacheService, OllamaClient};
-use anyhow::Result;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::RwLock;
+use crate::models::{BatchStats, ChatMessage, ModelStats, Role, WarmPrompt};
+use crate::services::{
+    is_transient, CacheService, Flight, GenerationParams, OllamaClient, SingleFlight,
+};
+use crate::utils::metrics::{BATCH_DEDUPLICATED, BATCH_SIZE};
+use crate::utils::template::UserWrap;
+use crate::utils::think::ThinkTags;
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Semaphore};
 use tokio::time::sleep;
 
 #[derive(Clone)]
 pub struct BatchProcessor {
+    cache: CacheService,
+    ollama: OllamaClient,
+    stats: Arc<BatchMetrics>,
+    sender: mpsc::UnboundedSender<PendingRequest>,
+    /// Applied to warmed prompts and cache-action keys, which arrive unwrapped
+    user_wrap: UserWrap,
+    in_flight: SingleFlight<SharedReply>,
+}
+
+type Responder = oneshot::Sender<Result<String, String>>;
+
+/// Everything needed to make one upstream call
+struct ChatJob {
+    cache_key: String,
+    /// False for prompts matching `cache.exempt_patterns`, whose replies are neither stored
+    /// nor shared with identical requests
+    cacheable: bool,
+    messages: Vec<ChatMessage>,
+    model: String,
+    system_prompt: String,
+}
+
+/// A cache-missed request waiting in the batch buffer
+struct PendingRequest {
+    job: ChatJob,
+    responder: Responder,
+}
+
+/// A processed reply and whether it was served from cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchReply {
+    pub content: String,
+    pub cached: bool,
+}
+
+/// What followers of an identical in-flight request are answered with
+#[derive(Debug, Clone)]
+pub struct SharedReply {
+    pub message: ChatMessage,
+    /// Why Ollama stopped generating, as reported for the leader's reply
+    pub done_reason: Option<String>,
+    /// Whether the leader was answered by a fallback model
+    pub fallback_used: bool,
+}
+
+/// Outcome of warming the cache from a list of prompts
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WarmReport {
+    pub warmed: usize,
+    pub already_cached: usize,
+    /// Skipped for matching `cache.exempt_patterns`
+    pub exempt: usize,
+    pub failed: usize,
+}
+
+/// Background task that drains the buffer into batches and calls Ollama
+struct BatchWorker {
     cache: CacheService,
     ollama: OllamaClient,
     config: BatchConfig,
-    stats: Arc<RwLock<BatchMetrics>>,
+    stats: Arc<BatchMetrics>,
+    /// Bounds concurrent Ollama calls to `max_concurrent_upstream`, across all batches
+    upstream: Semaphore,
+    /// Reasoning is stripped before replies are cached or returned
+    think_tags: Option<ThinkTags>,
 }
 
+/// Counters are atomic so concurrent requests don't serialize on one lock; only latency
+/// samples and the first request for a new model take one
 #[derive(Debug, Default)]
 struct BatchMetrics {
-    total_requests: u64,
-    cached_responses: u64,
-    deduplicated_requests: u64,
-    batches_processed: u64,
-    total_batch_size: u64,
+    total_requests: AtomicU64,
+    cached_responses: AtomicU64,
+    deduplicated_requests: AtomicU64,
+    batches_processed: AtomicU64,
+    total_batch_size: AtomicU64,
+    upstream_in_flight: AtomicU64,
+    upstream_latency: Mutex<LatencyTracker>,
+    cache_hit_latency: Mutex<LatencyTracker>,
+    per_model: RwLock<HashMap<String, Arc<ModelMetrics>>>,
+}
+
+/// Weight of the newest sample in the latency moving average
+const LATENCY_EMA_ALPHA: f64 = 0.1;
+
+/// Upper bounds of the latency histogram buckets, in ms; slower samples go in an overflow bucket
+const LATENCY_BUCKETS_MS: [f64; 12] = [
+    10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1_000.0, 2_500.0, 5_000.0, 10_000.0, 30_000.0, 60_000.0,
+];
+
+/// Exponential moving average plus a bucketed histogram for percentiles
+#[derive(Debug, Default)]
+struct LatencyTracker {
+    ema_ms: Option<f64>,
+    max_ms: f64,
+    buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    count: u64,
+}
+
+impl LatencyTracker {
+    fn record(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        self.ema_ms = Some(match self.ema_ms {
+            Some(ema) => ema + LATENCY_EMA_ALPHA * (ms - ema),
+            None => ms,
+        });
+        self.max_ms = self.max_ms.max(ms);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+    }
+
+    fn average_ms(&self) -> f64 {
+        self.ema_ms.unwrap_or(0.0)
+    }
+
+    /// Upper bound of the bucket holding the `quantile` sample, capped at the slowest seen
+    fn percentile_ms(&self, quantile: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = LATENCY_BUCKETS_MS.get(index).copied().unwrap_or(f64::MAX);
+                return bound.min(self.max_ms);
+            }
+        }
+        self.max_ms
+    }
+}
+
+#[derive(Debug, Default)]
+struct ModelMetrics {
+    total_requests: AtomicU64,
+    cache_hits: AtomicU64,
+    upstream_calls: AtomicU64,
+    total_latency_ms: AtomicU64,
+}
+
+/// Counts an Ollama call in `upstream_in_flight` until dropped, so a cancelled call is
+/// uncounted too
+struct InFlightCall<'a>(&'a AtomicU64);
+
+impl<'a> InFlightCall<'a> {
+    fn start(counter: &'a AtomicU64) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for InFlightCall<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl BatchMetrics {
+    fn model(&self, model: &str) -> Arc<ModelMetrics> {
+        if let Some(metrics) = self.per_model.read().unwrap().get(model) {
+            return metrics.clone();
+        }
+        let mut per_model = self.per_model.write().unwrap();
+        per_model.entry(model.to_string()).or_default().clone()
+    }
 }
 
 impl BatchProcessor {
     pub fn new(cache: CacheService, ollama: OllamaClient, config: BatchConfig) -> Self {
+        let stats = Arc::new(BatchMetrics::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let worker = BatchWorker {
+            cache: cache.clone(),
+            ollama: ollama.clone(),
+            upstream: Semaphore::new(config.max_concurrent_upstream.max(1)),
+            think_tags: ThinkTags::from_config(ollama.config()),
+            config,
+            stats: stats.clone(),
+        };
+        tokio::spawn(Arc::new(worker).run(receiver));
+
         Self {
+            user_wrap: UserWrap::new(ollama.config()),
             cache,
             ollama,
-            config,
-            stats: Arc::new(RwLock::new(BatchMetrics::default())),
+            stats,
+            sender,
+            in_flight: SingleFlight::new(),
         }
     }
 
+    /// Cache key for a request processed without per-request options
+    pub fn cache_key(&self, messages: &[ChatMessage], model: &str, system_prompt: &str) -> String {
+        let options = self.ollama.resolve_options(None);
+        self.cache
+            .generate_key(messages, model, system_prompt, &options, None, None)
+    }
+
+    /// Like `cache_key`, for messages as the client sent them, before `ollama.user_prefix`
+    /// and `user_suffix` are applied
+    pub fn client_cache_key(
+        &self,
+        messages: &[ChatMessage],
+        model: &str,
+        system_prompt: &str,
+    ) -> String {
+        let mut messages = messages.to_vec();
+        self.user_wrap.apply(&mut messages);
+        self.cache_key(&messages, model, system_prompt)
+    }
+
     /// Process a single request with caching and batching
     pub async fn process(
         &self,
         messages: Vec<ChatMessage>,
         model: &str,
         system_prompt: &str,
-        _priority: i32, // Can be used for priority queuing in future
+        priority: i32,
     ) -> Result<String> {
-        let mut stats = self.stats.write().await;
-        stats.total_requests += 1;
-        drop(stats);
+        self.process_reply(messages, model, system_prompt, priority)
+            .await
+            .map(|reply| reply.content)
+    }
 
-        // Check cache first
-        let cache_key = CacheService::generate_key(&messages, model);
+    /// Like `process`, but also reports whether the reply came from cache
+    pub async fn process_reply(
+        &self,
+        messages: Vec<ChatMessage>,
+        model: &str,
+        system_prompt: &str,
+        _priority: i32, // Can be used for priority queuing in future
+    ) -> Result<BatchReply> {
+        let model_stats = self.stats.model(model);
+        self.stats.total_requests.fetch_add(1, Ordering::Relaxed);
+        model_stats.total_requests.fetch_add(1, Ordering::Relaxed);
 
-        if let Some(cached) = self.cache.get(&cache_key).await {
-            let mut stats = self.stats.write().await;
-            stats.cached_responses += 1;
+        // Check cache first, unless the prompt is exempt from it
+        let cache_key = self.cache_key(&messages, model, system_prompt);
+        let cacheable = !self.cache.is_exempt(&messages);
+        if !cacheable {
+            tracing::debug!("🚫 Request matches cache.exempt_patterns; bypassing the cache");
+        }
+
+        let lookup_started = Instant::now();
+        let cached = if cacheable {
+            self.cache.get(&cache_key).await
+        } else {
+            None
+        };
+        if let Some(cached) = cached {
+            let latency = lookup_started.elapsed();
+            self.stats.cache_hit_latency.lock().unwrap().record(latency);
+            self.stats.cached_responses.fetch_add(1, Ordering::Relaxed);
+            model_stats.cache_hits.fetch_add(1, Ordering::Relaxed);
             tracing::info!("✅ Serving from cache");
-            return Ok(cached);
+            return Ok(BatchReply {
+                content: cached,
+                cached: true,
+            });
         }
 
-        // TODO: Implement actual batching logic with buffer
-        // For now, process immediately
-        let response = self
-            .ollama
-            .chat_completion(&messages, model, system_prompt, false)
-            .await?;
+        // Wait on an identical request that is already running instead of repeating it
+        let flight = if !cacheable {
+            None
+        } else {
+            match self.join_flight(&cache_key).await {
+                Flight::Leader(guard) => Some(guard),
+                Flight::Follower(waiter) => match waiter.wait().await {
+                    Some(result) => {
+                        return result
+                            .map(|reply| BatchReply {
+                                content: reply.message.content,
+                                cached: false,
+                            })
+                            .map_err(|e| anyhow!("{}", e))
+                    }
+                    // The leader gave up without a result, so run the request ourselves
+                    None => None,
+                },
+            }
+        };
+
+        let result = self
+            .submit(ChatJob {
+                cache_key,
+                cacheable,
+                messages,
+                model: model.to_string(),
+                system_prompt: system_prompt.to_string(),
+            })
+            .await;
+
+        if let Some(guard) = flight {
+            guard.complete(match &result {
+                Ok(content) => Ok(SharedReply {
+                    message: ChatMessage {
+                        role: Role::Assistant,
+                        content: content.clone(),
+                        tool_calls: None,
+                        images: None,
+                    },
+                    done_reason: None,
+                    fallback_used: false,
+                }),
+                Err(e) => Err(Arc::new(anyhow!("{}", e))),
+            });
+        }
+        result.map(|content| BatchReply {
+            content,
+            cached: false,
+        })
+    }
 
-        // Cache the response
-        self.cache.set(cache_key, response.clone()).await;
+    /// Join the in-flight request for `cache_key`, counting followers as deduplicated
+    pub async fn join_flight(&self, cache_key: &str) -> Flight<SharedReply> {
+        let flight = self.in_flight.join(cache_key);
+        if matches!(flight, Flight::Follower(_)) {
+            self.stats
+                .deduplicated_requests
+                .fetch_add(1, Ordering::Relaxed);
+            metrics::counter!(BATCH_DEDUPLICATED).increment(1);
+            tracing::info!("🔗 Joined identical in-flight request");
+        }
+        flight
+    }
 
-        let mut stats = self.stats.write().await;
-        stats.batches_processed += 1;
-        stats.total_batch_size += 1;
+    /// Buffer a request; the worker answers once its batch completes
+    async fn submit(&self, job: ChatJob) -> Result<String> {
+        let (responder, response) = oneshot::channel();
+        self.sender
+            .send(PendingRequest { job, responder })
+            .map_err(|_| anyhow!("Batch worker is not running"))?;
 
-        Ok(response)
+        response
+            .await
+            .map_err(|_| anyhow!("Batch worker dropped the request"))?
+            .map_err(|e| anyhow!(e))
     }
 
     /// Get batch processor statistics
     pub async fn stats(&self) -> BatchStats {
-        let metrics = self.stats.read().await;
-        let average_batch_size = if metrics.batches_processed > 0 {
-            metrics.total_batch_size as f64 / metrics.batches_processed as f64
+        let metrics = &self.stats;
+        let total_requests = metrics.total_requests.load(Ordering::Relaxed);
+        let cached_responses = metrics.cached_responses.load(Ordering::Relaxed);
+        let deduplicated_requests = metrics.deduplicated_requests.load(Ordering::Relaxed);
+        let batches_processed = metrics.batches_processed.load(Ordering::Relaxed);
+        let total_batch_size = metrics.total_batch_size.load(Ordering::Relaxed);
+
+        let average_batch_size = if batches_processed > 0 {
+            total_batch_size as f64 / batches_processed as f64
         } else {
             0.0
         };
 
-        let cache_hit_rate = if metrics.total_requests > 0 {
-            ((metrics.cached_responses as f64 / metrics.total_requests as f64) * 100.0) as u32
+        let cache_hit_rate = if total_requests > 0 {
+            ((cached_responses as f64 / total_requests as f64) * 100.0) as u32
         } else {
             0
         };
 
-        let deduplication_rate = if metrics.total_requests > 0 {
-            ((metrics.deduplicated_requests as f64 / metrics.total_requests as f64) * 100.0) as u32
+        let deduplication_rate = if total_requests > 0 {
+            ((deduplicated_requests as f64 / total_requests as f64) * 100.0) as u32
         } else {
             0
         };
 
+        let per_model = metrics
+            .per_model
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(model, m)| {
+                let total_requests = m.total_requests.load(Ordering::Relaxed);
+                let cache_hits = m.cache_hits.load(Ordering::Relaxed);
+                let upstream_calls = m.upstream_calls.load(Ordering::Relaxed);
+                let cache_hit_rate = if total_requests > 0 {
+                    cache_hits as f64 / total_requests as f64
+                } else {
+                    0.0
+                };
+                let average_latency_ms = if upstream_calls > 0 {
+                    m.total_latency_ms.load(Ordering::Relaxed) as f64 / upstream_calls as f64
+                } else {
+                    0.0
+                };
+
+                let stats = ModelStats {
+                    total_requests,
+                    cache_hits,
+                    cache_hit_rate,
+                    average_latency_ms,
+                };
+                (model.clone(), stats)
+            })
+            .collect();
+
+        let upstream_latency = metrics.upstream_latency.lock().unwrap();
         BatchStats {
-            total_requests: metrics.total_requests,
-            cached_responses: metrics.cached_responses,
-            deduplicated_requests: metrics.deduplicated_requests,
-            batches_processed: metrics.batches_processed,
+            total_requests,
+            cached_responses,
+            deduplicated_requests,
+            batches_processed,
             average_batch_size,
             cache_hit_rate,
             deduplication_rate,
+            avg_latency_ms: upstream_latency.average_ms(),
+            p50_latency_ms: upstream_latency.percentile_ms(0.5),
+            p95_latency_ms: upstream_latency.percentile_ms(0.95),
+            cache_hit_latency_ms: metrics.cache_hit_latency.lock().unwrap().average_ms(),
+            upstream_in_flight: metrics.upstream_in_flight.load(Ordering::Relaxed),
+            per_model,
         }
     }
 
+    /// Run prompts through `process` so their answers are cached ahead of time
+    pub async fn warm_cache(
+        &self,
+        prompts: Vec<WarmPrompt>,
+        default_model: &str,
+        default_system_prompt: &str,
+    ) -> WarmReport {
+        let mut report = WarmReport::default();
+        let mut pending = Vec::new();
+
+        for prompt in prompts {
+            let model = prompt.model.unwrap_or_else(|| default_model.to_string());
+            let system_prompt = prompt
+                .system_prompt
+                .unwrap_or_else(|| default_system_prompt.to_string());
+
+            // Wrapped like client requests, so the warmed entries are the ones they hit
+            let mut messages = prompt.messages;
+            self.user_wrap.apply(&mut messages);
+            let key = self.cache_key(&messages, &model, &system_prompt);
+            if self.cache.is_exempt(&messages) {
+                tracing::debug!("🚫 Not warming a prompt that matches cache.exempt_patterns");
+                report.exempt += 1;
+            } else if self.cache.contains(&key).await {
+                report.already_cached += 1;
+            } else {
+                pending.push((messages, model, system_prompt));
+            }
+        }
+
+        // Submitted together so the batch worker can group and dedupe them
+        let requests = pending
+            .into_iter()
+            .map(|(messages, model, system_prompt)| async move {
+                self.process(messages, &model, &system_prompt, 0).await
+            });
+        let results = join_all(requests).await;
+
+        for result in results {
+            match result {
+                Ok(_) => report.warmed += 1,
+                Err(e) => {
+                    tracing::warn!("Failed to warm cache entry: {}", e);
+                    report.failed += 1;
+                }
+            }
+        }
+
+        tracing::info!(
+            "🔥 Cache warmed: {} new, {} already cached, {} exempt, {} failed",
+            report.warmed,
+            report.already_cached,
+            report.exempt,
+            report.failed
+        );
+        report
+    }
+
+    /// Load a JSON array of `{messages, model}` prompts from disk
+    pub async fn load_warm_prompts(path: &str) -> Result<Vec<WarmPrompt>> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
     /// Warm the model by sending a test request
     pub async fn warm_model(&self, model: &str) -> Result<()> {
         tracing::info!("🔥 Warming model: {}", model);
 
         let messages = vec![ChatMessage {
-            role: "user".to_string(),
+            role: Role::User,
             content: "Hello".to_string(),
+            tool_calls: None,
+            images: None,
         }];
 
         self.ollama
-            .chat_completion(&messages, model, "You are a helpful assistant.", false)
+            .chat_completion(
+                &messages,
+                model,
+                "You are a helpful assistant.",
+                GenerationParams::default(),
+                false,
+            )
             .await?;
 
         tracing::info!("✅ Model warmed successfully");
@@ -123,31 +528,185 @@ impl BatchProcessor {
     }
 }
 
+impl BatchWorker {
+    /// Collect requests until the batch is full or the timeout elapses, then dispatch it in the
+    /// background, so the next batch fills while this one is with Ollama
+    async fn run(self: Arc<Self>, mut receiver: mpsc::UnboundedReceiver<PendingRequest>) {
+        let max_batch_size = self.config.max_batch_size.max(1);
+        let batch_timeout = Duration::from_millis(self.config.batch_timeout_ms);
+
+        while let Some(first) = receiver.recv().await {
+            let mut batch = vec![first];
+
+            let deadline = sleep(batch_timeout);
+            tokio::pin!(deadline);
+
+            while batch.len() < max_batch_size {
+                tokio::select! {
+                    request = receiver.recv() => match request {
+                        Some(request) => batch.push(request),
+                        None => break,
+                    },
+                    _ = &mut deadline => break,
+                }
+            }
+
+            let worker = self.clone();
+            tokio::spawn(async move { worker.dispatch(batch).await });
+        }
+    }
+
+    /// Send a batch to Ollama concurrently, collapsing identical requests
+    async fn dispatch(&self, batch: Vec<PendingRequest>) {
+        let batch_size = batch.len() as u64;
+        let mut groups: Vec<(ChatJob, Vec<Responder>)> = Vec::new();
+        let mut index_by_key: HashMap<String, usize> = HashMap::new();
+        let mut deduplicated = 0;
+
+        for PendingRequest { job, responder } in batch {
+            if self.config.enable_deduplication && job.cacheable {
+                if let Some(&index) = index_by_key.get(&job.cache_key) {
+                    groups[index].1.push(responder);
+                    deduplicated += 1;
+                    continue;
+                }
+                index_by_key.insert(job.cache_key.clone(), groups.len());
+            }
+
+            groups.push((job, vec![responder]));
+        }
+
+        tracing::debug!(
+            "📦 Dispatching batch of {} ({} upstream calls)",
+            batch_size,
+            groups.len()
+        );
+
+        metrics::histogram!(BATCH_SIZE).record(batch_size as f64);
+        metrics::counter!(BATCH_DEDUPLICATED).increment(deduplicated);
+
+        let stats = &self.stats;
+        stats.batches_processed.fetch_add(1, Ordering::Relaxed);
+        stats
+            .total_batch_size
+            .fetch_add(batch_size, Ordering::Relaxed);
+        stats
+            .deduplicated_requests
+            .fetch_add(deduplicated, Ordering::Relaxed);
+
+        let mut in_flight: FuturesUnordered<_> = groups
+            .into_iter()
+            .map(|(job, responders)| async move {
+                let _permit = self.upstream.acquire().await;
+                let in_flight = InFlightCall::start(&self.stats.upstream_in_flight);
+                let started = Instant::now();
+                let result = self
+                    .ollama
+                    .chat_response(
+                        &job.messages,
+                        &job.model,
+                        &job.system_prompt,
+                        GenerationParams::default(),
+                        false,
+                    )
+                    .await;
+                let latency = started.elapsed();
+                let latency_ms = latency.as_millis() as u64;
+
+                drop(in_flight);
+                self.stats.upstream_latency.lock().unwrap().record(latency);
+                let model_stats = self.stats.model(&job.model);
+                model_stats.upstream_calls.fetch_add(1, Ordering::Relaxed);
+                model_stats
+                    .total_latency_ms
+                    .fetch_add(latency_ms, Ordering::Relaxed);
+
+                (job, responders, result, latency)
+            })
+            .collect();
+
+        while let Some((job, responders, result, latency)) = in_flight.next().await {
+            let result = match result {
+                Ok(response) => {
+                    let done_reason = response.done_reason.clone();
+                    let mut content = response.into_message().content;
+                    if let Some(tags) = &self.think_tags {
+                        content = tags.strip(&content);
+                    }
+                    if job.cacheable {
+                        self.store(
+                            job.cache_key,
+                            content.clone(),
+                            done_reason.as_deref(),
+                            latency,
+                        )
+                        .await;
+                    }
+                    Ok(content)
+                }
+                Err(e) => {
+                    if job.cacheable && is_transient(&e) {
+                        self.cache.record_failure(&job.cache_key, e.to_string());
+                    }
+                    Err(e.to_string())
+                }
+            };
+
+            for responder in responders {
+                let _ = responder.send(result.clone());
+            }
+        }
+    }
+
+    /// Cache a reply on the same terms as a direct request: not when it was generated faster
+    /// than `cache.min_generation_ms` or cut off at the token limit
+    async fn store(
+        &self,
+        key: String,
+        content: String,
+        done_reason: Option<&str>,
+        latency: Duration,
+    ) {
+        if !self.cache.worth_caching(latency) {
+            tracing::debug!("⏩ Not caching batched reply generated in {:?}", latency);
+        } else if !self.cache.caches_done_reason(done_reason) {
+            tracing::debug!("⏩ Not caching batched reply cut off at the token limit");
+        } else {
+            self.cache.set(key, content).await;
+            return;
+        }
+        // Don't leave an older reply to be served in place of this one
+        self.cache.clear_failure(&key);
+        self.cache.invalidate_key(&key).await;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::{CacheConfig, OllamaConfig};
+    use std::sync::atomic::AtomicUsize;
 
     #[tokio::test]
     async fn test_batch_processor_stats() {
         let cache_config = CacheConfig {
             max_size_mb: 10,
             ttl_seconds: 60,
-            enabled: true,
+            ..Default::default()
         };
 
         let ollama_config = OllamaConfig {
-            api_url: "http://localhost:11434".to_string(),
             model: "test".to_string(),
             system_prompt: "test".to_string(),
-            keep_alive: "15m".to_string(),
-            timeout_seconds: 300,
+            ..Default::default()
         };
 
         let batch_config = BatchConfig {
             max_batch_size: 3,
             batch_timeout_ms: 2000,
             enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
         };
 
         let cache = CacheService::new(cache_config);
@@ -157,4 +716,484 @@ mod tests {
         let stats = processor.stats().await;
         assert_eq!(stats.total_requests, 0);
     }
+
+    #[test]
+    fn test_latency_tracker_reports_ema_and_percentiles() {
+        let mut tracker = LatencyTracker::default();
+        assert_eq!(tracker.percentile_ms(0.95), 0.0);
+
+        for _ in 0..19 {
+            tracker.record(Duration::from_millis(80));
+        }
+        tracker.record(Duration::from_millis(4_000));
+
+        // 19 of 20 samples fall in the 50-100ms bucket, reported by its upper bound; the top
+        // bucket is capped at the slowest sample
+        assert_eq!(tracker.percentile_ms(0.5), 100.0);
+        assert_eq!(tracker.percentile_ms(0.95), 100.0);
+        assert_eq!(tracker.percentile_ms(1.0), 4_000.0);
+
+        // The slow sample moves the average by a tenth of the difference
+        let expected = 80.0 + LATENCY_EMA_ALPHA * (4_000.0 - 80.0);
+        assert!((tracker.average_ms() - expected).abs() < 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_in_batch_are_deduplicated() {
+        let cache_config = CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        };
+
+        // Nothing listens here, so upstream calls fail fast without retries
+        let ollama_config = OllamaConfig {
+            api_url: "http://127.0.0.1:9".to_string(),
+            timeout_seconds: 5,
+            max_retries: 0,
+            ..Default::default()
+        };
+
+        let batch_config = BatchConfig {
+            max_batch_size: 2,
+            batch_timeout_ms: 500,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+
+        let cache = CacheService::new(cache_config);
+        let ollama = OllamaClient::new(ollama_config);
+        let processor = BatchProcessor::new(cache, ollama, batch_config);
+
+        let messages = vec![ChatMessage {
+            role: Role::User,
+            content: "Hello".to_string(),
+            tool_calls: None,
+            images: None,
+        }];
+        let (first, second) = tokio::join!(
+            processor.process(messages.clone(), "test", "test", 0),
+            processor.process(messages, "test", "test", 0),
+        );
+        assert!(first.is_err() && second.is_err());
+
+        // The second request follows the first instead of joining the batch
+        let stats = processor.stats().await;
+        assert_eq!(stats.total_requests, 2);
+        assert_eq!(stats.deduplicated_requests, 1);
+        assert_eq!(stats.batches_processed, 1);
+        assert_eq!(stats.average_batch_size, 1.0);
+    }
+
+    /// Fake Ollama that answers after 50ms and records the most calls it was serving at once
+    async fn peak_tracking_ollama() -> (OllamaClient, Arc<AtomicUsize>) {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let ollama_app = axum::Router::new().route(
+            "/api/chat",
+            axum::routing::post({
+                let (running, peak) = (running.clone(), peak.clone());
+                move || async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    sleep(Duration::from_millis(50)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    axum::Json(serde_json::json!({
+                        "model": "test",
+                        "message": {"role": "assistant", "content": "hi"},
+                        "done": true
+                    }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: format!("http://{addr}"),
+            max_retries: 0,
+            ..Default::default()
+        });
+        (ollama, peak)
+    }
+
+    fn user_messages(content: &str) -> Vec<ChatMessage> {
+        vec![ChatMessage {
+            role: Role::User,
+            content: content.to_string(),
+            tool_calls: None,
+            images: None,
+        }]
+    }
+
+    #[tokio::test]
+    async fn test_upstream_calls_are_bounded() {
+        let (ollama, peak) = peak_tracking_ollama().await;
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let batch_config = BatchConfig {
+            max_batch_size: 3,
+            batch_timeout_ms: 50,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 1,
+        };
+        let processor = BatchProcessor::new(cache, ollama, batch_config);
+
+        let (a, b, c) = tokio::join!(
+            processor.process(user_messages("a"), "test", "test", 0),
+            processor.process(user_messages("b"), "test", "test", 0),
+            processor.process(user_messages("c"), "test", "test", 0),
+        );
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+
+        // One batch of three distinct requests, sent to Ollama one at a time
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+        let stats = processor.stats().await;
+        assert_eq!(stats.batches_processed, 1);
+        assert_eq!(stats.upstream_in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_upstream_call_is_uncounted() {
+        let counter = AtomicU64::new(0);
+        let call = async {
+            let _in_flight = InFlightCall::start(&counter);
+            futures::future::pending::<()>().await;
+        };
+        // Polled once so the call is counted, then dropped mid-flight
+        let timed_out = tokio::time::timeout(Duration::from_millis(10), call).await;
+        assert!(timed_out.is_err());
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_dispatched_concurrently() {
+        let (ollama, peak) = peak_tracking_ollama().await;
+        let cache = CacheService::new(CacheConfig::default());
+        let batch_config = BatchConfig {
+            max_batch_size: 1,
+            batch_timeout_ms: 10,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+        let processor = BatchProcessor::new(cache, ollama, batch_config);
+
+        let (a, b, c) = tokio::join!(
+            processor.process(user_messages("a"), "test", "test", 0),
+            processor.process(user_messages("b"), "test", "test", 0),
+            processor.process(user_messages("c"), "test", "test", 0),
+        );
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+
+        // Three one-request batches, all with Ollama at once instead of one after another
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert_eq!(processor.stats().await.batches_processed, 3);
+    }
+
+    #[tokio::test]
+    async fn test_warm_cache_skips_cached_and_exempt_prompts() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            exempt_patterns: vec!["(?i)what time".to_string()],
+            ..Default::default()
+        });
+
+        // Nothing listens here, so uncached prompts fail to warm
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: "http://127.0.0.1:9".to_string(),
+            timeout_seconds: 5,
+            max_retries: 0,
+            ..Default::default()
+        });
+
+        let batch_config = BatchConfig {
+            max_batch_size: 2,
+            batch_timeout_ms: 50,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+        let processor = BatchProcessor::new(cache.clone(), ollama, batch_config);
+
+        let prompt = |content: &str| WarmPrompt {
+            messages: vec![ChatMessage {
+                role: Role::User,
+                content: content.to_string(),
+                tool_calls: None,
+                images: None,
+            }],
+            model: None,
+            system_prompt: None,
+        };
+        let cached = prompt("cached");
+        let key = processor.cache_key(&cached.messages, "test", "test");
+        cache.set(key, "answer".to_string()).await;
+
+        let report = processor
+            .warm_cache(
+                vec![cached, prompt("uncached"), prompt("What time is it?")],
+                "test",
+                "test",
+            )
+            .await;
+        assert_eq!(
+            report,
+            WarmReport {
+                warmed: 0,
+                already_cached: 1,
+                exempt: 1,
+                failed: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warmed_prompts_are_wrapped_like_client_requests() {
+        // Replies with the last message Ollama was sent
+        let ollama_app = axum::Router::new().route(
+            "/api/chat",
+            axum::routing::post(|axum::Json(body): axum::Json<serde_json::Value>| async move {
+                let messages = body["messages"].as_array().unwrap();
+                axum::Json(serde_json::json!({
+                    "model": "test",
+                    "message": {"role": "assistant", "content": messages.last().unwrap()["content"]},
+                    "done": true
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: format!("http://{addr}"),
+            max_retries: 0,
+            user_prefix: "<q>".to_string(),
+            user_suffix: "</q>".to_string(),
+            ..Default::default()
+        });
+        let batch_config = BatchConfig {
+            max_batch_size: 2,
+            batch_timeout_ms: 50,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+        let processor = BatchProcessor::new(cache.clone(), ollama, batch_config);
+
+        let prompt = WarmPrompt {
+            messages: user_messages("Hello"),
+            model: None,
+            system_prompt: None,
+        };
+        let report = processor.warm_cache(vec![prompt], "test", "test").await;
+        assert_eq!(report.warmed, 1);
+
+        // Stored under the key a client sending "Hello" looks up, with Ollama's reply to it
+        let key = processor.client_cache_key(&user_messages("Hello"), "test", "test");
+        assert_eq!(cache.get(&key).await.as_deref(), Some("<q>Hello</q>"));
+    }
+
+    #[tokio::test]
+    async fn test_exempt_prompts_bypass_the_cache_and_deduplication() {
+        let (ollama, _) = peak_tracking_ollama().await;
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            exempt_patterns: vec!["(?i)what time".to_string()],
+            ..Default::default()
+        });
+        let batch_config = BatchConfig {
+            max_batch_size: 2,
+            batch_timeout_ms: 50,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+        let processor = BatchProcessor::new(cache.clone(), ollama, batch_config);
+
+        // Identical exempt prompts each reach Ollama, and nothing is stored
+        let messages = user_messages("What time is it?");
+        let (first, second) = tokio::join!(
+            processor.process_reply(messages.clone(), "test", "test", 0),
+            processor.process_reply(messages.clone(), "test", "test", 0),
+        );
+        assert!(!first.unwrap().cached && !second.unwrap().cached);
+        let key = processor.cache_key(&messages, "test", "test");
+        assert!(!cache.contains(&key).await);
+        let reply = processor.process_reply(messages, "test", "test", 0).await;
+        assert!(!reply.unwrap().cached);
+        assert_eq!(processor.stats().await.deduplicated_requests, 0);
+
+        // Other prompts are still cached
+        let messages = user_messages("Hello");
+        processor
+            .process(messages.clone(), "test", "test", 0)
+            .await
+            .unwrap();
+        let reply = processor.process_reply(messages, "test", "test", 0).await;
+        assert!(reply.unwrap().cached);
+    }
+
+    #[tokio::test]
+    async fn test_batched_replies_are_cached_like_direct_ones() {
+        let ollama_app = axum::Router::new().route(
+            "/api/chat",
+            axum::routing::post(|| async {
+                axum::Json(serde_json::json!({
+                    "model": "test",
+                    "message": {"role": "assistant", "content": "cut"},
+                    "done": true,
+                    "done_reason": "length"
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            negative_ttl_seconds: 60,
+            ..Default::default()
+        });
+        let batch_config = BatchConfig {
+            max_batch_size: 2,
+            batch_timeout_ms: 50,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: format!("http://{addr}"),
+            max_retries: 0,
+            ..Default::default()
+        });
+        let processor = BatchProcessor::new(cache.clone(), ollama, batch_config.clone());
+
+        // A reply cut off at the token limit is returned but not stored
+        let messages = user_messages("Hello");
+        let reply = processor.process(messages.clone(), "test", "test", 0).await;
+        assert_eq!(reply.unwrap(), "cut");
+        let key = processor.cache_key(&messages, "test", "test");
+        assert!(!cache.contains(&key).await);
+
+        // Ollama being down is remembered, so direct requests for the key fail fast
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: "http://127.0.0.1:9".to_string(),
+            max_retries: 0,
+            ..Default::default()
+        });
+        let processor = BatchProcessor::new(cache.clone(), ollama, batch_config);
+        let reply = processor.process(messages, "test", "test", 0).await;
+        assert!(reply.is_err());
+        assert!(cache.recent_failure(&key).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reasoning_is_stripped_before_caching() {
+        let ollama_app = axum::Router::new().route(
+            "/api/chat",
+            axum::routing::post(|| async {
+                axum::Json(serde_json::json!({
+                    "model": "test",
+                    "message": {"role": "assistant", "content": "<think>hmm</think>Hi"},
+                    "done": true
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: format!("http://{addr}"),
+            max_retries: 0,
+            strip_think_tags: true,
+            ..Default::default()
+        });
+        let batch_config = BatchConfig {
+            max_batch_size: 2,
+            batch_timeout_ms: 50,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+        let processor = BatchProcessor::new(cache.clone(), ollama, batch_config);
+
+        let messages = user_messages("Hello");
+        let reply = processor.process(messages.clone(), "test", "test", 0).await;
+        assert_eq!(reply.unwrap(), "Hi");
+        let key = processor.cache_key(&messages, "test", "test");
+        assert_eq!(cache.get(&key).await.as_deref(), Some("Hi"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_are_all_counted() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let ollama = OllamaClient::new(OllamaConfig {
+            api_url: "http://127.0.0.1:9".to_string(),
+            max_retries: 0,
+            ..Default::default()
+        });
+        let batch_config = BatchConfig {
+            max_batch_size: 2,
+            batch_timeout_ms: 50,
+            enable_deduplication: true,
+            bulk_concurrency: 4,
+            max_concurrent_upstream: 4,
+        };
+        let processor = BatchProcessor::new(cache.clone(), ollama, batch_config);
+
+        // Every call is a cache hit, so they all race on the counters rather than the worker
+        let messages = vec![ChatMessage {
+            role: Role::User,
+            content: "Hello".to_string(),
+            tool_calls: None,
+            images: None,
+        }];
+        let key = processor.cache_key(&messages, "test", "test");
+        cache.set(key, "answer".to_string()).await;
+
+        let calls: Vec<_> = (0..50)
+            .map(|_| {
+                let processor = processor.clone();
+                let messages = messages.clone();
+                tokio::spawn(async move { processor.process(messages, "test", "test", 0).await })
+            })
+            .collect();
+        for call in calls {
+            assert_eq!(call.await.unwrap().unwrap(), "answer");
+        }
+
+        let stats = processor.stats().await;
+        assert_eq!(stats.total_requests, 50);
+        assert_eq!(stats.cached_responses, 50);
+        assert_eq!(stats.cache_hit_rate, 100);
+        assert_eq!(stats.per_model["test"].total_requests, 50);
+        assert_eq!(stats.per_model["test"].cache_hit_rate, 1.0);
+    }
 }
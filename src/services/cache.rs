@@ -1,86 +1,389 @@
-use crate::config::CacheConfig;
-use crate::models::{CacheStats, ChatMessage};
+use crate::config::{CacheBackendKind, CacheConfig};
+use crate::models::{CacheEntryInfo, CacheEntrySummary, CacheStats, ChatMessage, OllamaOptions};
+use crate::services::cache_backend::{CacheBackend, MemoryBackend, PersistedEntry, RedisBackend};
+use crate::utils::metrics::{CACHE_HITS, CACHE_MISSES};
 use anyhow::Result;
-use moka::future::Cache;
+use chrono::Utc;
+use regex::RegexSet;
 use sha2::{Digest, Sha256};
-use std::sync::Arc;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 
 #[derive(Clone)]
 pub struct CacheService {
-    cache: Cache<String, String>,
-    stats: Arc<RwLock<CacheMetrics>>,
+    backend: Arc<dyn CacheBackend>,
+    semantic_index: Arc<RwLock<Vec<SemanticEntry>>>,
+    stats: Arc<CacheMetrics>,
+    /// Recent upstream failures per key and when they expire, for `negative_ttl_seconds`
+    failures: Arc<Mutex<HashMap<String, (Instant, String)>>>,
+    /// Default TTL in seconds; starts as `config.ttl_seconds` and changes on config reload
+    ttl_seconds: Arc<AtomicU64>,
+    /// Compiled `config.exempt_patterns`
+    exempt: RegexSet,
     config: CacheConfig,
+    /// Value of the `cache` label on exported metrics
+    label: &'static str,
 }
 
+/// Prompt embedding pointing at a cached response
+#[derive(Debug, Clone)]
+struct SemanticEntry {
+    key: String,
+    /// `generate_scope` of the request; only requests with the same scope may share a reply
+    scope: String,
+    embedding: Vec<f32>,
+}
+
+/// Lock-free, so concurrent lookups don't queue up just to bump a counter
 #[derive(Debug, Default)]
 struct CacheMetrics {
-    hits: u64,
-    misses: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
 impl CacheService {
     pub fn new(config: CacheConfig) -> Self {
-        let max_capacity = config.max_size_mb * 1024 * 1024; // Convert MB to bytes
-        let ttl = Duration::from_secs(config.ttl_seconds);
-
-        let cache = Cache::builder()
-            .max_capacity(max_capacity)
-            .time_to_live(ttl)
-            .build();
-
+        let label = "response";
         Self {
-            cache,
-            stats: Arc::new(RwLock::new(CacheMetrics::default())),
+            backend: build_backend(&config, label),
+            semantic_index: Arc::new(RwLock::new(Vec::new())),
+            stats: Arc::new(CacheMetrics::default()),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            ttl_seconds: Arc::new(AtomicU64::new(config.ttl_seconds)),
+            exempt: RegexSet::new(&config.exempt_patterns)
+                .expect("cache.exempt_patterns are checked by Config::validate"),
             config,
+            label,
+        }
+    }
+
+    /// Set the `cache` label used when exporting metrics for this cache
+    pub fn with_label(mut self, label: &'static str) -> Self {
+        self.label = label;
+        // Redis keys are namespaced by label, so caches sharing a server stay apart, and
+        // eviction metrics carry it
+        self.backend = build_backend(&self.config, label);
+        self
+    }
+
+    /// Change the default TTL; entries already stored keep the TTL they were stored with
+    pub fn set_ttl(&self, ttl_seconds: u64) {
+        self.ttl_seconds.store(ttl_seconds, Ordering::Relaxed);
+    }
+
+    fn default_ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_seconds.load(Ordering::Relaxed))
+    }
+
+    /// Check that the backend is reachable, so misconfiguration shows up at startup
+    pub async fn check_backend(&self) -> Result<()> {
+        if self.config.enabled {
+            self.backend.contains("").await?;
         }
+        Ok(())
     }
 
-    /// Generate cache key from messages and model
-    pub fn generate_key(messages: &[ChatMessage], model: &str) -> String {
+    /// Whether the conversation's content matches one of `cache.exempt_patterns`, so it must
+    /// not be answered from or stored in the cache
+    pub fn is_exempt(&self, messages: &[ChatMessage]) -> bool {
+        if self.exempt.is_empty() {
+            return false;
+        }
+        let content = messages
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.exempt.is_match(&content)
+    }
+
+    /// Generate cache key from everything that affects the generated reply
+    pub fn generate_key(
+        &self,
+        messages: &[ChatMessage],
+        model: &str,
+        system_prompt: &str,
+        options: &OllamaOptions,
+        format: Option<&str>,
+        tools: Option<&[serde_json::Value]>,
+    ) -> String {
         let content: String = messages
             .iter()
-            .map(|m| format!("{}:{}", m.role, m.content))
+            .map(|m| {
+                let text = self.normalize(&m.content);
+                let mut part = match &m.tool_calls {
+                    Some(calls) => format!("{}:{}:{}", m.role, text, serde_json::json!(calls)),
+                    None => format!("{}:{}", m.role, text),
+                };
+                if let Some(images) = &m.images {
+                    part.push_str(":images:");
+                    part.push_str(&images.join(","));
+                }
+                part
+            })
             .collect::<Vec<_>>()
             .join("||");
 
-        let input = format!("{}::{}", model, content);
+        let options = serde_json::to_string(options).unwrap_or_default();
+        let tools = tools.map(|t| serde_json::json!(t).to_string());
+        let input = format!(
+            "{}::{}::{}::{}::{}::{}",
+            model,
+            system_prompt,
+            options,
+            format.unwrap_or_default(),
+            tools.unwrap_or_default(),
+            content
+        );
+        self.hash_key(&input)
+    }
+
+    /// Everything in `generate_key` except the messages: requests that differ in any of these
+    /// must not be answered with each other's replies, however similar their prompts
+    pub fn generate_scope(
+        &self,
+        model: &str,
+        system_prompt: &str,
+        options: &OllamaOptions,
+        format: Option<&str>,
+        tools: Option<&[serde_json::Value]>,
+    ) -> String {
+        let options = serde_json::to_string(options).unwrap_or_default();
+        let tools = tools.map(|t| serde_json::json!(t).to_string());
+        let input = format!(
+            "semantic::{}::{}::{}::{}::{}",
+            model,
+            system_prompt,
+            options,
+            format.unwrap_or_default(),
+            tools.unwrap_or_default()
+        );
+        self.hash_key(&input)
+    }
+
+    /// Key for a raw `/api/generate` prompt, namespaced so it never matches a chat key
+    pub fn generate_prompt_key(
+        &self,
+        prompt: &str,
+        model: &str,
+        options: &OllamaOptions,
+    ) -> String {
+        let options = serde_json::to_string(options).unwrap_or_default();
+        let input = format!(
+            "generate::{}::{}::{}",
+            model,
+            options,
+            self.normalize(prompt)
+        );
+        self.hash_key(&input)
+    }
+
+    /// Hash key input behind `config.version`; without a version keys match older releases
+    fn hash_key(&self, input: &str) -> String {
         let mut hasher = Sha256::new();
+        if !self.config.version.is_empty() {
+            hasher.update(self.config.version.as_bytes());
+            hasher.update(b"::");
+        }
         hasher.update(input.as_bytes());
         format!("{:x}", hasher.finalize())
     }
 
+    /// Apply the configured key normalization to message content
+    fn normalize<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        if !self.config.normalize {
+            return Cow::Borrowed(content);
+        }
+
+        let collapsed = content.split_whitespace().collect::<Vec<_>>().join(" ");
+        if self.config.normalize_lowercase {
+            Cow::Owned(collapsed.to_lowercase())
+        } else {
+            Cow::Owned(collapsed)
+        }
+    }
+
     /// Get cached response
     pub async fn get(&self, key: &str) -> Option<String> {
+        let content = self.lookup(key).await;
+        self.record_lookup(key, content.is_some());
+        content
+    }
+
+    /// Like `get`, but not counted as a hit or miss; for lookups with a semantic fallback,
+    /// whose outcome is recorded once with `record_lookup`
+    pub async fn lookup(&self, key: &str) -> Option<String> {
         if !self.config.enabled {
             return None;
         }
+        self.backend_get(key).await
+    }
 
-        match self.cache.get(key).await {
-            Some(value) => {
-                let mut stats = self.stats.write().await;
-                stats.hits += 1;
-                tracing::debug!("✅ Cache hit for key: {}", &key[..8]);
-                Some(value)
-            }
-            None => {
-                let mut stats = self.stats.write().await;
-                stats.misses += 1;
-                tracing::debug!("❌ Cache miss for key: {}", &key[..8]);
+    /// Count one lookup of `key` as a hit or a miss
+    pub fn record_lookup(&self, key: &str, hit: bool) {
+        if !self.config.enabled {
+            return;
+        }
+        if hit {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            metrics::counter!(CACHE_HITS, "cache" => self.label).increment(1);
+            tracing::debug!("✅ Cache hit for key: {}", short_key(key));
+        } else {
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            metrics::counter!(CACHE_MISSES, "cache" => self.label).increment(1);
+            tracing::debug!("❌ Cache miss for key: {}", short_key(key));
+        }
+    }
+
+    /// Remember that generating `key` failed, so repeats fail fast for `negative_ttl_seconds`
+    pub fn record_failure(&self, key: &str, error: String) {
+        if !self.config.enabled || self.config.negative_ttl_seconds == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(self.config.negative_ttl_seconds);
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, (expires, _)| *expires > now);
+        failures.insert(key.to_string(), (expires, error));
+        tracing::debug!("⛔ Remembering failure for key: {}", short_key(key));
+    }
+
+    /// The error from a failure recorded for `key` that has not expired yet
+    pub fn recent_failure(&self, key: &str) -> Option<String> {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(key) {
+            Some((expires, error)) if *expires > Instant::now() => Some(error.clone()),
+            Some(_) => {
+                failures.remove(key);
                 None
             }
+            None => None,
         }
     }
 
+    /// Forget a recorded failure once `key` has been generated successfully
+    pub fn clear_failure(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    /// Whether a reply that took `elapsed` to generate is slow enough to be worth caching
+    pub fn worth_caching(&self, elapsed: Duration) -> bool {
+        elapsed >= Duration::from_millis(self.config.min_generation_ms)
+    }
+
+    /// Whether a reply that ended for `done_reason` may be cached; ones cut off at the token
+    /// limit are only kept with `cache.cache_length_truncated`
+    pub fn caches_done_reason(&self, done_reason: Option<&str>) -> bool {
+        done_reason != Some("length") || self.config.cache_length_truncated
+    }
+
     /// Set cached response
     pub async fn set(&self, key: String, value: String) {
-        if !self.config.enabled {
+        self.set_with_ttl(key, value, None).await;
+    }
+
+    /// Set cached response, expiring after `ttl` instead of the configured TTL, which also
+    /// caps it. A zero TTL means the response is not cached at all. Either way a failure
+    /// recorded for `key` is forgotten, since Ollama has just answered it.
+    pub async fn set_with_ttl(&self, key: String, value: String, ttl: Option<Duration>) {
+        self.clear_failure(&key);
+        if !self.config.enabled || ttl.is_some_and(|ttl| ttl.is_zero()) {
             return;
         }
 
-        self.cache.insert(key.clone(), value).await;
-        tracing::debug!("💾 Cached response for key: {}", &key[..8]);
+        let short = short_key(&key).to_string();
+        let default_ttl = self.default_ttl();
+        let ttl = ttl.map_or(default_ttl, |ttl| ttl.min(default_ttl));
+        match self.backend.insert(key, value, Some(ttl)).await {
+            Ok(()) => tracing::debug!("💾 Cached response for key: {}", short),
+            Err(e) => tracing::warn!("Failed to cache response for key {}: {}", short, e),
+        }
+    }
+
+    /// Backend lookup; backend failures are logged and treated as a miss
+    async fn backend_get(&self, key: &str) -> Option<String> {
+        self.backend.get(key).await.unwrap_or_else(|e| {
+            tracing::warn!("Cache lookup failed for key {}: {}", short_key(key), e);
+            None
+        })
+    }
+
+    /// Whether semantic (embedding-based) lookup is enabled
+    pub fn semantic_enabled(&self) -> bool {
+        self.config.enabled && self.config.semantic_enabled
+    }
+
+    /// Model to embed prompts with, falling back to the chat model
+    pub fn embedding_model<'a>(&'a self, chat_model: &'a str) -> &'a str {
+        self.config.embedding_model.as_deref().unwrap_or(chat_model)
+    }
+
+    /// Find a cached response whose prompt embedding is most similar to `embedding`,
+    /// if the similarity exceeds the configured threshold. Not counted as a hit or miss; see
+    /// `lookup`.
+    pub async fn find_similar(&self, scope: &str, embedding: &[f32]) -> Option<String> {
+        if !self.semantic_enabled() {
+            return None;
+        }
+
+        let mut candidates: Vec<(f32, String)> = {
+            let index = self.semantic_index.read().await;
+            index
+                .iter()
+                .filter(|entry| entry.scope == scope)
+                .map(|entry| (cosine_similarity(&entry.embedding, embedding), entry))
+                .filter(|(similarity, _)| *similarity >= self.config.similarity_threshold)
+                .map(|(similarity, entry)| (similarity, entry.key.clone()))
+                .collect()
+        };
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        // Embeddings whose responses have expired or been evicted are dropped as they are met,
+        // rather than checking the whole index on every insert
+        let mut expired = Vec::new();
+        let mut found = None;
+        for (similarity, key) in candidates {
+            match self.backend_get(&key).await {
+                Some(content) => {
+                    found = Some((similarity, key, content));
+                    break;
+                }
+                None => expired.push(key),
+            }
+        }
+        if !expired.is_empty() {
+            self.semantic_index
+                .write()
+                .await
+                .retain(|entry| !expired.contains(&entry.key));
+        }
+
+        let (similarity, key, content) = found?;
+        tracing::debug!(
+            "🧠 Semantic cache hit for key: {} (similarity {:.3})",
+            short_key(&key),
+            similarity
+        );
+        Some(content)
+    }
+
+    /// Record the prompt embedding for a cached response
+    pub async fn add_embedding(&self, key: String, scope: String, embedding: Vec<f32>) {
+        if !self.semantic_enabled() {
+            return;
+        }
+
+        let mut index = self.semantic_index.write().await;
+        index.retain(|entry| entry.key != key);
+        index.push(SemanticEntry {
+            key,
+            scope,
+            embedding,
+        });
     }
 
     /// Check if key exists
@@ -88,62 +391,232 @@ impl CacheService {
         if !self.config.enabled {
             return false;
         }
-        self.cache.get(key).await.is_some()
+        self.backend.contains(key).await.unwrap_or(false)
+    }
+
+    /// Look up an entry for inspection without counting it as a hit or miss
+    pub async fn get_by_key(&self, key: &str) -> Option<CacheEntryInfo> {
+        self.backend.get_entry(key).await.unwrap_or_else(|e| {
+            tracing::warn!("Cache lookup failed for key {}: {}", short_key(key), e);
+            None
+        })
+    }
+
+    /// The `limit` most-served entries, with keys shortened to their log prefix; `None` when
+    /// the backend does not count hits
+    pub async fn top_entries(&self, limit: usize) -> Option<Vec<CacheEntrySummary>> {
+        let mut entries = self.backend.top_entries(limit).await.unwrap_or_else(|e| {
+            tracing::warn!("Failed to list cache entries: {}", e);
+            Some(Vec::new())
+        })?;
+        for entry in &mut entries {
+            entry.key = short_key(&entry.key).to_string();
+        }
+        Some(entries)
+    }
+
+    /// Remove a single entry, returning whether it existed
+    pub async fn invalidate_key(&self, key: &str) -> bool {
+        let existed = self.backend.remove(key).await.unwrap_or_else(|e| {
+            tracing::warn!("Failed to invalidate cache entry {}: {}", short_key(key), e);
+            false
+        });
+        self.semantic_index
+            .write()
+            .await
+            .retain(|entry| entry.key != key);
+        if existed {
+            tracing::info!("🗑️  Invalidated cache entry: {}", short_key(key));
+        }
+        existed
     }
 
     /// Clear cache
     pub async fn clear(&self) {
-        self.cache.invalidate_all();
-        let mut stats = self.stats.write().await;
-        stats.hits = 0;
-        stats.misses = 0;
+        if let Err(e) = self.backend.clear().await {
+            tracing::warn!("Failed to clear cache: {}", e);
+        }
+        self.semantic_index.write().await.clear();
+        self.failures.lock().unwrap().clear();
+        self.stats.hits.store(0, Ordering::Relaxed);
+        self.stats.misses.store(0, Ordering::Relaxed);
         tracing::info!("🧹 Cache cleared");
     }
 
+    /// File to persist to; Redis keeps its own data, so only the in-memory backend uses one
+    fn persist_path(&self) -> Option<&str> {
+        let persists = self.config.enabled && self.config.backend == CacheBackendKind::Memory;
+        self.config.persist_path.as_deref().filter(|_| persists)
+    }
+
+    /// Write all live entries to `persist_path` as newline-delimited JSON
+    pub async fn save_to_disk(&self) -> Result<usize> {
+        let Some(path) = self.persist_path() else {
+            return Ok(0);
+        };
+
+        let mut contents = String::new();
+        let mut count = 0;
+        for record in self.backend.export().await {
+            contents.push_str(&serde_json::to_string(&record)?);
+            contents.push('\n');
+            count += 1;
+        }
+
+        // Write to a temporary file first so a crash never leaves a truncated cache file
+        let tmp_path = format!("{}.tmp", path);
+        tokio::fs::write(&tmp_path, contents).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+
+        tracing::info!("💾 Persisted {} cache entries to {}", count, path);
+        Ok(count)
+    }
+
+    /// Restore entries from `persist_path`, skipping any whose TTL has already expired
+    pub async fn load_from_disk(&self) -> Result<usize> {
+        let Some(path) = self.persist_path() else {
+            return Ok(0);
+        };
+
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = Utc::now().timestamp_millis();
+        let mut count = 0;
+
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            let record: PersistedEntry = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    tracing::warn!("Skipping malformed cache entry: {}", e);
+                    continue;
+                }
+            };
+
+            let ttl_seconds = record.ttl_seconds.unwrap_or(self.config.ttl_seconds);
+            let ttl_ms = i64::try_from(ttl_seconds.saturating_mul(1000)).unwrap_or(i64::MAX);
+            if now - record.created_at >= ttl_ms {
+                continue;
+            }
+
+            self.backend.import(record).await;
+            count += 1;
+        }
+
+        tracing::info!("📂 Restored {} cache entries from {}", count, path);
+        Ok(count)
+    }
+
+    /// Periodically save the cache to disk if persistence is configured
+    pub fn spawn_persistence_task(&self) {
+        if self.persist_path().is_none() {
+            return;
+        }
+
+        let cache = self.clone();
+        let interval = Duration::from_secs(self.config.persist_interval_seconds.max(1));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = cache.save_to_disk().await {
+                    tracing::warn!("Failed to persist cache: {}", e);
+                }
+            }
+        });
+    }
+
     /// Get cache statistics
     pub async fn stats(&self) -> CacheStats {
-        let metrics = self.stats.read().await;
-        let total_requests = metrics.hits + metrics.misses;
+        let hits = self.stats.hits.load(Ordering::Relaxed);
+        let misses = self.stats.misses.load(Ordering::Relaxed);
+        let total_requests = hits + misses;
         let hit_rate = if total_requests > 0 {
-            metrics.hits as f64 / total_requests as f64
+            hits as f64 / total_requests as f64
         } else {
             0.0
         };
         let miss_rate = if total_requests > 0 {
-            metrics.misses as f64 / total_requests as f64
+            misses as f64 / total_requests as f64
         } else {
             0.0
         };
 
-        // Estimate memory usage (Moka doesn't provide exact memory usage)
-        let entry_count = self.cache.entry_count();
-        let estimated_size_mb = (entry_count as f64 * 5.0) / 1024.0; // Rough estimate: 5KB per entry
+        let usage = self.backend.usage().await.unwrap_or_else(|e| {
+            tracing::warn!("Failed to read cache usage: {}", e);
+            Default::default()
+        });
+        let entry_count = usage.entries;
+        let size_mb = usage.bytes as f64 / (1024.0 * 1024.0);
         let memory_usage_percent = if self.config.max_size_mb > 0 {
-            (estimated_size_mb / self.config.max_size_mb as f64) * 100.0
+            (size_mb / self.config.max_size_mb as f64) * 100.0
         } else {
             0.0
         };
 
         CacheStats {
             total_entries: entry_count,
-            total_size_mb: estimated_size_mb,
+            total_size_mb: size_mb,
             hit_rate,
             miss_rate,
             memory_usage_percent,
+            evictions_size: usage.evictions_size,
+            evictions_ttl: usage.evictions_ttl,
+            evictions_explicit: usage.evictions_explicit,
         }
     }
 }
 
+fn build_backend(config: &CacheConfig, label: &'static str) -> Arc<dyn CacheBackend> {
+    let ttl = Duration::from_secs(config.ttl_seconds);
+    match config.backend {
+        CacheBackendKind::Memory => {
+            let max_capacity = config.max_size_mb * 1024 * 1024; // Convert MB to bytes
+            Arc::new(MemoryBackend::new(max_capacity, ttl, label))
+        }
+        CacheBackendKind::Redis => {
+            let prefix = format!("{}{}:", config.redis_prefix, label);
+            Arc::new(RedisBackend::new(config.redis_url.clone(), prefix, ttl))
+        }
+    }
+}
+
+/// Cosine similarity of two vectors, 0.0 if their lengths differ or either is zero
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+/// Key prefix for log lines; never panics on short or non-ASCII keys
+fn short_key(key: &str) -> &str {
+    key.get(..8).unwrap_or(key)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::Role;
 
     #[tokio::test]
     async fn test_cache_service() {
         let config = CacheConfig {
             max_size_mb: 10,
             ttl_seconds: 60,
-            enabled: true,
+            ..Default::default()
         };
 
         let cache = CacheService::new(config);
@@ -160,4 +633,449 @@ mod tests {
         let stats = cache.stats().await;
         assert_eq!(stats.hit_rate, 0.5); // 1 hit, 1 miss
     }
+
+    #[tokio::test]
+    async fn test_capacity_is_measured_in_bytes() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 1,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+
+        let value = "x".repeat(400 * 1024);
+        for key in ["a", "b", "c", "d"] {
+            cache.set(key.to_string(), value.clone()).await;
+        }
+
+        // Four 400KB entries cannot all fit in 1MB
+        let stats = cache.stats().await;
+        assert!(stats.total_entries < 4);
+        assert!(stats.total_size_mb <= 1.0);
+        assert!(stats.total_size_mb > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_per_entry_ttl_overrides_config() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 3600,
+            ..Default::default()
+        });
+
+        cache
+            .set_with_ttl(
+                "short".to_string(),
+                "value".to_string(),
+                Some(Duration::from_secs(5)),
+            )
+            .await;
+        let entry = cache.get_by_key("short").await.unwrap();
+        assert!(entry.expires_in_seconds <= 5);
+
+        // A longer TTL is capped at the configured one
+        cache
+            .set_with_ttl(
+                "long".to_string(),
+                "value".to_string(),
+                Some(Duration::from_secs(u64::MAX)),
+            )
+            .await;
+        let entry = cache.get_by_key("long").await.unwrap();
+        assert!(entry.expires_in_seconds <= 3600);
+
+        // A zero TTL is never stored
+        cache
+            .set_with_ttl(
+                "never".to_string(),
+                "value".to_string(),
+                Some(Duration::ZERO),
+            )
+            .await;
+        assert!(!cache.contains("never").await);
+    }
+
+    #[tokio::test]
+    async fn test_cleared_entries_count_as_explicit_evictions() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 3600,
+            ..Default::default()
+        });
+        cache.set("kept".to_string(), "value".to_string()).await;
+        cache.set("kept".to_string(), "newer".to_string()).await;
+        assert_eq!(cache.stats().await.total_entries, 1);
+
+        cache.clear().await;
+        let stats = cache.stats().await;
+        assert_eq!(stats.evictions_explicit, 1);
+        assert_eq!(stats.evictions_ttl, 0);
+        assert_eq!(stats.evictions_size, 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_key_reports_whether_entry_existed() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        cache.set("key".to_string(), "stale".to_string()).await;
+
+        let entry = cache.get_by_key("key").await.unwrap();
+        assert_eq!(entry.content, "stale");
+        assert!(entry.expires_in_seconds <= 60);
+
+        assert!(cache.invalidate_key("key").await);
+        assert!(!cache.invalidate_key("key").await);
+        assert!(cache.get_by_key("key").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_top_entries_are_ordered_by_hits() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        for key in ["rarely-served", "often-served", "never-served"] {
+            cache.set(key.to_string(), "answer".to_string()).await;
+        }
+        cache.get("rarely-served").await;
+        for _ in 0..3 {
+            cache.get("often-served").await;
+        }
+
+        let top = cache.top_entries(2).await.unwrap();
+        let listed: Vec<_> = top.iter().map(|e| (e.key.as_str(), e.hit_count)).collect();
+        assert_eq!(listed, [("often-se", 3), ("rarely-s", 1)]);
+        assert_eq!(top[0].size_bytes, "answer".len());
+
+        // Inspecting an entry does not count as serving it
+        let entry = cache.get_by_key("often-served").await.unwrap();
+        assert_eq!(entry.hit_count, 3);
+
+        // Redis does not count hits, so it can't rank entries at all
+        let redis = CacheService::new(CacheConfig {
+            backend: CacheBackendKind::Redis,
+            redis_url: "redis://127.0.0.1:1".to_string(),
+            ..Default::default()
+        });
+        assert!(redis.top_entries(2).await.is_none());
+    }
+
+    #[test]
+    fn test_failures_are_remembered_until_success() {
+        let cache = CacheService::new(CacheConfig {
+            negative_ttl_seconds: 5,
+            ..Default::default()
+        });
+        cache.record_failure("down", "connection refused".to_string());
+        assert_eq!(
+            cache.recent_failure("down").as_deref(),
+            Some("connection refused")
+        );
+        assert_eq!(cache.recent_failure("other"), None);
+
+        cache.clear_failure("down");
+        assert_eq!(cache.recent_failure("down"), None);
+
+        // Off by default
+        let cache = CacheService::new(CacheConfig::default());
+        cache.record_failure("down", "connection refused".to_string());
+        assert_eq!(cache.recent_failure("down"), None);
+    }
+
+    #[test]
+    fn test_key_depends_on_system_prompt_and_options() {
+        let cache = CacheService::new(CacheConfig::default());
+        let messages = vec![ChatMessage {
+            role: Role::User,
+            content: "Hello".to_string(),
+            tool_calls: None,
+            images: None,
+        }];
+        let defaults = OllamaOptions::default();
+        let key = cache.generate_key(&messages, "model", "prompt", &defaults, None, None);
+
+        assert_eq!(
+            key,
+            cache.generate_key(&messages, "model", "prompt", &defaults, None, None)
+        );
+        assert_ne!(
+            key,
+            cache.generate_key(&messages, "model", "other prompt", &defaults, None, None)
+        );
+
+        let warmer = OllamaOptions {
+            temperature: Some(1.2),
+            ..Default::default()
+        };
+        assert_ne!(
+            key,
+            cache.generate_key(&messages, "model", "prompt", &warmer, None, None)
+        );
+
+        let stopped = OllamaOptions {
+            stop: Some(vec!["\n".to_string()]),
+            ..Default::default()
+        };
+        assert_ne!(
+            key,
+            cache.generate_key(&messages, "model", "prompt", &stopped, None, None)
+        );
+
+        // Images are part of the key
+        let mut with_image = messages.clone();
+        with_image[0].images = Some(vec!["iVBORw0KGgo=".to_string()]);
+        let image_key = cache.generate_key(&with_image, "model", "prompt", &defaults, None, None);
+        assert_ne!(key, image_key);
+        with_image[0].images = Some(vec!["R0lGODlh".to_string()]);
+        assert_ne!(
+            image_key,
+            cache.generate_key(&with_image, "model", "prompt", &defaults, None, None)
+        );
+
+        // Raw prompts are keyed separately from chat messages
+        let prompt_key = cache.generate_prompt_key("Hello", "model", &defaults);
+        assert_ne!(key, prompt_key);
+        assert_ne!(
+            prompt_key,
+            cache.generate_prompt_key("Hello", "model", &warmer)
+        );
+    }
+
+    #[test]
+    fn test_normalized_keys_ignore_whitespace_and_case() {
+        let user = |content: &str| {
+            vec![ChatMessage {
+                role: Role::User,
+                content: content.to_string(),
+                tool_calls: None,
+                images: None,
+            }]
+        };
+        let options = OllamaOptions::default();
+        let key = |cache: &CacheService, content: &str| {
+            cache.generate_key(&user(content), "model", "prompt", &options, None, None)
+        };
+
+        // Off by default
+        let cache = CacheService::new(CacheConfig::default());
+        assert_ne!(
+            key(&cache, "What is Rust?"),
+            key(&cache, " What  is Rust? ")
+        );
+
+        let cache = CacheService::new(CacheConfig {
+            normalize: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            key(&cache, "What is Rust?"),
+            key(&cache, " What \n is Rust? ")
+        );
+        assert_ne!(key(&cache, "What is Rust?"), key(&cache, "what is rust?"));
+
+        let cache = CacheService::new(CacheConfig {
+            normalize: true,
+            normalize_lowercase: true,
+            ..Default::default()
+        });
+        assert_eq!(key(&cache, "What is Rust?"), key(&cache, "what  is RUST?"));
+    }
+
+    #[test]
+    fn test_version_changes_every_key() {
+        let messages = vec![ChatMessage {
+            role: Role::User,
+            content: "What is Rust?".to_string(),
+            tool_calls: None,
+            images: None,
+        }];
+        let options = OllamaOptions::default();
+        let keys = |version: &str| {
+            let cache = CacheService::new(CacheConfig {
+                version: version.to_string(),
+                ..Default::default()
+            });
+            (
+                cache.generate_key(&messages, "model", "prompt", &options, None, None),
+                cache.generate_prompt_key("What is Rust?", "model", &options),
+            )
+        };
+
+        let (unversioned, v1, v2) = (keys(""), keys("v1"), keys("v2"));
+        assert_eq!(v1, keys("v1"));
+        assert_ne!(v1.0, v2.0);
+        assert_ne!(v1.1, v2.1);
+        assert_ne!(unversioned.0, v1.0);
+        assert_ne!(unversioned.1, v1.1);
+    }
+
+    #[test]
+    fn test_exempt_patterns_match_message_content() {
+        let message = |content: &str| ChatMessage {
+            role: Role::User,
+            content: content.to_string(),
+            tool_calls: None,
+            images: None,
+        };
+        let cache = CacheService::new(CacheConfig {
+            exempt_patterns: vec![r"(?i)current time".to_string(), r"\bmy name\b".to_string()],
+            ..Default::default()
+        });
+
+        assert!(cache.is_exempt(&[message("What is the Current Time in Oslo?")]));
+        assert!(cache.is_exempt(&[message("Hi"), message("Do you remember my name?")]));
+        assert!(!cache.is_exempt(&[message("What is Rust?")]));
+        assert!(!CacheService::new(CacheConfig::default()).is_exempt(&[message("current time")]));
+    }
+
+    #[tokio::test]
+    async fn test_short_key_does_not_panic() {
+        let config = CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        };
+
+        let cache = CacheService::new(config);
+        cache.set("abc".to_string(), "value".to_string()).await;
+        assert_eq!(cache.get("abc").await, Some("value".to_string()));
+        assert_eq!(short_key("héllo wörld"), "héllo w");
+        assert_eq!(short_key("ab"), "ab");
+    }
+
+    #[tokio::test]
+    async fn test_semantic_lookup_respects_threshold() {
+        let config = CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            semantic_enabled: true,
+            similarity_threshold: 0.9,
+            ..Default::default()
+        };
+
+        let cache = CacheService::new(config);
+        cache
+            .set("semantic_key".to_string(), "answer".to_string())
+            .await;
+        cache
+            .add_embedding(
+                "semantic_key".to_string(),
+                "model".to_string(),
+                vec![1.0, 0.0, 0.0],
+            )
+            .await;
+
+        let similar = cache.find_similar("model", &[0.99, 0.1, 0.0]).await;
+        assert_eq!(similar, Some("answer".to_string()));
+
+        let dissimilar = cache.find_similar("model", &[0.0, 1.0, 0.0]).await;
+        assert!(dissimilar.is_none());
+
+        let other_model = cache.find_similar("other", &[1.0, 0.0, 0.0]).await;
+        assert!(other_model.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_semantic_matches_are_pruned_on_lookup() {
+        let cache = CacheService::new(CacheConfig {
+            semantic_enabled: true,
+            similarity_threshold: 0.9,
+            ..Default::default()
+        });
+        for (key, embedding) in [("closest", [1.0, 0.0]), ("close", [0.95, 0.1])] {
+            cache.set(key.to_string(), format!("{key} answer")).await;
+            cache
+                .add_embedding(key.to_string(), "scope".to_string(), embedding.to_vec())
+                .await;
+        }
+
+        // The best match's response is gone, so the next one serves and the stale one is dropped
+        cache.backend.remove("closest").await.unwrap();
+        let similar = cache.find_similar("scope", &[1.0, 0.0]).await;
+        assert_eq!(similar, Some("close answer".to_string()));
+        let index = cache.semantic_index.read().await;
+        let keys: Vec<_> = index.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, ["close"]);
+    }
+
+    #[tokio::test]
+    async fn test_semantic_lookup_requires_the_same_scope() {
+        let cache = CacheService::new(CacheConfig {
+            semantic_enabled: true,
+            similarity_threshold: 0.9,
+            ..Default::default()
+        });
+        let options = OllamaOptions::default();
+        let brief = cache.generate_scope("model", "Be brief.", &options, None, None);
+        let pirate = cache.generate_scope("model", "Talk like a pirate.", &options, None, None);
+        assert_ne!(brief, pirate);
+
+        cache.set("key".to_string(), "answer".to_string()).await;
+        cache
+            .add_embedding("key".to_string(), brief.clone(), vec![1.0, 0.0])
+            .await;
+
+        // Same prompt embedding, different system prompt
+        assert!(cache.find_similar(&pirate, &[1.0, 0.0]).await.is_none());
+        assert_eq!(
+            cache.find_similar(&brief, &[1.0, 0.0]).await,
+            Some("answer".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_semantic_hit_counts_as_one_lookup() {
+        let cache = CacheService::new(CacheConfig {
+            semantic_enabled: true,
+            similarity_threshold: 0.9,
+            ..Default::default()
+        });
+        cache
+            .set("original".to_string(), "answer".to_string())
+            .await;
+        cache
+            .add_embedding("original".to_string(), "model".to_string(), vec![1.0, 0.0])
+            .await;
+
+        // An exact miss answered by a similar prompt, as the chat handler does it
+        let mut cached = cache.lookup("rephrased").await;
+        if cached.is_none() {
+            cached = cache.find_similar("model", &[0.99, 0.1]).await;
+        }
+        cache.record_lookup("rephrased", cached.is_some());
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.hit_rate, 1.0);
+        assert_eq!(stats.miss_rate, 0.0);
+        assert_eq!(cache.stats.hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_persist_round_trip() {
+        let path = std::env::temp_dir().join(format!("cache-{}.jsonl", uuid::Uuid::new_v4()));
+        let config = CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            persist_path: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let cache = CacheService::new(config.clone());
+        cache
+            .set("persisted_key".to_string(), "persisted_value".to_string())
+            .await;
+        assert_eq!(cache.save_to_disk().await.unwrap(), 1);
+
+        let restored = CacheService::new(config);
+        assert_eq!(restored.load_from_disk().await.unwrap(), 1);
+        assert_eq!(
+            restored.get("persisted_key").await,
+            Some("persisted_value".to_string())
+        );
+
+        std::fs::remove_file(path).ok();
+    }
 }
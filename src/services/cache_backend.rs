@@ -0,0 +1,472 @@
+use crate::models::{CacheEntryInfo, CacheEntrySummary};
+use crate::utils::metrics::CACHE_EVICTIONS;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use moka::future::Cache;
+use moka::notification::RemovalCause;
+use moka::Expiry;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+/// Redis calls fail after this long, so an unreachable server only delays a cache miss
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a Redis key count is reused, since counting scans the whole keyspace
+const REDIS_COUNT_TTL: Duration = Duration::from_secs(30);
+
+/// Storage behind `CacheService`; hit/miss accounting and key generation stay in the service
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+
+    /// Entry with its expiry, for inspection
+    async fn get_entry(&self, key: &str) -> Result<Option<CacheEntryInfo>>;
+
+    /// Store `value`, expiring after `ttl` or the configured TTL
+    async fn insert(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()>;
+
+    /// Remove one entry, returning whether it existed
+    async fn remove(&self, key: &str) -> Result<bool>;
+
+    async fn contains(&self, key: &str) -> Result<bool>;
+
+    async fn clear(&self) -> Result<()>;
+
+    async fn usage(&self) -> Result<CacheUsage>;
+
+    /// The `limit` most-served entries with full keys, or `None` from backends that do not
+    /// count hits
+    async fn top_entries(&self, _limit: usize) -> Result<Option<Vec<CacheEntrySummary>>> {
+        Ok(None)
+    }
+
+    /// Live entries for `persist_path`; only the in-memory backend needs persisting
+    async fn export(&self) -> Vec<PersistedEntry> {
+        Vec::new()
+    }
+
+    async fn import(&self, _entry: PersistedEntry) {}
+}
+
+/// Entry count, approximate size in bytes, and evictions by cause since startup. Only the
+/// memory backend counts evictions; Redis expires and evicts entries on its own.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheUsage {
+    pub entries: u64,
+    pub bytes: u64,
+    pub evictions_size: u64,
+    pub evictions_ttl: u64,
+    pub evictions_explicit: u64,
+}
+
+/// Entries the memory backend has dropped, by why they went
+#[derive(Debug, Default)]
+struct Evictions {
+    size: AtomicU64,
+    ttl: AtomicU64,
+    explicit: AtomicU64,
+}
+
+impl Evictions {
+    fn record(&self, cause: RemovalCause, label: &'static str) {
+        let (counter, cause) = match cause {
+            RemovalCause::Size => (&self.size, "size"),
+            RemovalCause::Expired => (&self.ttl, "ttl"),
+            RemovalCause::Explicit => (&self.explicit, "explicit"),
+            // Overwriting an entry is not an eviction
+            RemovalCause::Replaced => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!(CACHE_EVICTIONS, "cache" => label, "cause" => cause).increment(1);
+    }
+}
+
+/// One line of the on-disk cache file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PersistedEntry {
+    pub key: String,
+    pub content: String,
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+    #[serde(default)]
+    pub hit_count: u64,
+}
+
+#[derive(Debug, Clone)]
+struct CachedEntry {
+    content: String,
+    /// Wall-clock creation time (ms), so expiry survives a save/load cycle
+    created_at: i64,
+    /// Overrides the configured TTL for this entry
+    ttl: Option<Duration>,
+    /// Shared between the clones moka hands out, so every get counts
+    hit_count: Arc<AtomicU64>,
+}
+
+impl CachedEntry {
+    fn new(content: String, created_at: i64, ttl: Option<Duration>, hit_count: u64) -> Self {
+        Self {
+            content,
+            created_at,
+            ttl,
+            hit_count: Arc::new(AtomicU64::new(hit_count)),
+        }
+    }
+
+    fn hits(&self) -> u64 {
+        self.hit_count.load(Ordering::Relaxed)
+    }
+}
+
+/// Expires entries `ttl` after their wall-clock creation time
+struct EntryExpiry {
+    ttl: Duration,
+}
+
+impl EntryExpiry {
+    fn remaining(&self, entry: &CachedEntry) -> Option<Duration> {
+        let age_ms = (Utc::now().timestamp_millis() - entry.created_at).max(0) as u64;
+        let ttl = entry.ttl.unwrap_or(self.ttl);
+        Some(ttl.saturating_sub(Duration::from_millis(age_ms)))
+    }
+}
+
+impl Expiry<String, CachedEntry> for EntryExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &CachedEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        self.remaining(value)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &CachedEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.remaining(value)
+    }
+}
+
+/// Per-process moka cache bounded by `max_capacity` bytes
+pub struct MemoryBackend {
+    cache: Cache<String, CachedEntry>,
+    ttl: Duration,
+    evictions: Arc<Evictions>,
+}
+
+impl MemoryBackend {
+    /// `label` is the `cache` label on the eviction metric
+    pub fn new(max_capacity: u64, ttl: Duration, label: &'static str) -> Self {
+        let evictions = Arc::new(Evictions::default());
+        let counts = evictions.clone();
+        let listener = move |_key: Arc<String>, _entry: CachedEntry, cause: RemovalCause| {
+            counts.record(cause, label)
+        };
+
+        // Weigh entries by their size in bytes so `max_capacity` is a real byte budget
+        let cache = Cache::builder()
+            .max_capacity(max_capacity)
+            .weigher(|key: &String, entry: &CachedEntry| {
+                (key.len() + entry.content.len())
+                    .try_into()
+                    .unwrap_or(u32::MAX)
+            })
+            .expire_after(EntryExpiry { ttl })
+            .eviction_listener(listener)
+            .build();
+
+        Self {
+            cache,
+            ttl,
+            evictions,
+        }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.cache.get(key).await.map(|entry| {
+            entry.hit_count.fetch_add(1, Ordering::Relaxed);
+            entry.content
+        }))
+    }
+
+    async fn get_entry(&self, key: &str) -> Result<Option<CacheEntryInfo>> {
+        let Some(entry) = self.cache.get(key).await else {
+            return Ok(None);
+        };
+        let expiry = EntryExpiry { ttl: self.ttl };
+
+        Ok(Some(CacheEntryInfo {
+            key: key.to_string(),
+            expires_in_seconds: expiry.remaining(&entry).unwrap_or_default().as_secs(),
+            created_at: chrono::DateTime::from_timestamp_millis(entry.created_at)
+                .map(|t| t.to_rfc3339()),
+            hit_count: entry.hits(),
+            content: entry.content,
+        }))
+    }
+
+    async fn insert(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        let entry = CachedEntry::new(value, Utc::now().timestamp_millis(), ttl, 0);
+        self.cache.insert(key, entry).await;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool> {
+        Ok(self.cache.remove(key).await.is_some())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.cache.get(key).await.is_some())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.cache.invalidate_all();
+        Ok(())
+    }
+
+    async fn usage(&self) -> Result<CacheUsage> {
+        // Apply pending inserts and evictions so the counts are current
+        self.cache.run_pending_tasks().await;
+        Ok(CacheUsage {
+            entries: self.cache.entry_count(),
+            bytes: self.cache.weighted_size(),
+            evictions_size: self.evictions.size.load(Ordering::Relaxed),
+            evictions_ttl: self.evictions.ttl.load(Ordering::Relaxed),
+            evictions_explicit: self.evictions.explicit.load(Ordering::Relaxed),
+        })
+    }
+
+    async fn top_entries(&self, limit: usize) -> Result<Option<Vec<CacheEntrySummary>>> {
+        let mut entries: Vec<_> = self
+            .cache
+            .iter()
+            .map(|(key, entry)| CacheEntrySummary {
+                key: key.to_string(),
+                hit_count: entry.hits(),
+                created_at: chrono::DateTime::from_timestamp_millis(entry.created_at)
+                    .map(|t| t.to_rfc3339()),
+                size_bytes: entry.content.len(),
+            })
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.hit_count));
+        entries.truncate(limit);
+        Ok(Some(entries))
+    }
+
+    async fn export(&self) -> Vec<PersistedEntry> {
+        self.cache
+            .iter()
+            .map(|(key, entry)| PersistedEntry {
+                key: key.to_string(),
+                hit_count: entry.hits(),
+                content: entry.content,
+                created_at: entry.created_at,
+                ttl_seconds: entry.ttl.map(|ttl| ttl.as_secs()),
+            })
+            .collect()
+    }
+
+    async fn import(&self, record: PersistedEntry) {
+        let entry = CachedEntry::new(
+            record.content,
+            record.created_at,
+            record.ttl_seconds.map(Duration::from_secs),
+            record.hit_count,
+        );
+        self.cache.insert(record.key, entry).await;
+    }
+}
+
+/// Redis-backed cache shared between instances. Values are stored as plain strings under
+/// `prefix + key` and expire through Redis' own TTL.
+pub struct RedisBackend {
+    url: String,
+    prefix: String,
+    ttl: Duration,
+    /// Connected on first use, so the service starts even while Redis is down
+    connection: OnceCell<ConnectionManager>,
+    /// Last key count and when it was taken
+    entry_count: Mutex<Option<(Instant, u64)>>,
+}
+
+impl RedisBackend {
+    pub fn new(url: String, prefix: String, ttl: Duration) -> Self {
+        Self {
+            url,
+            prefix,
+            ttl,
+            connection: OnceCell::new(),
+            entry_count: Mutex::new(None),
+        }
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| async {
+                let client = redis::Client::open(self.url.as_str())?;
+                let connection = ConnectionManager::new_with_backoff_and_timeouts(
+                    client,
+                    2,
+                    100,
+                    1,
+                    REDIS_TIMEOUT,
+                    REDIS_TIMEOUT,
+                )
+                .await?;
+                tracing::info!("🔌 Connected to Redis cache at {}", self.url);
+                Ok::<_, anyhow::Error>(connection)
+            })
+            .await?;
+        Ok(connection.clone())
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// Every key under this backend's prefix
+    async fn keys(&self) -> Result<Vec<String>> {
+        let mut connection = self.connection().await?;
+        let pattern = format!("{}*", self.prefix);
+        let mut iter = connection.scan_match::<_, String>(pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        let mut connection = self.connection().await?;
+        Ok(connection.get(self.key(key)).await?)
+    }
+
+    async fn get_entry(&self, key: &str) -> Result<Option<CacheEntryInfo>> {
+        let mut connection = self.connection().await?;
+        let (content, ttl): (Option<String>, i64) = redis::pipe()
+            .get(self.key(key))
+            .ttl(self.key(key))
+            .query_async(&mut connection)
+            .await?;
+
+        Ok(content.map(|content| CacheEntryInfo {
+            key: key.to_string(),
+            content,
+            // Redis only knows the remaining TTL, not when the entry was written or how often
+            // it was served
+            created_at: None,
+            expires_in_seconds: ttl.max(0) as u64,
+            hit_count: 0,
+        }))
+    }
+
+    async fn insert(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let seconds = ttl.unwrap_or(self.ttl).as_secs().max(1);
+        connection
+            .set_ex::<_, _, ()>(self.key(&key), value, seconds)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool> {
+        let mut connection = self.connection().await?;
+        let removed: u64 = connection.del(self.key(key)).await?;
+        Ok(removed > 0)
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool> {
+        let mut connection = self.connection().await?;
+        Ok(connection.exists(self.key(key)).await?)
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let keys = self.keys().await?;
+        let mut connection = self.connection().await?;
+        for chunk in keys.chunks(500) {
+            connection.del::<_, ()>(chunk).await?;
+        }
+        *self.entry_count.lock().unwrap() = None;
+        Ok(())
+    }
+
+    async fn usage(&self) -> Result<CacheUsage> {
+        // Sizes are not tracked; counting keys is enough to see the cache fill up, so a recent
+        // count is reused rather than scanning on every stats call
+        let cached = *self.entry_count.lock().unwrap();
+        let entries = match cached {
+            Some((counted_at, entries)) if counted_at.elapsed() < REDIS_COUNT_TTL => entries,
+            _ => {
+                let entries = self.keys().await?.len() as u64;
+                *self.entry_count.lock().unwrap() = Some((Instant::now(), entries));
+                entries
+            }
+        };
+        Ok(CacheUsage {
+            entries,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evictions_are_counted_by_cause() {
+        // moka only sweeps expired entries about once a second, so drive the listener directly
+        let evictions = Evictions::default();
+        for cause in [
+            RemovalCause::Expired,
+            RemovalCause::Size,
+            RemovalCause::Expired,
+            RemovalCause::Replaced,
+            RemovalCause::Explicit,
+        ] {
+            evictions.record(cause, "test");
+        }
+        assert_eq!(evictions.ttl.load(Ordering::Relaxed), 2);
+        assert_eq!(evictions.size.load(Ordering::Relaxed), 1);
+        assert_eq!(evictions.explicit.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    #[ignore] // Run only when Redis is available on REDIS_URL
+    async fn test_redis_round_trip() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1".to_string());
+        let prefix = format!("test-{}:", uuid::Uuid::new_v4());
+        let backend = RedisBackend::new(url, prefix, Duration::from_secs(60));
+
+        backend
+            .insert("key".to_string(), "value".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(backend.get("key").await.unwrap(), Some("value".to_string()));
+        let entry = backend.get_entry("key").await.unwrap().unwrap();
+        assert!(entry.expires_in_seconds > 0 && entry.expires_in_seconds <= 60);
+        assert_eq!(backend.usage().await.unwrap().entries, 1);
+
+        backend.clear().await.unwrap();
+        assert!(!backend.contains("key").await.unwrap());
+    }
+}
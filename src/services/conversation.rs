@@ -0,0 +1,155 @@
+use crate::config::ConversationConfig;
+use crate::models::{ChatMessage, Role};
+use crate::services::CacheService;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type SessionLock = Arc<tokio::sync::Mutex<()>>;
+
+/// Stores per-session chat history on top of the conversation cache
+#[derive(Clone)]
+pub struct ConversationStore {
+    cache: CacheService,
+    config: ConversationConfig,
+    /// Held while a session's history is read, extended and written back, so concurrent turns
+    /// in one session don't overwrite each other; entries exist only while in use
+    locks: Arc<Mutex<HashMap<String, SessionLock>>>,
+}
+
+impl ConversationStore {
+    pub fn new(cache: CacheService, config: ConversationConfig) -> Self {
+        Self {
+            cache,
+            config,
+            locks: Arc::default(),
+        }
+    }
+
+    fn session_lock(&self, session_id: &str) -> SessionLock {
+        let mut locks = self.locks.lock().unwrap();
+        locks.entry(session_id.to_string()).or_default().clone()
+    }
+
+    /// Forget a session's lock once no other update holds or waits on it
+    fn release(&self, session_id: &str, lock: SessionLock) {
+        let mut locks = self.locks.lock().unwrap();
+        // One reference in the map, one here
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(session_id);
+        }
+    }
+
+    fn key(session_id: &str) -> String {
+        format!("session:{}", session_id)
+    }
+
+    /// Get the stored history for a session (empty if none)
+    pub async fn history(&self, session_id: &str) -> Vec<ChatMessage> {
+        match self.cache.get(&Self::key(session_id)).await {
+            Some(serialized) => serde_json::from_str(&serialized).unwrap_or_else(|e| {
+                tracing::warn!("Discarding corrupt history for {}: {}", session_id, e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        }
+    }
+
+    /// Append an exchange (the new messages plus the assistant reply) to a session,
+    /// keeping only the most recent `max_turns` turns
+    pub async fn record_exchange(&self, session_id: &str, messages: &[ChatMessage], reply: &str) {
+        let reply = ChatMessage {
+            role: Role::Assistant,
+            content: reply.to_string(),
+            tool_calls: None,
+            images: None,
+        };
+        self.record_message(session_id, messages, reply).await;
+    }
+
+    /// Like `record_exchange`, but keeps the full reply message (e.g. tool calls)
+    pub async fn record_message(
+        &self,
+        session_id: &str,
+        messages: &[ChatMessage],
+        reply: ChatMessage,
+    ) {
+        let lock = self.session_lock(session_id);
+        let guard = lock.lock().await;
+        let mut history = self.history(session_id).await;
+        history.extend_from_slice(messages);
+        history.push(reply);
+
+        // A turn is one user message plus the assistant reply
+        let max_messages = self.config.max_turns.saturating_mul(2);
+        if history.len() > max_messages {
+            history.drain(..history.len() - max_messages);
+        }
+
+        match serde_json::to_string(&history) {
+            Ok(serialized) => self.cache.set(Self::key(session_id), serialized).await,
+            Err(e) => tracing::warn!("Failed to serialize history for {}: {}", session_id, e),
+        }
+        drop(guard);
+        self.release(session_id, lock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheConfig;
+
+    #[tokio::test]
+    async fn test_history_is_capped_to_max_turns() {
+        let cache = CacheService::new(CacheConfig {
+            max_size_mb: 10,
+            ttl_seconds: 60,
+            ..Default::default()
+        });
+        let store = ConversationStore::new(cache, ConversationConfig { max_turns: 2 });
+
+        for i in 0..3 {
+            let messages = vec![ChatMessage {
+                role: Role::User,
+                content: format!("question {}", i),
+                tool_calls: None,
+                images: None,
+            }];
+            store
+                .record_exchange("abc", &messages, &format!("answer {}", i))
+                .await;
+        }
+
+        let history = store.history("abc").await;
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0].content, "question 1");
+        assert_eq!(history[3].content, "answer 2");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_turns_are_all_kept() {
+        let cache = CacheService::new(CacheConfig::default());
+        let store = ConversationStore::new(cache, ConversationConfig { max_turns: 50 });
+
+        let turns = (0..20).map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let messages = vec![ChatMessage {
+                    role: Role::User,
+                    content: format!("question {}", i),
+                    tool_calls: None,
+                    images: None,
+                }];
+                store
+                    .record_exchange("abc", &messages, &format!("answer {}", i))
+                    .await;
+            })
+        });
+        for turn in futures::future::join_all(turns).await {
+            turn.unwrap();
+        }
+
+        assert_eq!(store.history("abc").await.len(), 40);
+        assert!(store.locks.lock().unwrap().is_empty());
+    }
+}
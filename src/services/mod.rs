@@ -1,9 +1,19 @@
+pub mod audit;
+pub mod backend;
 pub mod cache;
+pub mod cache_backend;
+pub mod conversation;
 pub mod ollama;
 pub mod queue;
 pub mod batch;
+pub mod single_flight;
+pub mod worker;
 
+pub use audit::AuditLog;
 pub use cache::CacheService;
-pub use ollama::OllamaClient;
+pub use conversation::ConversationStore;
+pub use ollama::{is_transient, stream_error_code, GenerationParams, OllamaClient, OllamaError};
 pub use queue::QueueService;
-pub use batch::BatchProcessor;
+pub use batch::{BatchProcessor, SharedReply};
+pub use single_flight::{Flight, FlightResult, SingleFlight};
+pub use worker::QueueWorker;
@@ -1,15 +1,125 @@
 use crate::config::OllamaConfig;
-use crate::models::{ChatMessage, OllamaRequest, OllamaResponse};
+use crate::models::{
+    BackendStatus, ChatMessage, DeletionOutcome, ModelDeletion, OllamaDeleteRequest,
+    OllamaEmbeddingRequest, OllamaEmbeddingResponse, OllamaGenerateRequest, OllamaGenerateResponse,
+    OllamaModelInfo, OllamaOptions, OllamaPullRequest, OllamaPullResponse, OllamaRequest,
+    OllamaResponse, OllamaTagsResponse, PullProgress, Role, StreamErrorCode,
+};
+use crate::services::backend::{BackendLease, BackendPool};
+use crate::utils::metrics::{OLLAMA_IN_FLIGHT, OLLAMA_LATENCY};
 use anyhow::{anyhow, Result};
 use futures::stream::{Stream, StreamExt};
-use reqwest::Client;
+use rand::Rng;
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
 use std::pin::Pin;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::time::sleep;
+
+/// Failures talking to Ollama, kept distinct so handlers can choose a status code
+#[derive(Debug, Error)]
+pub enum OllamaError {
+    /// Ollama could not be reached
+    #[error("Failed to send request to Ollama: {0}")]
+    Unavailable(String),
+    /// Ollama did not respond, or stopped streaming, in time
+    #[error("Ollama timed out: {0}")]
+    Timeout(String),
+    /// Ollama answered with a non-success status
+    #[error("Ollama API error: {status} - {body}")]
+    Api {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// Ollama answered, but not with something we could parse
+    #[error("Invalid response from Ollama: {0}")]
+    InvalidResponse(String),
+    /// The requested model is not installed on the backend
+    #[error("model '{0}' not installed; pull it first")]
+    ModelNotFound(String),
+    /// The generation ran past `ollama.max_generation_seconds`
+    #[error("generation exceeded the {0}s limit")]
+    DeadlineExceeded(u64),
+}
+
+/// Phrases in Ollama's error bodies that mean a model could not be loaded
+const LOAD_FAILURES: &[&str] = &[
+    "requires more system memory",
+    "out of memory",
+    "failed to load model",
+    "unable to load model",
+    "error loading model",
+    "llama runner process has terminated",
+    "timed out waiting for llama runner to start",
+];
+
+impl OllamaError {
+    /// Ollama closed a stream before its `done` chunk, as when the connection drops
+    pub fn stream_ended() -> Self {
+        Self::Unavailable("stream ended without done".to_string())
+    }
+
+    /// The backend has the model but could not load it, e.g. because it doesn't fit in
+    /// memory. A model that is not installed is not a load failure.
+    pub fn is_load_failure(&self) -> bool {
+        match self {
+            Self::Api { status, body } if status.is_server_error() => {
+                let body = body.to_lowercase();
+                LOAD_FAILURES.iter().any(|phrase| body.contains(phrase))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Classify an error that ended a stream or a queued request
+pub fn stream_error_code(error: &anyhow::Error) -> StreamErrorCode {
+    match error.downcast_ref::<OllamaError>() {
+        Some(OllamaError::Unavailable(_)) => StreamErrorCode::UpstreamUnavailable,
+        Some(OllamaError::Timeout(_)) | Some(OllamaError::DeadlineExceeded(_)) => {
+            StreamErrorCode::Timeout
+        }
+        Some(OllamaError::InvalidResponse(_)) => StreamErrorCode::ParseError,
+        Some(OllamaError::Api { .. }) | Some(OllamaError::ModelNotFound(_)) | None => {
+            StreamErrorCode::UpstreamError
+        }
+    }
+}
+
+/// Whether an error means Ollama is unreachable or stalled, rather than the request being bad
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<OllamaError>(),
+        Some(OllamaError::Unavailable(_)) | Some(OllamaError::Timeout(_))
+    )
+}
+
+/// Per-request settings layered on top of the configured defaults
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerationParams<'a> {
+    pub options: Option<&'a OllamaOptions>,
+    pub format: Option<&'a str>,
+    pub tools: Option<&'a [serde_json::Value]>,
+    /// Overrides the configured `keep_alive` for this request
+    pub keep_alive: Option<&'a str>,
+    /// When the generation must end by, from [`OllamaClient::generation_deadline`]. Unset, the
+    /// `ollama.max_generation_seconds` budget starts when the request is sent; set it to share
+    /// one budget across a retried generation.
+    pub deadline: Option<tokio::time::Instant>,
+}
 
 #[derive(Clone)]
 pub struct OllamaClient {
     client: Client,
+    backends: Arc<BackendPool>,
     config: OllamaConfig,
+    /// When each model last answered a request, so keep-warm pings skip busy models
+    last_used: Arc<Mutex<HashMap<String, Instant>>>,
 }
 
 impl OllamaClient {
@@ -18,57 +128,144 @@ impl OllamaClient {
             .timeout(Duration::from_secs(config.timeout_seconds))
             .build()
             .expect("Failed to create HTTP client");
+        let backends = Arc::new(BackendPool::new(config.backend_urls()));
 
-        Self { client, config }
+        Self {
+            client,
+            backends,
+            config,
+            last_used: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 
-    /// Send a chat completion request (non-streaming)
-    pub async fn chat_completion(
+    /// The settings this client was built with
+    pub fn config(&self) -> &OllamaConfig {
+        &self.config
+    }
+
+    /// Per-request options with unset fields filled from the configured defaults
+    pub fn resolve_options(&self, options: Option<&OllamaOptions>) -> OllamaOptions {
+        options
+            .map(|o| o.or(&self.config.options))
+            .unwrap_or_else(|| self.config.options.clone())
+    }
+
+    /// How long `model` stays loaded: the request's value, then the per-model value, then
+    /// the global default
+    pub fn keep_alive(&self, model: &str, requested: Option<&str>) -> String {
+        let per_model = self.config.keep_alive_by_model.get(model);
+        requested
+            .or(per_model.map(String::as_str))
+            .unwrap_or(&self.config.keep_alive)
+            .to_string()
+    }
+
+    /// Build a chat request, merging per-request options over the configured defaults
+    fn build_request(
         &self,
         messages: &[ChatMessage],
         model: &str,
         system_prompt: &str,
+        params: GenerationParams,
         stream: bool,
-    ) -> Result<String> {
+    ) -> OllamaRequest {
         let mut all_messages = vec![ChatMessage {
-            role: "system".to_string(),
+            role: Role::System,
             content: system_prompt.to_string(),
+            tool_calls: None,
+            images: None,
         }];
         all_messages.extend_from_slice(messages);
 
-        let request = OllamaRequest {
+        let options = self.resolve_options(params.options);
+
+        OllamaRequest {
             model: model.to_string(),
             messages: all_messages,
             stream,
-            keep_alive: Some(self.config.keep_alive.clone()),
-        };
+            keep_alive: Some(self.keep_alive(model, params.keep_alive)),
+            options: (!options.is_empty()).then_some(options),
+            format: params.format.map(str::to_string),
+            tools: params.tools.map(<[_]>::to_vec),
+        }
+    }
 
-        let url = format!("{}/api/chat", self.config.api_url);
+    /// Send a chat completion request (non-streaming)
+    pub async fn chat_completion(
+        &self,
+        messages: &[ChatMessage],
+        model: &str,
+        system_prompt: &str,
+        params: GenerationParams<'_>,
+        stream: bool,
+    ) -> Result<String> {
+        let message = self
+            .chat_message(messages, model, system_prompt, params, stream)
+            .await?;
+        Ok(message.content)
+    }
+
+    /// Send a chat request and return the full assistant message, including any tool calls
+    pub async fn chat_message(
+        &self,
+        messages: &[ChatMessage],
+        model: &str,
+        system_prompt: &str,
+        params: GenerationParams<'_>,
+        stream: bool,
+    ) -> Result<ChatMessage> {
         let response = self
-            .client
-            .post(&url)
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| anyhow!("Failed to send request to Ollama: {}", e))?;
+            .chat_response(messages, model, system_prompt, params, stream)
+            .await?;
+        Ok(response.into_message())
+    }
 
-        if !response.status().is_success() {
-            return Err(anyhow!(
-                "Ollama API error: {} - {}",
-                response.status(),
-                response.text().await.unwrap_or_default()
-            ));
-        }
+    /// Send a chat request and return Ollama's whole response, including token counts
+    pub async fn chat_response(
+        &self,
+        messages: &[ChatMessage],
+        model: &str,
+        system_prompt: &str,
+        params: GenerationParams<'_>,
+        stream: bool,
+    ) -> Result<OllamaResponse> {
+        let request = self.build_request(messages, model, system_prompt, params, stream);
+        let generate = async {
+            let (response, _lease) = self.send_for_model("/api/chat", &request, model).await?;
+            response
+                .json()
+                .await
+                .map_err(|e| OllamaError::InvalidResponse(e.to_string()).into())
+        };
+        let deadline = params.deadline.or_else(|| self.generation_deadline());
+        self.within_deadline(deadline, generate).await
+    }
 
-        let ollama_response: OllamaResponse = response
+    /// Complete a raw prompt via `/api/generate`, without any chat templating of messages
+    pub async fn generate(
+        &self,
+        prompt: &str,
+        model: &str,
+        options: Option<&OllamaOptions>,
+    ) -> Result<String> {
+        let options = self.resolve_options(options);
+        let request = OllamaGenerateRequest {
+            model: model.to_string(),
+            prompt: prompt.to_string(),
+            stream: false,
+            keep_alive: Some(self.keep_alive(model, None)),
+            options: (!options.is_empty()).then_some(options),
+        };
+        let (response, _lease) = self
+            .send_for_model("/api/generate", &request, model)
+            .await?;
+
+        let generate_response: OllamaGenerateResponse = response
             .json()
             .await
-            .map_err(|e| anyhow!("Failed to parse Ollama response: {}", e))?;
+            .map_err(|e| OllamaError::InvalidResponse(e.to_string()))?;
 
-        Ok(ollama_response
-            .message
-            .map(|m| m.content)
-            .unwrap_or_default())
+        Ok(generate_response.response)
     }
 
     /// Send a streaming chat completion request
@@ -77,91 +274,825 @@ impl OllamaClient {
         messages: &[ChatMessage],
         model: &str,
         system_prompt: &str,
+        params: GenerationParams<'_>,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<OllamaResponse>> + Send>>> {
-        let mut all_messages = vec![ChatMessage {
-            role: "system".to_string(),
-            content: system_prompt.to_string(),
-        }];
-        all_messages.extend_from_slice(messages);
+        let request = self.build_request(messages, model, system_prompt, params, true);
+        let deadline = params.deadline.or_else(|| self.generation_deadline());
+        let (response, lease) = self
+            .within_deadline(deadline, self.send_for_model("/api/chat", &request, model))
+            .await?;
+
+        // Ollama may pack several lines into one chunk or split a line across chunks.
+        // The lease lives as long as the stream so the backend counts as busy until it ends.
+        let stream = json_lines::<OllamaResponse, _, _>(response.bytes_stream()).map(move |item| {
+            let _lease = &lease;
+            item
+        });
+
+        let stream = match self.config.stream_idle_timeout_seconds {
+            0 => Box::pin(stream),
+            seconds => with_idle_timeout(Box::pin(stream), Duration::from_secs(seconds)),
+        };
+        Ok(match deadline {
+            Some(deadline) => with_deadline(stream, deadline, self.config.max_generation_seconds),
+            None => stream,
+        })
+    }
+
+    /// When a generation starting now must end by, per `ollama.max_generation_seconds`
+    pub fn generation_deadline(&self) -> Option<tokio::time::Instant> {
+        let seconds = self.config.max_generation_seconds;
+        (seconds > 0).then(|| tokio::time::Instant::now() + Duration::from_secs(seconds))
+    }
+
+    /// Give up on `future` with `DeadlineExceeded` once `deadline` passes
+    async fn within_deadline<T>(
+        &self,
+        deadline: Option<tokio::time::Instant>,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let Some(deadline) = deadline else {
+            return future.await;
+        };
+        let seconds = self.config.max_generation_seconds;
+        tokio::time::timeout_at(deadline, future)
+            .await
+            .unwrap_or_else(|_| {
+                tracing::warn!("⏱️  Generation ran past {}s; giving up", seconds);
+                Err(OllamaError::DeadlineExceeded(seconds).into())
+            })
+    }
+
+    /// Like `send_with_retry`, reporting Ollama's 404 as the model not being installed
+    async fn send_for_model<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+        model: &str,
+    ) -> Result<(Response, BackendLease)> {
+        let result = self.send_with_retry(path, body).await.map_err(|error| {
+            match error.downcast_ref::<OllamaError>() {
+                Some(OllamaError::Api { status, .. }) if *status == StatusCode::NOT_FOUND => {
+                    tracing::warn!("Model {} is not installed", model);
+                    OllamaError::ModelNotFound(model.to_string()).into()
+                }
+                _ => error,
+            }
+        });
+        if result.is_ok() {
+            self.record_use(model);
+        }
+        result
+    }
+
+    fn record_use(&self, model: &str) {
+        self.last_used
+            .lock()
+            .unwrap()
+            .insert(model.to_string(), Instant::now());
+    }
 
-        let request = OllamaRequest {
+    /// Whether `model` has gone unused for at least `idle`
+    fn idle_for(&self, model: &str, idle: Duration) -> bool {
+        self.last_used
+            .lock()
+            .unwrap()
+            .get(model)
+            .is_none_or(|used| used.elapsed() >= idle)
+    }
+
+    /// Load `model` without generating anything, resetting its `keep_alive` timer. This goes
+    /// straight to `send_with_retry`: a ping is not a use, so the next idle tick pings again.
+    async fn keep_warm(&self, model: &str) -> Result<()> {
+        let request = OllamaGenerateRequest {
             model: model.to_string(),
-            messages: all_messages,
-            stream: true,
-            keep_alive: Some(self.config.keep_alive.clone()),
+            prompt: String::new(),
+            stream: false,
+            keep_alive: Some(self.keep_alive(model, None)),
+            options: None,
         };
+        self.send_with_retry("/api/generate", &request).await?;
+        Ok(())
+    }
+
+    /// One tick of the keep-warm loop: ping `model` if nothing has used it for `period`
+    async fn keep_warm_tick(&self, model: &str, period: Duration) {
+        if !self.idle_for(model, period) {
+            return;
+        }
+        tracing::debug!("🔥 Keep-warm ping for {}", model);
+        if let Err(e) = self.keep_warm(model).await {
+            tracing::warn!("Keep-warm ping for {} failed: {}", model, e);
+        }
+    }
+
+    /// POST a request to an Ollama backend, recording in-flight and latency metrics
+    async fn send_with_retry<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<(Response, BackendLease)> {
+        let started = Instant::now();
+        metrics::gauge!(OLLAMA_IN_FLIGHT).increment(1.0);
+        let result = self.send_with_retry_inner(path, body).await;
+        metrics::gauge!(OLLAMA_IN_FLIGHT).decrement(1.0);
+        metrics::histogram!(OLLAMA_LATENCY).record(started.elapsed().as_secs_f64());
+        result
+    }
 
-        let url = format!("{}/api/chat", self.config.api_url);
+    /// Retry connection errors and 5xx responses with exponential backoff, moving to
+    /// another backend when one is unreachable. 4xx responses are returned immediately.
+    async fn send_with_retry_inner<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<(Response, BackendLease)> {
+        let mut attempt = 0;
+
+        loop {
+            let lease = self.backends.acquire();
+            let url = format!("{}{}", lease.url(), path);
+
+            let error = match self.client.post(&url).json(body).send().await {
+                Ok(response) if response.status().is_success() => return Ok((response, lease)),
+                Ok(response) => {
+                    let status = response.status();
+                    let error = OllamaError::Api {
+                        status,
+                        body: response.text().await.unwrap_or_default(),
+                    };
+                    if !status.is_server_error() {
+                        return Err(error.into());
+                    }
+                    error
+                }
+                Err(e) => {
+                    let retryable = e.is_connect();
+                    let error = if e.is_timeout() {
+                        OllamaError::Timeout(e.to_string())
+                    } else {
+                        OllamaError::Unavailable(e.to_string())
+                    };
+                    if !retryable {
+                        return Err(error.into());
+                    }
+                    lease.mark_unhealthy();
+                    error
+                }
+            };
+
+            if attempt >= self.config.max_retries {
+                return Err(error.into());
+            }
+            attempt += 1;
+
+            let delay = backoff_delay(self.config.retry_base_delay_ms, attempt);
+            tracing::warn!(
+                "🔁 Ollama request failed (attempt {}/{}), retrying in {}ms: {}",
+                attempt,
+                self.config.max_retries,
+                delay.as_millis(),
+                error
+            );
+            sleep(delay).await;
+        }
+    }
+
+    /// Generate an embedding vector for a single prompt
+    pub async fn generate_embedding(&self, model: &str, prompt: &str) -> Result<Vec<f32>> {
+        let request = OllamaEmbeddingRequest {
+            model: model.to_string(),
+            prompt: prompt.to_string(),
+        };
+
+        let (response, _lease) = self
+            .send_for_model("/api/embeddings", &request, model)
+            .await?;
+
+        let embedding_response: OllamaEmbeddingResponse = response
+            .json()
+            .await
+            .map_err(|e| OllamaError::InvalidResponse(e.to_string()))?;
+
+        Ok(embedding_response.embedding)
+    }
+
+    /// List the models installed on the Ollama server
+    pub async fn list_models(&self) -> Result<Vec<OllamaModelInfo>> {
+        let lease = self.backends.acquire();
+        let url = format!("{}/api/tags", lease.url());
         let response = self
             .client
-            .post(&url)
-            .json(&request)
+            .get(&url)
             .send()
             .await
-            .map_err(|e| anyhow!("Failed to send request to Ollama: {}", e))?;
-
-        if !response.status().is_success() {
-            return Err(anyhow!(
-                "Ollama API error: {}",
-                response.status()
-            ));
-        }
-
-        let stream = response.bytes_stream().map(move |result| {
-            result
-                .map_err(|e| anyhow!("Stream error: {}", e))
-                .and_then(|bytes| {
-                    let text = String::from_utf8(bytes.to_vec())
-                        .map_err(|e| anyhow!("UTF-8 error: {}", e))?;
-
-                    // Parse each line as JSON
-                    for line in text.lines() {
-                        if line.trim().is_empty() {
-                            continue;
-                        }
+            .map_err(|e| OllamaError::Unavailable(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(OllamaError::Api { status, body }.into());
+        }
+
+        let tags: OllamaTagsResponse = response
+            .json()
+            .await
+            .map_err(|e| OllamaError::InvalidResponse(e.to_string()))?;
+
+        Ok(tags.models)
+    }
+
+    /// Remove a model from every backend, returning what happened on each. Fails when no
+    /// backend deleted it: with the first backend's error, or `ModelNotFound` if none had it.
+    pub async fn delete_model(&self, model: &str) -> Result<Vec<ModelDeletion>> {
+        let backends: Vec<String> = self
+            .backends
+            .urls()
+            .map(|(_, url)| url.to_string())
+            .collect();
+        let request = OllamaDeleteRequest {
+            name: model.to_string(),
+        };
+
+        let mut deletions = Vec::with_capacity(backends.len());
+        let mut first_error = None;
+        for backend in backends {
+            let url = format!("{}/api/delete", backend);
+            let result = match self.client.delete(&url).json(&request).send().await {
+                Ok(response) => match response.status() {
+                    status if status.is_success() => Ok(DeletionOutcome::Deleted),
+                    // Not every backend need have every model
+                    StatusCode::NOT_FOUND => Ok(DeletionOutcome::NotFound),
+                    status => {
+                        let body = response.text().await.unwrap_or_default();
+                        Err(OllamaError::Api { status, body })
+                    }
+                },
+                Err(e) => Err(OllamaError::Unavailable(e.to_string())),
+            };
+
+            // Keep going, so one bad backend doesn't leave the others holding the model
+            let deletion = match result {
+                Ok(outcome) => ModelDeletion {
+                    url: backend,
+                    outcome,
+                    error: None,
+                },
+                Err(error) => {
+                    tracing::warn!("Failed to delete {} from {}: {}", model, backend, error);
+                    let deletion = ModelDeletion {
+                        url: backend,
+                        outcome: DeletionOutcome::Failed,
+                        error: Some(error.to_string()),
+                    };
+                    first_error.get_or_insert(error);
+                    deletion
+                }
+            };
+            deletions.push(deletion);
+        }
 
-                        match serde_json::from_str::<OllamaResponse>(line) {
-                            Ok(response) => return Ok(response),
-                            Err(e) => {
-                                tracing::warn!("Failed to parse line: {} - {}", line, e);
-                            }
+        if !deletions
+            .iter()
+            .any(|d| d.outcome == DeletionOutcome::Deleted)
+        {
+            let error = first_error.unwrap_or(OllamaError::ModelNotFound(model.to_string()));
+            return Err(error.into());
+        }
+        Ok(deletions)
+    }
+
+    /// Download a model onto every backend in turn, streaming Ollama's progress.
+    /// Stops at the first backend that fails, e.g. when the model is not in the registry.
+    pub fn pull_model(
+        &self,
+        model: &str,
+    ) -> Pin<Box<dyn Stream<Item = Result<PullProgress>> + Send>> {
+        let client = self.client.clone();
+        let timeout = Duration::from_secs(self.config.pull_timeout_seconds);
+        let backends: Vec<String> = self
+            .backends
+            .urls()
+            .map(|(_, url)| url.to_string())
+            .collect();
+        let request = OllamaPullRequest {
+            model: model.to_string(),
+            stream: true,
+        };
+
+        Box::pin(async_stream::stream! {
+            for backend in backends {
+                tracing::info!("⬇️  Pulling {} on {}", request.model, backend);
+                let url = format!("{}/api/pull", backend);
+                let sent = client.post(&url).json(&request).timeout(timeout).send().await;
+                let response = match sent {
+                    Ok(response) if response.status().is_success() => response,
+                    Ok(response) => {
+                        let status = response.status();
+                        let body = response.text().await.unwrap_or_default();
+                        yield Err(OllamaError::Api { status, body }.into());
+                        return;
+                    }
+                    Err(e) => {
+                        yield Err(OllamaError::Unavailable(e.to_string()).into());
+                        return;
+                    }
+                };
+
+                let mut lines =
+                    Box::pin(json_lines::<OllamaPullResponse, _, _>(response.bytes_stream()));
+                while let Some(line) = lines.next().await {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(e) => {
+                            yield Err(e);
+                            return;
                         }
+                    };
+                    if let Some(error) = line.error {
+                        let model = &request.model;
+                        yield Err(anyhow!("Failed to pull {} on {}: {}", model, backend, error));
+                        return;
                     }
 
-                    // If no valid response found, return an error
-                    Err(anyhow!("No valid response in chunk"))
-                })
-        });
+                    let percent = match (line.completed, line.total) {
+                        (Some(completed), Some(total)) if total > 0 => {
+                            Some(completed as f64 / total as f64 * 100.0)
+                        }
+                        _ => None,
+                    };
+                    yield Ok(PullProgress {
+                        backend: Some(backend.clone()),
+                        status: line.status,
+                        digest: line.digest,
+                        percent,
+                        done: false,
+                        error: None,
+                    });
+                }
+            }
+        })
+    }
 
-        Ok(Box::pin(stream))
+    /// Whether `model` is installed, treating an untagged name as `:latest`
+    pub async fn model_available(&self, model: &str) -> Result<bool> {
+        let models = self.list_models().await?;
+        Ok(models.iter().any(|m| model_matches(&m.name, model)))
     }
 
-    /// Check if Ollama is available
+    /// Check every backend, updating the rotation; true if any backend is available
     pub async fn health_check(&self) -> Result<bool> {
-        let url = format!("{}/api/tags", self.config.api_url);
-        match self.client.get(&url).send().await {
-            Ok(response) => Ok(response.status().is_success()),
-            Err(_) => Ok(false),
+        let mut any_healthy = false;
+
+        for (index, base_url) in self.backends.urls() {
+            let url = format!("{}/api/tags", base_url);
+            let healthy = match self.client.get(&url).send().await {
+                Ok(response) => response.status().is_success(),
+                Err(_) => false,
+            };
+            self.backends.set_healthy(index, healthy);
+            any_healthy |= healthy;
+        }
+
+        Ok(any_healthy)
+    }
+
+    /// Periodically re-check backends so failed ones rejoin the rotation once they recover
+    pub fn spawn_health_monitor(&self) {
+        let client = self.clone();
+        let period = Duration::from_secs(self.config.health_check_interval_seconds.max(1));
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let _ = client.health_check().await;
+            }
+        });
+    }
+
+    /// Every `keep_warm_interval_seconds`, load `model` again if nothing has used it since,
+    /// so it isn't unloaded once `keep_alive` runs out
+    pub fn spawn_keep_warm(&self, model: String) {
+        if self.config.keep_warm_interval_seconds == 0 {
+            return;
         }
+        let client = self.clone();
+        let period = Duration::from_secs(self.config.keep_warm_interval_seconds);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                client.keep_warm_tick(&model, period).await;
+            }
+        });
     }
+
+    /// Health and load of each configured backend
+    pub fn backend_status(&self) -> Vec<BackendStatus> {
+        self.backends.status()
+    }
+}
+
+/// End the stream with an error once Ollama has sent nothing for `idle_timeout`
+fn with_idle_timeout<T: Send + 'static>(
+    mut stream: Pin<Box<dyn Stream<Item = Result<T>> + Send>>,
+    idle_timeout: Duration,
+) -> Pin<Box<dyn Stream<Item = Result<T>> + Send>> {
+    Box::pin(async_stream::stream! {
+        loop {
+            match tokio::time::timeout(idle_timeout, stream.next()).await {
+                Ok(Some(item)) => yield item,
+                Ok(None) => break,
+                Err(_) => {
+                    let seconds = idle_timeout.as_secs_f32();
+                    tracing::warn!("⏱️  Ollama stream idle for {}s; closing it", seconds);
+                    let error = OllamaError::Timeout(format!("no output for {}s", seconds));
+                    yield Err(error.into());
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// End the stream with an error if it is still going at `deadline`
+fn with_deadline<T: Send + 'static>(
+    mut stream: Pin<Box<dyn Stream<Item = Result<T>> + Send>>,
+    deadline: tokio::time::Instant,
+    seconds: u64,
+) -> Pin<Box<dyn Stream<Item = Result<T>> + Send>> {
+    Box::pin(async_stream::stream! {
+        loop {
+            match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(item)) => yield item,
+                Ok(None) => break,
+                Err(_) => {
+                    tracing::warn!("⏱️  Generation ran past {}s; closing the stream", seconds);
+                    yield Err(OllamaError::DeadlineExceeded(seconds).into());
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Whether an installed model name refers to the requested one
+fn model_matches(installed: &str, requested: &str) -> bool {
+    installed == requested
+        || (!requested.contains(':') && installed == format!("{}:latest", requested))
+}
+
+/// Parse a newline-delimited JSON body, buffering lines split across chunks
+fn json_lines<T, B, E>(
+    bytes: impl Stream<Item = std::result::Result<B, E>> + Send,
+) -> impl Stream<Item = Result<T>> + Send
+where
+    T: DeserializeOwned + Send,
+    B: AsRef<[u8]> + Send,
+    E: std::fmt::Display + Send,
+{
+    async_stream::stream! {
+        let mut bytes = Box::pin(bytes);
+        let mut buffer = Vec::new();
+
+        while let Some(chunk) = bytes.next().await {
+            match chunk {
+                Ok(chunk) => buffer.extend_from_slice(chunk.as_ref()),
+                Err(e) => {
+                    yield Err(OllamaError::Unavailable(format!("stream error: {}", e)).into());
+                    return;
+                }
+            }
+
+            while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=newline).collect();
+                if let Some(item) = parse_json_line(&line) {
+                    yield item;
+                }
+            }
+        }
+
+        // The last line may not end with a newline
+        if let Some(item) = parse_json_line(&buffer) {
+            yield item;
+        }
+    }
+}
+
+/// Parse one line of a JSON-lines body; blank lines yield nothing
+fn parse_json_line<T: DeserializeOwned>(line: &[u8]) -> Option<Result<T>> {
+    let line = std::str::from_utf8(line).ok()?.trim();
+    if line.is_empty() {
+        return None;
+    }
+    let parsed = serde_json::from_str(line)
+        .map_err(|e| OllamaError::InvalidResponse(format!("{} in line: {}", e, line)).into());
+    Some(parsed)
+}
+
+/// Exponential backoff with jitter: `base * 2^(attempt - 1)` plus up to `base` ms random jitter
+fn backoff_delay(base_ms: u64, attempt: u32) -> Duration {
+    let exponential = base_ms.saturating_mul(1u64 << (attempt.saturating_sub(1)).min(16));
+    let jitter = if base_ms > 0 {
+        rand::thread_rng().gen_range(0..=base_ms)
+    } else {
+        0
+    };
+    Duration::from_millis(exponential.saturating_add(jitter))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::ChatRequest;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     fn create_test_client() -> OllamaClient {
         let config = OllamaConfig {
-            api_url: "http://localhost:11434".to_string(),
             model: "llama2".to_string(),
             system_prompt: "You are a helpful assistant.".to_string(),
-            keep_alive: "15m".to_string(),
-            timeout_seconds: 300,
+            ..Default::default()
         };
 
         OllamaClient::new(config)
     }
 
+    #[test]
+    fn test_request_options_override_config_defaults() {
+        let client = OllamaClient::new(OllamaConfig {
+            options: OllamaOptions {
+                temperature: Some(0.2),
+                top_k: Some(40),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let overrides = OllamaOptions {
+            temperature: Some(0.9),
+            num_ctx: Some(8192),
+            ..Default::default()
+        };
+        let params = GenerationParams {
+            options: Some(&overrides),
+            ..Default::default()
+        };
+        let request = client.build_request(&[], "model", "prompt", params, false);
+        let options = request.options.unwrap();
+        assert_eq!(options.temperature, Some(0.9));
+        assert_eq!(options.top_k, Some(40));
+        assert_eq!(options.num_ctx, Some(8192));
+
+        // Unset fields are omitted so Ollama applies its own defaults
+        let json = serde_json::to_value(&options).unwrap();
+        assert!(json.get("seed").is_none());
+
+        // No options at all omits the object entirely
+        let client = create_test_client();
+        let request =
+            client.build_request(&[], "model", "prompt", GenerationParams::default(), false);
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("options").is_none());
+    }
+
+    #[test]
+    fn test_keep_alive_precedence() {
+        let client = OllamaClient::new(OllamaConfig {
+            keep_alive: "15m".to_string(),
+            keep_alive_by_model: HashMap::from([("small".to_string(), "1m".to_string())]),
+            ..Default::default()
+        });
+
+        assert_eq!(client.keep_alive("big", None), "15m");
+        assert_eq!(client.keep_alive("small", None), "1m");
+        assert_eq!(client.keep_alive("small", Some("0")), "0");
+
+        let params = GenerationParams {
+            keep_alive: Some("-1"),
+            ..Default::default()
+        };
+        let request = client.build_request(&[], "small", "prompt", params, false);
+        assert_eq!(request.keep_alive.as_deref(), Some("-1"));
+    }
+
+    #[test]
+    fn test_stop_sequences_are_sent_in_options() {
+        let client = create_test_client();
+        let options = OllamaOptions {
+            stop: Some(vec!["\n\n".to_string(), "User:".to_string()]),
+            ..Default::default()
+        };
+        let params = GenerationParams {
+            options: Some(&options),
+            ..Default::default()
+        };
+        let request = client.build_request(&[], "model", "prompt", params, false);
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["options"]["stop"],
+            serde_json::json!(["\n\n", "User:"])
+        );
+
+        let request =
+            client.build_request(&[], "model", "prompt", GenerationParams::default(), false);
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.pointer("/options/stop").is_none());
+    }
+
+    #[test]
+    fn test_chat_request_stop_is_folded_into_options() {
+        let client = create_test_client();
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "messages": [{"role": "user", "content": "Hello"}],
+            "options": {"temperature": 0.5},
+            "stop": ["User:"],
+        }))
+        .unwrap();
+        let options = request.generation_options();
+        let params = GenerationParams {
+            options: options.as_ref(),
+            ..Default::default()
+        };
+        let ollama_request =
+            client.build_request(&request.messages, "model", "prompt", params, false);
+        let json = serde_json::to_value(&ollama_request).unwrap();
+        assert_eq!(json["options"]["stop"], serde_json::json!(["User:"]));
+        assert_eq!(json["options"]["temperature"], 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_handles_split_and_batched_lines() {
+        // One chunk ends mid-line, the next finishes it and carries a second line
+        let chunks = futures::stream::iter([
+            Ok::<_, std::convert::Infallible>(b"{\"status\":\"pulling\",\"total\":10,".to_vec()),
+            Ok(b"\"completed\":5}\n{\"status\":\"success\"}\n".to_vec()),
+        ]);
+
+        let lines: Vec<OllamaPullResponse> =
+            json_lines(chunks).map(|line| line.unwrap()).collect().await;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].completed, Some(5));
+        assert_eq!(lines[1].status, "success");
+    }
+
+    #[tokio::test]
+    async fn test_chat_chunk_with_two_lines_yields_both() {
+        let chunk = concat!(
+            r#"{"message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            "\n",
+            r#"{"message":{"role":"assistant","content":"lo"},"done":false}"#,
+            "\n",
+        );
+        let chunks = futures::stream::iter([
+            Ok::<_, std::convert::Infallible>(chunk.as_bytes().to_vec()),
+            Ok(br#"{"done":true}"#.to_vec()),
+        ]);
+        let responses: Vec<OllamaResponse> =
+            json_lines(chunks).map(|item| item.unwrap()).collect().await;
+
+        let content: Vec<_> = responses
+            .iter()
+            .filter_map(|r| r.message.as_ref().map(|m| m.content.as_str()))
+            .collect();
+        assert_eq!(content, ["Hel", "lo"]);
+        assert!(responses[2].done);
+    }
+
+    #[test]
+    fn test_untagged_model_names_match_latest() {
+        assert!(model_matches("llama3:latest", "llama3"));
+        assert!(model_matches("deepseek-r1:8b", "deepseek-r1:8b"));
+        assert!(!model_matches("deepseek-r1:8b", "deepseek-r1"));
+        assert!(!model_matches("llama3:latest", "llama3:8b"));
+    }
+
+    #[test]
+    fn test_tool_calls_round_trip() {
+        let tools = vec![serde_json::json!({
+            "type": "function",
+            "function": {"name": "get_weather", "parameters": {"type": "object"}}
+        })];
+        let params = GenerationParams {
+            tools: Some(&tools),
+            ..Default::default()
+        };
+        let request = create_test_client().build_request(&[], "model", "prompt", params, false);
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["tools"][0]["function"]["name"], "get_weather");
+
+        let response: OllamaResponse = serde_json::from_value(serde_json::json!({
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{"function": {"name": "get_weather", "arguments": {}}}]
+            },
+            "done": true
+        }))
+        .unwrap();
+        assert!(response.message.unwrap().has_tool_calls());
+    }
+
+    #[test]
+    fn test_images_are_sent_only_when_present() {
+        let messages: Vec<ChatMessage> = serde_json::from_value(serde_json::json!([
+            {"role": "user", "content": "What is in this picture?", "images": ["iVBORw0KGgo="]},
+            {"role": "user", "content": "And now?"}
+        ]))
+        .unwrap();
+        let request = create_test_client().build_request(
+            &messages,
+            "llava",
+            "prompt",
+            GenerationParams::default(),
+            false,
+        );
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["messages"][1]["images"],
+            serde_json::json!(["iVBORw0KGgo="])
+        );
+        assert!(json["messages"][0].get("images").is_none());
+        assert!(json["messages"][2].get("images").is_none());
+    }
+
+    #[test]
+    fn test_keep_warm_skips_recently_used_models() {
+        let client = create_test_client();
+        let period = Duration::from_secs(60);
+        assert!(client.idle_for("llama2", period));
+
+        client.record_use("llama2");
+        assert!(!client.idle_for("llama2", period));
+        assert!(client.idle_for("llama2", Duration::ZERO));
+        assert!(client.idle_for("mistral", period));
+    }
+
+    #[tokio::test]
+    async fn test_keep_warm_pings_on_every_idle_tick() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let counter = pings.clone();
+        let ollama_app = axum::Router::new().route(
+            "/api/generate",
+            axum::routing::post(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                axum::Json(serde_json::json!({"response": "", "done": true}))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+
+        let client = OllamaClient::new(OllamaConfig {
+            api_url: format!("http://{addr}"),
+            max_retries: 0,
+            ..Default::default()
+        });
+        let period = Duration::from_secs(60);
+        client.keep_warm_tick("llama3", period).await;
+        client.keep_warm_tick("llama3", period).await;
+        assert_eq!(pings.load(Ordering::SeqCst), 2);
+
+        // A real request does hold off the next ping
+        client.record_use("llama3");
+        client.keep_warm_tick("llama3", period).await;
+        assert_eq!(pings.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_load_failures_are_recognised_by_message() {
+        let api = |status, body: &str| OllamaError::Api {
+            status,
+            body: body.to_string(),
+        };
+        let oom = r#"{"error":"model requires more system memory (9.5 GiB) than is available"}"#;
+        assert!(api(StatusCode::INTERNAL_SERVER_ERROR, oom).is_load_failure());
+        let crashed = r#"{"error":"llama runner process has terminated: exit status 2"}"#;
+        assert!(api(StatusCode::INTERNAL_SERVER_ERROR, crashed).is_load_failure());
+        let cuda = r#"{"error":"CUDA error: out of memory"}"#;
+        assert!(api(StatusCode::INTERNAL_SERVER_ERROR, cuda).is_load_failure());
+
+        // Other errors that merely mention loading or memory are not
+        let other = r#"{"error":"failed to download payload"}"#;
+        assert!(!api(StatusCode::INTERNAL_SERVER_ERROR, other).is_load_failure());
+        assert!(!api(StatusCode::BAD_REQUEST, oom).is_load_failure());
+        assert!(!OllamaError::ModelNotFound("llama3".to_string()).is_load_failure());
+    }
+
+    #[test]
+    fn test_backoff_delay_grows_exponentially() {
+        for attempt in 1..=4 {
+            let delay = backoff_delay(100, attempt).as_millis() as u64;
+            let exponential = 100 * 2u64.pow(attempt - 1);
+            assert!(delay >= exponential && delay <= exponential + 100);
+        }
+        assert_eq!(backoff_delay(0, 3), Duration::ZERO);
+    }
+
     #[tokio::test]
     #[ignore] // Run only when Ollama is available
     async fn test_health_check() {
@@ -169,4 +1100,103 @@ mod tests {
         let result = client.health_check().await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_idle_stream_ends_with_an_error() {
+        let upstream = futures::stream::iter([Ok(1)]).chain(futures::stream::pending());
+        let stream = with_idle_timeout(Box::pin(upstream), Duration::from_millis(20));
+        let items: Vec<Result<i32>> = stream.collect().await;
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(*items[0].as_ref().unwrap(), 1);
+        let error = items[1].as_ref().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OllamaError>(),
+            Some(OllamaError::Timeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_past_deadline_ends_with_an_error() {
+        // Chunks keep coming, so only the deadline can stop it
+        let upstream = futures::stream::repeat_with(|| Ok(1)).then(|item| async move {
+            sleep(Duration::from_millis(5)).await;
+            item
+        });
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(50);
+        let stream = with_deadline(Box::pin(upstream), deadline, 1);
+        let items: Vec<Result<i32>> = stream.collect().await;
+
+        assert!(items.len() > 1);
+        let error = items.last().unwrap().as_ref().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OllamaError>(),
+            Some(OllamaError::DeadlineExceeded(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_delete_model_reports_missing_models() {
+        let ollama_app = axum::Router::new().route(
+            "/api/delete",
+            axum::routing::delete(
+                |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    match body["name"].as_str() {
+                        Some("llama3") => axum::http::StatusCode::OK,
+                        _ => axum::http::StatusCode::NOT_FOUND,
+                    }
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+
+        let client = OllamaClient::new(OllamaConfig {
+            api_url: format!("http://{addr}"),
+            max_retries: 0,
+            ..Default::default()
+        });
+        let deletions = client.delete_model("llama3").await.unwrap();
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(deletions[0].outcome, DeletionOutcome::Deleted);
+
+        let error = client.delete_model("ghost").await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OllamaError>(),
+            Some(OllamaError::ModelNotFound(model)) if model == "ghost"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_model_is_reported_as_not_installed() {
+        let ollama_app = axum::Router::new().route(
+            "/api/chat",
+            axum::routing::post(|| async {
+                (
+                    axum::http::StatusCode::NOT_FOUND,
+                    r#"{"error":"model \"ghost\" not found, try pulling it first"}"#,
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ollama_app).await.unwrap() });
+
+        let client = OllamaClient::new(OllamaConfig {
+            api_url: format!("http://{addr}"),
+            max_retries: 0,
+            ..Default::default()
+        });
+        let error = client
+            .chat_completion_stream(&[], "ghost", "", GenerationParams::default())
+            .await
+            .err()
+            .unwrap();
+
+        match error.downcast_ref::<OllamaError>() {
+            Some(OllamaError::ModelNotFound(model)) => assert_eq!(model, "ghost"),
+            other => panic!("expected ModelNotFound, got {other:?}"),
+        }
+    }
 }
@@ -1,134 +1,696 @@
 use crate::config::QueueConfig;
-use crate::models::{ChatMessage, QueueStatus};
-use std::collections::VecDeque;
+use crate::models::{
+    ChatMessage, ChatResponse, QueueStatus, QueuedRequestSummary, StreamErrorCode,
+};
+use crate::utils::metrics::QUEUE_LENGTH;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{broadcast, Mutex, Notify, RwLock};
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span};
 use uuid::Uuid;
 
+/// Tokens buffered per subscriber; one that falls further behind waits for the final result
+const TOKEN_CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("queue is draining for shutdown and not accepting new requests")]
+    Draining,
+    #[error("queue is full ({capacity} requests waiting)")]
+    Full {
+        capacity: usize,
+        /// About one request's processing time, when a slot should free up
+        retry_after_seconds: u64,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub struct QueuedRequest {
     pub id: String,
     pub messages: Vec<ChatMessage>,
     pub model: String,
     pub system_prompt: String,
+    pub priority: i32,
     pub timestamp: i64,
+    /// Cancelled when the client cancels the request, including mid-generation
+    pub cancel_token: CancellationToken,
+    /// Carries the request id, so every log line from enqueue to completion can be correlated
+    pub span: Span,
+    /// Insertion order, used to keep FIFO ordering within a priority level
+    sequence: u64,
+}
+
+/// Higher priority first; earlier insertion first within the same priority
+impl Ord for QueuedRequest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for QueuedRequest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedRequest {}
+
+impl QueuedRequest {
+    /// Span for one queued request, outliving the HTTP request that enqueued it
+    fn span(id: &str, model: &str) -> Span {
+        let span =
+            tracing::info_span!(parent: None, "queued_request", request_id = %id, model = %model);
+        span.follows_from(Span::current());
+        span
+    }
+
+    /// Requests with equal keys would generate the same reply
+    fn coalesce_key(&self) -> String {
+        serde_json::json!([self.model, self.system_prompt, self.messages]).to_string()
+    }
+}
+
+/// One line of the on-disk queue file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PersistedRequest {
+    id: String,
+    messages: Vec<ChatMessage>,
+    model: String,
+    system_prompt: String,
+    priority: i32,
+    timestamp: i64,
+}
+
+impl From<&QueuedRequest> for PersistedRequest {
+    fn from(request: &QueuedRequest) -> Self {
+        Self {
+            id: request.id.clone(),
+            messages: request.messages.clone(),
+            model: request.model.clone(),
+            system_prompt: request.system_prompt.clone(),
+            priority: request.priority,
+            timestamp: request.timestamp,
+        }
+    }
+}
+
+/// How a request finished
+#[derive(Debug, Clone)]
+pub enum RequestOutcome {
+    Success(ChatResponse),
+    Failed {
+        error: String,
+        code: StreamErrorCode,
+    },
+    Cancelled,
+}
+
+impl RequestOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RequestOutcome::Success(_) => "success",
+            RequestOutcome::Failed { .. } => "failed",
+            RequestOutcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Outcome of a request the worker has finished
+#[derive(Debug, Clone)]
+pub struct CompletedRequest {
+    pub outcome: RequestOutcome,
+    pub completed_at: i64,
+}
+
+/// Where a request currently is in its lifecycle
+#[derive(Debug, Clone)]
+pub enum RequestState {
+    Queued(QueueStatus),
+    Processing,
+    Completed(CompletedRequest),
+    /// Finished, but the result is past its TTL
+    Expired,
+    Unknown,
+}
+
+/// A request the worker is processing
+struct InProgress {
+    cancel_token: CancellationToken,
+    span: Span,
+    /// Persisted until completion, so a restart queues the request again
+    record: PersistedRequest,
+}
+
+/// Reply generated so far for a request being processed, and a channel for the tokens to come
+struct TokenFeed {
+    content: String,
+    sender: broadcast::Sender<String>,
 }
 
 #[derive(Clone)]
 pub struct QueueService {
-    queue: Arc<RwLock<VecDeque<QueuedRequest>>>,
-    processing: Arc<RwLock<bool>>,
+    queue: Arc<RwLock<BinaryHeap<QueuedRequest>>>,
+    next_sequence: Arc<AtomicU64>,
+    in_progress: Arc<RwLock<HashMap<String, InProgress>>>,
+    feeds: Arc<RwLock<HashMap<String, TokenFeed>>>,
+    results: Arc<RwLock<HashMap<String, CompletedRequest>>>,
+    /// Completion times of expired results, kept for another TTL to report them as expired
+    expired: Arc<RwLock<HashMap<String, i64>>>,
+    notify: Arc<Notify>,
+    draining: Arc<RwLock<bool>>,
+    /// Serializes writes to `persist_path` so the latest snapshot always wins
+    persist_lock: Arc<Mutex<()>>,
+    /// Queued requests served from another request's generation
+    deduplicated_requests: Arc<AtomicU64>,
     config: QueueConfig,
 }
 
 impl QueueService {
     pub fn new(config: QueueConfig) -> Self {
         Self {
-            queue: Arc::new(RwLock::new(VecDeque::new())),
-            processing: Arc::new(RwLock::new(false)),
+            queue: Arc::new(RwLock::new(BinaryHeap::new())),
+            next_sequence: Arc::new(AtomicU64::new(0)),
+            in_progress: Arc::new(RwLock::new(HashMap::new())),
+            feeds: Arc::new(RwLock::new(HashMap::new())),
+            results: Arc::new(RwLock::new(HashMap::new())),
+            expired: Arc::new(RwLock::new(HashMap::new())),
+            notify: Arc::new(Notify::new()),
+            draining: Arc::new(RwLock::new(false)),
+            persist_lock: Arc::new(Mutex::new(())),
+            deduplicated_requests: Arc::new(AtomicU64::new(0)),
             config,
         }
     }
 
-    /// Enqueue a new request
+    /// Enqueue a new request; higher `priority` values are dequeued first
     pub async fn enqueue(
         &self,
         messages: Vec<ChatMessage>,
         model: String,
         system_prompt: String,
-    ) -> String {
+        priority: i32,
+    ) -> Result<String, QueueError> {
+        if *self.draining.read().await {
+            return Err(QueueError::Draining);
+        }
+
         let id = Uuid::new_v4().to_string();
         let timestamp = chrono::Utc::now().timestamp_millis();
 
         let request = QueuedRequest {
+            span: QueuedRequest::span(&id, &model),
             id: id.clone(),
             messages,
             model,
             system_prompt,
+            priority,
             timestamp,
+            cancel_token: CancellationToken::new(),
+            sequence: self.next_sequence.fetch_add(1, AtomicOrdering::Relaxed),
         };
 
         let mut queue = self.queue.write().await;
-        queue.push_back(request);
+        let capacity = self.config.max_queue_size;
+        if capacity > 0 && queue.len() >= capacity {
+            return Err(QueueError::Full {
+                capacity,
+                retry_after_seconds: (self.config.estimated_time_per_request_ms / 1000).max(1),
+            });
+        }
+        let span = request.span.clone();
+        queue.push(request);
+        metrics::gauge!(QUEUE_LENGTH).set(queue.len() as f64);
 
-        tracing::debug!("📥 Request {} added to queue (length: {})", id, queue.len());
+        span.in_scope(|| {
+            tracing::debug!("📥 Request {} added to queue (length: {})", id, queue.len());
+        });
+        drop(queue);
+        self.notify.notify_one();
+        self.persist().await;
+
+        Ok(id)
+    }
 
-        id
+    /// The requested priority clamped to `0..=max_priority`, or `default_priority` when unset
+    pub fn resolve_priority(&self, requested: Option<i32>) -> i32 {
+        requested
+            .unwrap_or(self.config.default_priority)
+            .clamp(0, self.config.max_priority.max(0))
     }
 
-    /// Get status for a specific request
+    /// Get status for a specific queued request
     pub async fn get_status(&self, request_id: &str) -> Option<QueueStatus> {
         let queue = self.queue.read().await;
-        let processing = self.processing.read().await;
 
-        let position = queue.iter().position(|r| r.id == request_id);
+        let position = Self::ordered(&queue)
+            .iter()
+            .position(|r| r.id == request_id);
 
         position.map(|pos| {
             let queue_position = pos + 1;
             let queue_length = queue.len();
             let estimated_wait_time = pos as u64 * self.config.estimated_time_per_request_ms;
-            let is_processing = *processing && pos == 0;
 
             QueueStatus {
                 queue_position,
                 queue_length,
                 estimated_wait_time,
-                is_processing,
+                is_processing: false,
             }
         })
     }
 
+    /// Every waiting request in dequeue order; messages and system prompt only with
+    /// `include_content`
+    pub async fn list(&self, include_content: bool) -> Vec<QueuedRequestSummary> {
+        let queue = self.queue.read().await;
+        Self::ordered(&queue)
+            .into_iter()
+            .enumerate()
+            .map(|(pos, request)| QueuedRequestSummary {
+                request_id: request.id.clone(),
+                model: request.model.clone(),
+                priority: request.priority,
+                queue_position: pos + 1,
+                enqueued_at: chrono::DateTime::from_timestamp_millis(request.timestamp)
+                    .map(|t| t.to_rfc3339()),
+                message_count: request.messages.len(),
+                system_prompt: include_content.then(|| request.system_prompt.clone()),
+                messages: include_content.then(|| request.messages.clone()),
+            })
+            .collect()
+    }
+
+    /// Look up a request whether it is queued, being processed, or finished
+    pub async fn get_state(&self, request_id: &str) -> RequestState {
+        if let Some(status) = self.get_status(request_id).await {
+            return RequestState::Queued(status);
+        }
+        if self.in_progress.read().await.contains_key(request_id) {
+            return RequestState::Processing;
+        }
+
+        self.prune_results().await;
+        if let Some(completed) = self.results.read().await.get(request_id) {
+            return RequestState::Completed(completed.clone());
+        }
+        if self.expired.read().await.contains_key(request_id) {
+            return RequestState::Expired;
+        }
+        RequestState::Unknown
+    }
+
+    /// Drop results older than the TTL, remembering their ids for one more TTL
+    async fn prune_results(&self) {
+        let ttl_ms =
+            i64::try_from(self.config.result_ttl_seconds.saturating_mul(1000)).unwrap_or(i64::MAX);
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let mut results = self.results.write().await;
+        let mut expired = self.expired.write().await;
+        results.retain(|id, completed| {
+            let alive = now - completed.completed_at < ttl_ms;
+            if !alive {
+                expired.insert(id.clone(), completed.completed_at);
+            }
+            alive
+        });
+        expired.retain(|_, completed_at| now - *completed_at < ttl_ms * 2);
+    }
+
     /// Get general queue info
     pub async fn get_queue_info(&self) -> (usize, bool) {
         let queue = self.queue.read().await;
-        let processing = self.processing.read().await;
-        (queue.len(), *processing)
+        let in_progress = self.in_progress.read().await;
+        (queue.len(), !in_progress.is_empty())
     }
 
-    /// Dequeue the next request (internal use)
+    /// How long the oldest and newest queued requests have been waiting, in ms
+    pub async fn wait_times(&self) -> Option<(u64, u64)> {
+        let queue = self.queue.read().await;
+        let oldest = queue.iter().map(|r| r.timestamp).min()?;
+        let newest = queue.iter().map(|r| r.timestamp).max()?;
+        let now = chrono::Utc::now().timestamp_millis();
+        let age = |timestamp: i64| (now - timestamp).max(0) as u64;
+        Some((age(oldest), age(newest)))
+    }
+
+    /// Dequeue the next request and mark it as being processed (internal use)
     pub async fn dequeue(&self) -> Option<QueuedRequest> {
         let mut queue = self.queue.write().await;
-        let request = queue.pop_front();
+        let request = queue.pop();
+        metrics::gauge!(QUEUE_LENGTH).set(queue.len() as f64);
 
-        if request.is_some() {
-            tracing::debug!("📤 Request dequeued (remaining: {})", queue.len());
+        if let Some(request) = &request {
+            self.start_processing(request).await;
+            drop(queue);
+            self.persist().await;
         }
 
         request
     }
 
-    /// Mark queue as processing
-    pub async fn set_processing(&self, is_processing: bool) {
-        let mut processing = self.processing.write().await;
-        *processing = is_processing;
+    /// Take queued requests identical to `leader` from the next `coalesce_window - 1` entries,
+    /// marking them as being processed so one generation can serve them all
+    pub async fn take_duplicates(&self, leader: &QueuedRequest) -> Vec<QueuedRequest> {
+        let window = self.config.coalesce_window.saturating_sub(1);
+        if window == 0 {
+            return Vec::new();
+        }
+
+        let mut queue = self.queue.write().await;
+        let key = leader.coalesce_key();
+        let ids: Vec<String> = Self::ordered(&queue)
+            .into_iter()
+            .take(window)
+            .filter(|r| !r.cancel_token.is_cancelled() && r.coalesce_key() == key)
+            .map(|r| r.id.clone())
+            .collect();
+        if ids.is_empty() {
+            return Vec::new();
+        }
+
+        let remaining = std::mem::take(&mut *queue);
+        let (duplicates, rest): (Vec<_>, Vec<_>) =
+            remaining.into_iter().partition(|r| ids.contains(&r.id));
+        queue.extend(rest);
+        metrics::gauge!(QUEUE_LENGTH).set(queue.len() as f64);
+        drop(queue);
+
+        for request in &duplicates {
+            self.start_processing(request).await;
+        }
+        self.deduplicated_requests
+            .fetch_add(duplicates.len() as u64, AtomicOrdering::Relaxed);
+        tracing::debug!(
+            "🔗 Coalesced {} queued requests into {}",
+            duplicates.len(),
+            leader.id
+        );
+        self.persist().await;
+        duplicates
+    }
+
+    /// Queued requests served from another request's generation so far
+    pub fn deduplicated_requests(&self) -> u64 {
+        self.deduplicated_requests.load(AtomicOrdering::Relaxed)
     }
 
-    /// Check if we can process more requests
-    pub async fn can_process(&self) -> bool {
-        let processing = self.processing.read().await;
-        !*processing
+    /// Open the token feed for a request and mark it as in progress
+    async fn start_processing(&self, request: &QueuedRequest) {
+        let (sender, _) = broadcast::channel(TOKEN_CHANNEL_CAPACITY);
+        let feed = TokenFeed {
+            content: String::new(),
+            sender,
+        };
+        self.feeds.write().await.insert(request.id.clone(), feed);
+        let in_progress = InProgress {
+            cancel_token: request.cancel_token.clone(),
+            span: request.span.clone(),
+            record: PersistedRequest::from(request),
+        };
+        self.in_progress
+            .write()
+            .await
+            .insert(request.id.clone(), in_progress);
+
+        let waited_ms = (chrono::Utc::now().timestamp_millis() - request.timestamp).max(0);
+        request.span.in_scope(|| {
+            tracing::debug!(waited_ms, "📤 Request {} dequeued", request.id);
+        });
     }
 
-    /// Cancel a request
+    /// Wait until a request is available, then dequeue it
+    pub async fn next_request(&self) -> QueuedRequest {
+        loop {
+            if let Some(request) = self.dequeue().await {
+                return request;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Forward a generated token to clients streaming this request
+    pub async fn publish_token(&self, request_id: &str, token: &str) {
+        if let Some(feed) = self.feeds.write().await.get_mut(request_id) {
+            feed.content.push_str(token);
+            // Sending only fails when nobody is streaming the request
+            let _ = feed.sender.send(token.to_string());
+        }
+    }
+
+    /// The reply generated so far and a receiver for the rest, while the request is being
+    /// processed. The receiver closes once the request completes.
+    pub async fn subscribe(
+        &self,
+        request_id: &str,
+    ) -> Option<(String, broadcast::Receiver<String>)> {
+        let feeds = self.feeds.read().await;
+        let feed = feeds.get(request_id)?;
+        Some((feed.content.clone(), feed.sender.subscribe()))
+    }
+
+    /// Record the outcome of a processed request
+    pub async fn complete(&self, request_id: &str, outcome: RequestOutcome) {
+        let label = outcome.as_str();
+        let completed = CompletedRequest {
+            outcome,
+            completed_at: chrono::Utc::now().timestamp_millis(),
+        };
+        // Store the result first, so the request never looks unknown in between
+        self.results
+            .write()
+            .await
+            .insert(request_id.to_string(), completed);
+        let processed = self.in_progress.write().await.remove(request_id);
+        self.feeds.write().await.remove(request_id);
+        self.prune_results().await;
+        if processed.is_some() {
+            self.persist().await;
+        }
+
+        // Requests cancelled while still queued are completed inside their own span already
+        let span = processed.map_or_else(Span::current, |p| p.span);
+        span.in_scope(|| {
+            tracing::debug!(outcome = label, "✅ Request {} completed", request_id);
+        });
+    }
+
+    /// Cancel a request, aborting the upstream call if it is already being processed
     pub async fn cancel(&self, request_id: &str) -> bool {
         let mut queue = self.queue.write().await;
 
-        if let Some(pos) = queue.iter().position(|r| r.id == request_id) {
-            queue.remove(pos);
-            tracing::debug!("❌ Request {} cancelled", request_id);
+        let mut removed = None;
+        queue.retain(|r| {
+            let keep = r.id != request_id;
+            if !keep {
+                removed = Some(r.span.clone());
+            }
+            keep
+        });
+        metrics::gauge!(QUEUE_LENGTH).set(queue.len() as f64);
+
+        if let Some(span) = removed {
+            drop(queue);
+            self.persist().await;
+            span.in_scope(|| tracing::debug!("❌ Request {} cancelled", request_id));
+            self.complete(request_id, RequestOutcome::Cancelled)
+                .instrument(span)
+                .await;
+            return true;
+        }
+        drop(queue);
+
+        if let Some(in_progress) = self.in_progress.read().await.get(request_id) {
+            in_progress.cancel_token.cancel();
+            in_progress.span.in_scope(|| {
+                tracing::debug!("❌ In-flight request {} cancelled", request_id);
+            });
             return true;
         }
 
         false
     }
 
+    /// Cancel every queued and in-flight request, returning how many were cancelled
+    pub async fn clear(&self) -> usize {
+        let removed: Vec<QueuedRequest> = {
+            let mut queue = self.queue.write().await;
+            let removed = queue.drain().collect();
+            metrics::gauge!(QUEUE_LENGTH).set(0.0);
+            removed
+        };
+        self.persist().await;
+        for request in &removed {
+            self.complete(&request.id, RequestOutcome::Cancelled)
+                .instrument(request.span.clone())
+                .await;
+        }
+
+        // The worker records these as cancelled once their generation stops
+        let in_flight = {
+            let in_progress = self.in_progress.read().await;
+            for request in in_progress.values() {
+                request.cancel_token.cancel();
+            }
+            in_progress.len()
+        };
+
+        let cancelled = removed.len() + in_flight;
+        tracing::warn!(
+            "🧹 Queue cleared: {} queued and {} in-flight requests cancelled",
+            removed.len(),
+            in_flight
+        );
+        cancelled
+    }
+
+    /// Queued requests in the order they will be dequeued
+    fn ordered(queue: &BinaryHeap<QueuedRequest>) -> Vec<&QueuedRequest> {
+        let mut ordered: Vec<&QueuedRequest> = queue.iter().collect();
+        ordered.sort_by(|a, b| b.cmp(a));
+        ordered
+    }
+
+    /// Stop accepting new requests and wait up to `timeout` for queued ones to finish.
+    /// Returns the number of requests still queued when draining stopped.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        *self.draining.write().await = true;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (remaining, is_processing) = self.get_queue_info().await;
+            if (remaining == 0 && !is_processing) || Instant::now() >= deadline {
+                return remaining;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Write the queued and in-progress requests to `persist_path`, logging rather than
+    /// failing on errors. In-progress requests are written as queued, so one interrupted by a
+    /// shutdown runs again after the restart.
+    async fn persist(&self) {
+        let Some(path) = &self.config.persist_path else {
+            return;
+        };
+
+        let _guard = self.persist_lock.lock().await;
+        let mut contents = String::new();
+        {
+            let queue = self.queue.read().await;
+            let in_progress = self.in_progress.read().await;
+            let queued = Self::ordered(&queue)
+                .into_iter()
+                .map(PersistedRequest::from);
+            let records = in_progress.values().map(|p| p.record.clone()).chain(queued);
+            for record in records {
+                match serde_json::to_string(&record) {
+                    Ok(line) => {
+                        contents.push_str(&line);
+                        contents.push('\n');
+                    }
+                    Err(e) => tracing::warn!("Failed to serialize queued request: {}", e),
+                }
+            }
+        }
+
+        // Write to a temporary file first so a crash never leaves a truncated queue file
+        let tmp_path = format!("{}.tmp", path);
+        let result = async {
+            tokio::fs::write(&tmp_path, contents).await?;
+            tokio::fs::rename(&tmp_path, path).await
+        };
+        if let Err(e) = result.await {
+            tracing::warn!("Failed to persist queue to {}: {}", path, e);
+        }
+    }
+
+    /// Restore queued requests from `persist_path`, dropping any older than
+    /// `persist_max_age_seconds`
+    pub async fn load_from_disk(&self) -> Result<usize> {
+        let Some(path) = &self.config.persist_path else {
+            return Ok(0);
+        };
+
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let max_age_ms = i64::try_from(self.config.persist_max_age_seconds.saturating_mul(1000))
+            .unwrap_or(i64::MAX);
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut records: Vec<PersistedRequest> = Vec::new();
+        let mut stale = 0;
+
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<PersistedRequest>(line) {
+                Ok(record) if now - record.timestamp >= max_age_ms => stale += 1,
+                Ok(record) => records.push(record),
+                Err(e) => tracing::warn!("Skipping malformed queued request: {}", e),
+            }
+        }
+
+        // Re-number in arrival order so FIFO ordering within a priority is preserved
+        records.sort_by_key(|record| record.timestamp);
+        let count = records.len();
+
+        let mut queue = self.queue.write().await;
+        for record in records {
+            queue.push(QueuedRequest {
+                span: QueuedRequest::span(&record.id, &record.model),
+                id: record.id,
+                messages: record.messages,
+                model: record.model,
+                system_prompt: record.system_prompt,
+                priority: record.priority,
+                timestamp: record.timestamp,
+                cancel_token: CancellationToken::new(),
+                sequence: self.next_sequence.fetch_add(1, AtomicOrdering::Relaxed),
+            });
+        }
+        metrics::gauge!(QUEUE_LENGTH).set(queue.len() as f64);
+        drop(queue);
+
+        if count > 0 {
+            self.notify.notify_one();
+        }
+        tracing::info!(
+            "📂 Restored {} queued requests from {} ({} stale dropped)",
+            count,
+            path,
+            stale
+        );
+        Ok(count)
+    }
+
     /// Get queue length
+    #[cfg(test)]
     pub async fn len(&self) -> usize {
         let queue = self.queue.read().await;
         queue.len()
     }
 
     /// Check if queue is empty
+    #[cfg(test)]
     pub async fn is_empty(&self) -> bool {
         let queue = self.queue.read().await;
         queue.is_empty()
@@ -138,21 +700,22 @@ impl QueueService {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::Role;
+
+    fn test_queue() -> QueueService {
+        QueueService::new(QueueConfig::default())
+    }
 
     #[tokio::test]
     async fn test_queue_service() {
-        let config = QueueConfig {
-            max_concurrent: 1,
-            estimated_time_per_request_ms: 30000,
-        };
-
-        let queue = QueueService::new(config);
+        let queue = test_queue();
 
         // Test enqueue
         let messages = vec![];
         let id = queue
-            .enqueue(messages, "model".to_string(), "prompt".to_string())
-            .await;
+            .enqueue(messages, "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
 
         assert_eq!(queue.len().await, 1);
 
@@ -165,5 +728,403 @@ mod tests {
         let request = queue.dequeue().await;
         assert!(request.is_some());
         assert_eq!(queue.len().await, 0);
+        assert!(matches!(
+            queue.get_state(&id).await,
+            RequestState::Processing
+        ));
+
+        // Test completion
+        let response = ChatResponse {
+            message: ChatMessage {
+                role: Role::Assistant,
+                content: "done".to_string(),
+                tool_calls: None,
+                images: None,
+            },
+            cached: Some(false),
+            dropped_messages: None,
+            fallback_used: None,
+            messages: None,
+            done_reason: None,
+        };
+        queue.complete(&id, RequestOutcome::Success(response)).await;
+        match queue.get_state(&id).await {
+            RequestState::Completed(CompletedRequest {
+                outcome: RequestOutcome::Success(response),
+                ..
+            }) => assert_eq!(response.message.content, "done"),
+            other => panic!("unexpected state: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persisted_queue_survives_restart() {
+        let path = std::env::temp_dir().join(format!("queue-{}.jsonl", Uuid::new_v4()));
+        let config = QueueConfig {
+            persist_path: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let queue = QueueService::new(config.clone());
+        let first = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+        let second = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+
+        // A request queued two hours ago, past the one hour max age
+        let stale = PersistedRequest {
+            id: "stale".to_string(),
+            messages: vec![],
+            model: "model".to_string(),
+            system_prompt: "prompt".to_string(),
+            priority: 0,
+            timestamp: chrono::Utc::now().timestamp_millis() - 2 * 60 * 60 * 1000,
+        };
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        contents.push_str(&serde_json::to_string(&stale).unwrap());
+        std::fs::write(&path, contents).unwrap();
+
+        let restored = QueueService::new(config);
+        assert_eq!(restored.load_from_disk().await.unwrap(), 2);
+        assert_eq!(restored.dequeue().await.unwrap().id, first);
+        assert_eq!(restored.dequeue().await.unwrap().id, second);
+        assert!(restored.dequeue().await.is_none());
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_in_progress_requests_are_persisted_as_queued() {
+        let path = std::env::temp_dir().join(format!("queue-{}.jsonl", Uuid::new_v4()));
+        let config = QueueConfig {
+            persist_path: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let queue = QueueService::new(config.clone());
+        let id = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+        assert_eq!(queue.dequeue().await.unwrap().id, id);
+
+        // Interrupted mid-generation, the request is queued again after a restart
+        let restored = QueueService::new(config.clone());
+        assert_eq!(restored.load_from_disk().await.unwrap(), 1);
+        assert_eq!(restored.dequeue().await.unwrap().id, id);
+
+        // Once it completes, it no longer survives a restart
+        queue
+            .complete(
+                &id,
+                RequestOutcome::Failed {
+                    error: "boom".to_string(),
+                    code: StreamErrorCode::UpstreamError,
+                },
+            )
+            .await;
+        let restored = QueueService::new(config);
+        assert_eq!(restored.load_from_disk().await.unwrap(), 0);
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_results_expire_after_ttl() {
+        // A result that finished 90 minutes ago, past the one hour TTL
+        let queue = test_queue();
+        let completed_at = chrono::Utc::now().timestamp_millis() - 90 * 60 * 1000;
+        queue.results.write().await.insert(
+            "old".to_string(),
+            CompletedRequest {
+                outcome: RequestOutcome::Cancelled,
+                completed_at,
+            },
+        );
+
+        assert!(matches!(
+            queue.get_state("old").await,
+            RequestState::Expired
+        ));
+        assert!(matches!(
+            queue.get_state("unknown").await,
+            RequestState::Unknown
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_in_window_are_coalesced() {
+        let config = QueueConfig {
+            coalesce_window: 3,
+            ..Default::default()
+        };
+
+        let queue = QueueService::new(config);
+        let mut ids = Vec::new();
+        for model in ["a", "b", "a", "a"] {
+            let id = queue
+                .enqueue(vec![], model.to_string(), "prompt".to_string(), 0)
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        // Only the two entries after the leader are in the window, so the last "a" waits
+        let leader = queue.dequeue().await.unwrap();
+        let duplicates = queue.take_duplicates(&leader).await;
+        let duplicate_ids: Vec<_> = duplicates.iter().map(|r| r.id.clone()).collect();
+        assert_eq!(duplicate_ids, [ids[2].clone()]);
+        assert_eq!(queue.deduplicated_requests(), 1);
+        assert!(matches!(
+            queue.get_state(&ids[2]).await,
+            RequestState::Processing
+        ));
+        assert_eq!(queue.len().await, 2);
+        assert_eq!(queue.dequeue().await.unwrap().id, ids[1]);
+    }
+
+    /// Collects formatted log output for assertions
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events_share_the_request_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let queue = test_queue();
+        let id = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+        queue.dequeue().await.unwrap();
+        queue.complete(&id, RequestOutcome::Cancelled).await;
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let span = format!("queued_request{{request_id={id} model=model}}");
+        let lines: Vec<_> = output.lines().filter(|l| l.contains(&span)).collect();
+        assert_eq!(lines.len(), 3, "{output}");
+        assert!(lines[0].contains("added to queue"), "{output}");
+        assert!(lines[1].contains("dequeued") && lines[1].contains("waited_ms="));
+        assert!(lines[2].contains("outcome=\"cancelled\""), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_cancel_in_flight_request_fires_token() {
+        let queue = test_queue();
+        let id = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+
+        let request = queue.dequeue().await.unwrap();
+        assert!(!request.cancel_token.is_cancelled());
+
+        assert!(queue.cancel(&id).await);
+        assert!(request.cancel_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_is_dequeued_first() {
+        let queue = test_queue();
+        let mut ids = Vec::new();
+        for priority in [0, 0, 5] {
+            let id = queue
+                .enqueue(vec![], "model".to_string(), "prompt".to_string(), priority)
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        // Position reflects priority order, not insertion order
+        assert_eq!(queue.get_status(&ids[2]).await.unwrap().queue_position, 1);
+        assert_eq!(queue.get_status(&ids[0]).await.unwrap().queue_position, 2);
+
+        // FIFO within the same priority
+        assert_eq!(queue.dequeue().await.unwrap().id, ids[2]);
+        assert_eq!(queue.dequeue().await.unwrap().id, ids[0]);
+        assert_eq!(queue.dequeue().await.unwrap().id, ids[1]);
+
+        // Missing priorities take the default, out-of-range ones are clamped
+        assert_eq!(queue.resolve_priority(None), 0);
+        assert_eq!(queue.resolve_priority(Some(i32::MAX)), 10);
+        assert_eq!(queue.resolve_priority(Some(-5)), 0);
+    }
+
+    #[tokio::test]
+    async fn test_list_hides_content_unless_requested() {
+        let queue = test_queue();
+        let messages = vec![ChatMessage {
+            role: Role::User,
+            content: "my secret question".to_string(),
+            tool_calls: None,
+            images: None,
+        }];
+        let low = queue
+            .enqueue(messages, "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+        let high = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 5)
+            .await
+            .unwrap();
+
+        let list = queue.list(false).await;
+        let ids: Vec<_> = list.iter().map(|r| r.request_id.as_str()).collect();
+        assert_eq!(ids, [high.as_str(), low.as_str()]);
+        assert_eq!(list[1].queue_position, 2);
+        assert_eq!(list[1].message_count, 1);
+        let json = serde_json::to_string(&list).unwrap();
+        assert!(!json.contains("my secret question"));
+
+        let list = queue.list(true).await;
+        let messages = list[1].messages.as_ref().unwrap();
+        assert_eq!(messages[0].content, "my secret question");
+        assert_eq!(list[1].system_prompt.as_deref(), Some("prompt"));
+    }
+
+    #[tokio::test]
+    async fn test_drain_rejects_new_requests() {
+        let queue = test_queue();
+        queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+
+        let remaining = queue.drain(Duration::from_millis(10)).await;
+        assert_eq!(remaining, 1);
+
+        let result = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await;
+        assert!(matches!(result, Err(QueueError::Draining)));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_new_requests() {
+        let config = QueueConfig {
+            max_queue_size: 2,
+            ..Default::default()
+        };
+
+        let queue = QueueService::new(config);
+        for _ in 0..2 {
+            queue
+                .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+                .await
+                .unwrap();
+        }
+
+        let result = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await;
+        assert!(matches!(
+            result,
+            Err(QueueError::Full {
+                capacity: 2,
+                retry_after_seconds: 30
+            })
+        ));
+        assert_eq!(queue.len().await, 2);
+
+        // Room frees up once the worker takes a request
+        queue.dequeue().await.unwrap();
+        assert!(queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wait_times_track_oldest_and_newest() {
+        let queue = test_queue();
+        assert_eq!(queue.wait_times().await, None);
+
+        for _ in 0..2 {
+            queue
+                .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+                .await
+                .unwrap();
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        let (oldest, newest) = queue.wait_times().await.unwrap();
+        assert!(oldest >= newest + 40, "oldest {oldest}, newest {newest}");
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_gets_backlog_then_live_tokens() {
+        let queue = test_queue();
+        let id = queue
+            .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+            .await
+            .unwrap();
+        assert!(queue.subscribe(&id).await.is_none());
+
+        queue.dequeue().await.unwrap();
+        queue.publish_token(&id, "Hel").await;
+        let (so_far, mut tokens) = queue.subscribe(&id).await.unwrap();
+        assert_eq!(so_far, "Hel");
+
+        queue.publish_token(&id, "lo").await;
+        assert_eq!(tokens.recv().await.unwrap(), "lo");
+
+        // Completing the request closes the feed
+        queue.complete(&id, RequestOutcome::Cancelled).await;
+        assert!(matches!(
+            tokens.recv().await,
+            Err(broadcast::error::RecvError::Closed)
+        ));
+        assert!(queue.subscribe(&id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_clear_cancels_queued_and_in_flight_requests() {
+        let queue = test_queue();
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let id = queue
+                .enqueue(vec![], "model".to_string(), "prompt".to_string(), 0)
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+        let in_flight = queue.dequeue().await.unwrap();
+
+        assert_eq!(queue.clear().await, 3);
+        assert!(queue.is_empty().await);
+        assert!(in_flight.cancel_token.is_cancelled());
+        for id in &ids[1..] {
+            assert!(matches!(
+                queue.get_state(id).await,
+                RequestState::Completed(CompletedRequest {
+                    outcome: RequestOutcome::Cancelled,
+                    ..
+                })
+            ));
+        }
     }
 }
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Result shared between the leader of a flight and its followers
+pub type FlightResult<T> = Result<T, Arc<anyhow::Error>>;
+
+type Slot<T> = watch::Receiver<Option<FlightResult<T>>>;
+
+/// Collapses concurrent identical requests so only the first one reaches Ollama
+#[derive(Clone)]
+pub struct SingleFlight<T> {
+    in_flight: Arc<Mutex<HashMap<String, Slot<T>>>>,
+}
+
+/// Role of a caller joining a flight
+pub enum Flight<T> {
+    /// First caller for the key: run the request and `complete` the guard
+    Leader(FlightGuard<T>),
+    /// Another caller is already running the request
+    Follower(FlightWaiter<T>),
+}
+
+/// Held by the leader; followers are released when it completes or is dropped
+pub struct FlightGuard<T> {
+    key: String,
+    sender: watch::Sender<Option<FlightResult<T>>>,
+    in_flight: Arc<Mutex<HashMap<String, Slot<T>>>>,
+}
+
+pub struct FlightWaiter<T> {
+    receiver: Slot<T>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Become the leader for `key`, or follow the request already running for it
+    pub fn join(&self, key: &str) -> Flight<T> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(receiver) = in_flight.get(key) {
+            return Flight::Follower(FlightWaiter {
+                receiver: receiver.clone(),
+            });
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        in_flight.insert(key.to_string(), receiver);
+        Flight::Leader(FlightGuard {
+            key: key.to_string(),
+            sender,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+impl<T: Clone> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> FlightGuard<T> {
+    /// Publish the result to every follower
+    pub fn complete(self, result: FlightResult<T>) {
+        self.sender.send_replace(Some(result));
+    }
+}
+
+impl<T> Drop for FlightGuard<T> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let ours = in_flight
+            .get(&self.key)
+            .is_some_and(|receiver| receiver.same_channel(&self.sender.subscribe()));
+        if ours {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+impl<T: Clone> FlightWaiter<T> {
+    /// Wait for the leader's result; `None` if the leader gave up without one
+    pub async fn wait(mut self) -> Option<FlightResult<T>> {
+        match self.receiver.wait_for(Option::is_some).await {
+            Ok(result) => result.clone(),
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_followers_share_the_leaders_result() {
+        let flights = SingleFlight::<String>::new();
+
+        let Flight::Leader(guard) = flights.join("key") else {
+            panic!("first caller should lead");
+        };
+        let Flight::Follower(waiter) = flights.join("key") else {
+            panic!("second caller should follow");
+        };
+
+        guard.complete(Ok("answer".to_string()));
+        assert_eq!(waiter.wait().await.unwrap().unwrap(), "answer");
+
+        // The key is free again once the leader is done
+        assert!(matches!(flights.join("key"), Flight::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn test_followers_are_released_when_leader_drops() {
+        let flights = SingleFlight::<String>::new();
+
+        let leader = flights.join("key");
+        let Flight::Follower(waiter) = flights.join("key") else {
+            panic!("second caller should follow");
+        };
+
+        drop(leader);
+        assert!(waiter.wait().await.is_none());
+    }
+}
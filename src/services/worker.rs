@@ -0,0 +1,238 @@
+use crate::config::QueueConfig;
+use crate::models::{ChatMessage, ChatResponse, Role};
+use crate::services::queue::{QueuedRequest, RequestOutcome};
+use crate::services::{
+    stream_error_code, AuditLog, GenerationParams, OllamaClient, OllamaError, QueueService,
+};
+use crate::utils::template::UserWrap;
+use futures::stream::StreamExt;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+
+/// Background task that pulls requests off the queue and runs them against Ollama
+#[derive(Clone)]
+pub struct QueueWorker {
+    queue: Arc<QueueService>,
+    ollama: OllamaClient,
+    config: QueueConfig,
+    /// Applied when a request is sent to Ollama; the queue keeps the client's messages
+    user_wrap: UserWrap,
+    audit: AuditLog,
+}
+
+impl QueueWorker {
+    pub fn new(
+        queue: Arc<QueueService>,
+        ollama: OllamaClient,
+        config: QueueConfig,
+        user_wrap: UserWrap,
+        audit: AuditLog,
+    ) -> Self {
+        Self {
+            queue,
+            ollama,
+            config,
+            user_wrap,
+            audit,
+        }
+    }
+
+    /// Spawn the worker loop, processing at most `max_concurrent` requests at a time
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            let semaphore = Arc::new(Semaphore::new(self.config.max_concurrent.max(1)));
+
+            loop {
+                let permit = match semaphore.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                let request = self.queue.next_request().await;
+
+                let worker = self.clone();
+                tokio::spawn(async move {
+                    worker.process(request).await;
+                    drop(permit);
+                });
+            }
+        });
+    }
+
+    async fn process(&self, request: QueuedRequest) {
+        let span = request.span.clone();
+        self.generate(request).instrument(span).await;
+    }
+
+    async fn generate(&self, request: QueuedRequest) {
+        tracing::info!("⚙️  Processing queued request {}", request.id);
+        let duplicates = self.queue.take_duplicates(&request).await;
+        let requests: Vec<&QueuedRequest> = std::iter::once(&request).chain(&duplicates).collect();
+
+        let mut messages = request.messages.clone();
+        self.user_wrap.apply(&mut messages);
+
+        // Streamed, so clients of `/api/chat-queue/stream` see tokens as they arrive
+        let generation = async {
+            let mut stream = self
+                .ollama
+                .chat_completion_stream(
+                    &messages,
+                    &request.model,
+                    &request.system_prompt,
+                    GenerationParams::default(),
+                )
+                .await?;
+
+            let mut content = String::new();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                if let Some(message) = chunk.message.filter(|m| !m.content.is_empty()) {
+                    for request in &requests {
+                        self.queue
+                            .publish_token(&request.id, &message.content)
+                            .await;
+                    }
+                    content.push_str(&message.content);
+                }
+                if chunk.done {
+                    return Ok((content, chunk.done_reason));
+                }
+            }
+            Err(anyhow::Error::from(OllamaError::stream_ended()))
+        };
+
+        // Keep generating while any coalesced request still wants the reply
+        let all_cancelled =
+            futures::future::join_all(requests.iter().map(|r| r.cancel_token.cancelled()));
+
+        // Dropping the generation future aborts the in-flight HTTP request
+        let outcome = tokio::select! {
+            result = generation => match result {
+                Ok((content, done_reason)) => RequestOutcome::Success(ChatResponse {
+                    message: ChatMessage {
+                        role: Role::Assistant,
+                        content,
+                        tool_calls: None,
+                        images: None,
+                    },
+                    cached: Some(false),
+                    dropped_messages: None,
+                    fallback_used: None,
+                    messages: None,
+                    done_reason,
+                }),
+                Err(e) => {
+                    tracing::error!("Queued request {} failed: {}", request.id, e);
+                    RequestOutcome::Failed {
+                        error: e.to_string(),
+                        code: stream_error_code(&e),
+                    }
+                }
+            },
+            _ = all_cancelled => {
+                tracing::info!("🛑 Queued request {} cancelled mid-generation", request.id);
+                RequestOutcome::Cancelled
+            }
+        };
+
+        // Requests cancelled while sharing the generation don't get its result
+        for request in &requests {
+            let outcome = if request.cancel_token.is_cancelled() {
+                RequestOutcome::Cancelled
+            } else {
+                outcome.clone()
+            };
+            if let RequestOutcome::Success(response) = &outcome {
+                self.audit.record(
+                    &request.id,
+                    &request.model,
+                    &request.messages,
+                    &response.message.content,
+                    false,
+                );
+            }
+            self.queue
+                .complete(&request.id, outcome)
+                .instrument(request.span.clone())
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OllamaConfig;
+    use crate::handlers::chat::tests::spawn_ollama;
+    use crate::services::audit::tests::{read_records, temp_log};
+    use crate::services::queue::RequestState;
+    use axum::{routing::post, Json, Router};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_queued_replies_are_wrapped_audited_and_keep_the_done_reason() {
+        // Fake Ollama replying with the last message it was sent
+        let ollama_app = Router::new().route(
+            "/api/chat",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                let content =
+                    body["messages"].as_array().unwrap().last().unwrap()["content"].clone();
+                let reply = serde_json::json!({
+                    "message": {"role": "assistant", "content": content},
+                    "done": true,
+                    "done_reason": "length"
+                });
+                format!("{reply}\n")
+            }),
+        );
+        let url = spawn_ollama(ollama_app).await;
+
+        let config = QueueConfig::default();
+        let queue = Arc::new(QueueService::new(config.clone()));
+        let (audit, audit_path) = temp_log().await;
+        let ollama_config = OllamaConfig {
+            api_url: url,
+            max_retries: 0,
+            user_prefix: "<q>".to_string(),
+            user_suffix: "</q>".to_string(),
+            ..Default::default()
+        };
+        let user_wrap = UserWrap::new(&ollama_config);
+        let ollama = OllamaClient::new(ollama_config);
+        QueueWorker::new(queue.clone(), ollama, config, user_wrap, audit).spawn();
+
+        let message = ChatMessage {
+            role: Role::User,
+            content: "Hello".to_string(),
+            tool_calls: None,
+            images: None,
+        };
+        let id = queue
+            .enqueue(vec![message], "llama3".to_string(), String::new(), 0)
+            .await
+            .unwrap();
+
+        let mut outcome = None;
+        for _ in 0..100 {
+            if let RequestState::Completed(completed) = queue.get_state(&id).await {
+                outcome = Some(completed.outcome);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        match outcome {
+            Some(RequestOutcome::Success(response)) => {
+                assert_eq!(response.message.content, "<q>Hello</q>");
+                assert_eq!(response.done_reason.as_deref(), Some("length"));
+            }
+            other => panic!("expected a successful reply, got {other:?}"),
+        }
+
+        let records = read_records(&audit_path, 1).await;
+        assert_eq!(records[0]["request_id"], id);
+        assert_eq!(records[0]["messages"][0]["content"], "Hello");
+        assert_eq!(records[0]["response"], "<q>Hello</q>");
+        tokio::fs::remove_file(&audit_path).await.unwrap();
+    }
+}
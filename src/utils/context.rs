@@ -0,0 +1,144 @@
+use crate::config::LimitsConfig;
+use crate::models::{ChatMessage, Role};
+
+/// Rough token count for budgeting without a tokenizer: about four characters per token
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Drop the oldest turns until the conversation fits `max_context_messages` and
+/// `max_context_tokens` (0 disables either), counting the system prompt against the token
+/// budget. A turn is a user message with the assistant and tool messages answering it, and is
+/// dropped whole so no reply or tool result is left without its question. System messages and
+/// the latest turn are always kept. Returns how many messages were dropped.
+pub fn trim_context(
+    messages: &mut Vec<ChatMessage>,
+    system_prompt: &str,
+    limits: &LimitsConfig,
+) -> usize {
+    let mut count = messages.len();
+    let mut tokens = estimate_tokens(system_prompt)
+        + messages
+            .iter()
+            .map(|m| estimate_tokens(&m.content))
+            .sum::<usize>();
+    let over_budget = |count: usize, tokens: usize| {
+        (limits.max_context_messages > 0 && count > limits.max_context_messages)
+            || (limits.max_context_tokens > 0 && tokens > limits.max_context_tokens)
+    };
+
+    // Each user message opens a turn; anything before the first one is a turn of its own
+    let mut turn = 0;
+    let turns: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .map(|(index, message)| {
+            if message.role == Role::User && index > 0 {
+                turn += 1;
+            }
+            turn
+        })
+        .collect();
+    let latest = turns.last().copied().unwrap_or(0);
+
+    let mut dropped = vec![false; messages.len()];
+    for current in 0..latest {
+        if !over_budget(count, tokens) {
+            break;
+        }
+        for (index, message) in messages.iter().enumerate() {
+            if turns[index] == current && message.role != Role::System {
+                dropped[index] = true;
+                count -= 1;
+                tokens -= estimate_tokens(&message.content);
+            }
+        }
+    }
+
+    let mut index = 0;
+    messages.retain(|_| {
+        index += 1;
+        !dropped[index - 1]
+    });
+    dropped.iter().filter(|&&d| d).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+            tool_calls: None,
+            images: None,
+        }
+    }
+
+    #[test]
+    fn test_oldest_turns_are_dropped_first() {
+        let conversation = vec![
+            message(Role::System, "Be brief."),
+            message(Role::User, "first question"),
+            message(Role::Assistant, "first answer"),
+            message(Role::User, "second question"),
+        ];
+
+        // Dropping one message would strand "first answer", so the whole turn goes
+        let limits = LimitsConfig {
+            max_context_messages: 3,
+            ..Default::default()
+        };
+        let mut messages = conversation.clone();
+        assert_eq!(trim_context(&mut messages, "", &limits), 2);
+        let kept: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(kept, ["Be brief.", "second question"]);
+
+        // Each message here is 3-4 tokens, and the system prompt counts too
+        let limits = LimitsConfig {
+            max_context_tokens: 12,
+            ..Default::default()
+        };
+        let mut messages = conversation.clone();
+        assert_eq!(trim_context(&mut messages, "Reply in English", &limits), 2);
+        let kept: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(kept, ["Be brief.", "second question"]);
+
+        // The latest message is kept even when it alone exceeds the budget
+        let limits = LimitsConfig {
+            max_context_tokens: 1,
+            ..Default::default()
+        };
+        let mut messages = conversation.clone();
+        assert_eq!(trim_context(&mut messages, "", &limits), 2);
+        assert_eq!(messages.last().unwrap().content, "second question");
+
+        let mut messages = conversation.clone();
+        assert_eq!(trim_context(&mut messages, "", &LimitsConfig::default()), 0);
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn test_tool_results_go_with_their_turn() {
+        let conversation = vec![
+            message(Role::User, "weather in Paris?"),
+            message(Role::Assistant, "calling get_weather"),
+            message(Role::Tool, "18C, cloudy"),
+            message(Role::Assistant, "It is 18C."),
+            message(Role::User, "and in Rome?"),
+            message(Role::Assistant, "calling get_weather"),
+            message(Role::Tool, "24C, sunny"),
+        ];
+
+        // The latest turn is kept whole, tool result included, even over the limit
+        let limits = LimitsConfig {
+            max_context_messages: 2,
+            ..Default::default()
+        };
+        let mut messages = conversation.clone();
+        assert_eq!(trim_context(&mut messages, "", &limits), 4);
+        let kept: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(kept, ["and in Rome?", "calling get_weather", "24C, sunny"]);
+    }
+}
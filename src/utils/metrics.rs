@@ -0,0 +1,52 @@
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+/// Cache lookups served from cache, labelled by `cache`
+pub const CACHE_HITS: &str = "cache_hits_total";
+/// Cache lookups that missed, labelled by `cache`
+pub const CACHE_MISSES: &str = "cache_misses_total";
+/// Entries dropped from the in-memory cache, labelled by `cache` and `cause`
+pub const CACHE_EVICTIONS: &str = "cache_evictions_total";
+/// Requests waiting in the queue
+pub const QUEUE_LENGTH: &str = "queue_length";
+/// Requests currently waiting on Ollama
+pub const OLLAMA_IN_FLIGHT: &str = "ollama_requests_in_flight";
+/// Time until Ollama responds, including retries
+pub const OLLAMA_LATENCY: &str = "ollama_request_duration_seconds";
+/// Number of requests dispatched per batch
+pub const BATCH_SIZE: &str = "batch_size";
+/// Requests collapsed into an identical in-flight or batched request
+pub const BATCH_DEDUPLICATED: &str = "batch_deduplicated_requests_total";
+
+const LATENCY_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0];
+
+fn builder() -> Result<PrometheusBuilder, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(OLLAMA_LATENCY.to_string()), LATENCY_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full(BATCH_SIZE.to_string()), BATCH_SIZE_BUCKETS)
+}
+
+/// Install the global Prometheus recorder and return a handle for rendering `/metrics`
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    builder()?.install_recorder()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histograms_render_with_buckets() {
+        let recorder = builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!(OLLAMA_LATENCY).record(0.3);
+            metrics::counter!(CACHE_HITS, "cache" => "response").increment(1);
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains("ollama_request_duration_seconds_bucket{le=\"0.5\"} 1"));
+        assert!(rendered.contains("cache_hits_total{cache=\"response\"} 1"));
+    }
+}
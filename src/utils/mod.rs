@@ -1,2 +1,7 @@
 // Utility modules can be added here
 // For example: logging helpers, validation, etc.
+pub mod context;
+pub mod metrics;
+pub mod template;
+pub mod think;
+pub mod validation;
@@ -0,0 +1,132 @@
+use crate::config::OllamaConfig;
+use crate::models::{ChatMessage, Role};
+use chrono::Utc;
+use std::collections::HashMap;
+
+/// Replace `{{name}}` placeholders in a system prompt. `date` (YYYY-MM-DD) and `time`
+/// (HH:MM, UTC) are built in; `vars` add to or override them. Unknown placeholders are
+/// left as they are.
+pub fn render_prompt(template: &str, vars: Option<&HashMap<String, String>>) -> String {
+    if !template.contains("{{") {
+        return template.to_string();
+    }
+
+    let now = Utc::now();
+    let lookup = |name: &str| -> Option<String> {
+        if let Some(value) = vars.and_then(|vars| vars.get(name)) {
+            return Some(value.clone());
+        }
+        match name {
+            "date" => Some(now.format("%Y-%m-%d").to_string()),
+            "time" => Some(now.format("%H:%M").to_string()),
+            _ => None,
+        }
+    };
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let end = start + 2 + len + 2;
+
+        rendered.push_str(&rest[..start]);
+        match lookup(name.trim()) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// `ollama.user_prefix` and `ollama.user_suffix`, wrapped around user messages
+#[derive(Debug, Clone, Default)]
+pub struct UserWrap {
+    prefix: String,
+    suffix: String,
+}
+
+impl UserWrap {
+    pub fn new(config: &OllamaConfig) -> Self {
+        Self {
+            prefix: config.user_prefix.clone(),
+            suffix: config.user_suffix.clone(),
+        }
+    }
+
+    /// Wrap the content of every user message, leaving other roles alone
+    pub(crate) fn apply(&self, messages: &mut [ChatMessage]) {
+        if self.prefix.is_empty() && self.suffix.is_empty() {
+            return;
+        }
+        for message in messages.iter_mut().filter(|m| m.role == Role::User) {
+            message.content = format!("{}{}{}", self.prefix, message.content, self.suffix);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prompt_substitutes_variables() {
+        let vars = HashMap::from([("user".to_string(), "Ada".to_string())]);
+        assert_eq!(
+            render_prompt("Hello {{user}}, {{ user }}!", Some(&vars)),
+            "Hello Ada, Ada!"
+        );
+
+        let today = Utc::now().format("%Y-%m-%d").to_string();
+        assert_eq!(
+            render_prompt("Today is {{date}}.", None),
+            format!("Today is {today}.")
+        );
+
+        // Request variables win over built-ins
+        let vars = HashMap::from([("date".to_string(), "yesterday".to_string())]);
+        assert_eq!(render_prompt("{{date}}", Some(&vars)), "yesterday");
+
+        assert_eq!(
+            render_prompt("{{unknown}} and {{", None),
+            "{{unknown}} and {{"
+        );
+        assert_eq!(render_prompt("No placeholders", None), "No placeholders");
+    }
+
+    #[test]
+    fn test_user_wrap_only_touches_user_messages() {
+        let wrap = UserWrap::new(&OllamaConfig {
+            user_prefix: "[safe] ".to_string(),
+            user_suffix: " [/safe]".to_string(),
+            ..Default::default()
+        });
+        let message = |role, content: &str| ChatMessage {
+            role,
+            content: content.to_string(),
+            tool_calls: None,
+            images: None,
+        };
+        let mut messages = vec![
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello"),
+            message(Role::User, "Bye"),
+        ];
+        wrap.apply(&mut messages);
+
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            ["[safe] Hi [/safe]", "Hello", "[safe] Bye [/safe]"]
+        );
+
+        // Empty strings leave messages untouched
+        let mut unchanged = vec![message(Role::User, "Hi")];
+        UserWrap::default().apply(&mut unchanged);
+        assert_eq!(unchanged[0].content, "Hi");
+    }
+}
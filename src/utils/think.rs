@@ -0,0 +1,153 @@
+use crate::config::OllamaConfig;
+
+/// Delimiters of a reasoning block to remove from replies
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThinkTags {
+    pub open: String,
+    pub close: String,
+}
+
+impl ThinkTags {
+    /// `None` when stripping is disabled or the tags are unusable
+    pub fn from_config(config: &OllamaConfig) -> Option<Self> {
+        let usable = !config.think_open_tag.is_empty() && !config.think_close_tag.is_empty();
+        (config.strip_think_tags && usable).then(|| Self {
+            open: config.think_open_tag.clone(),
+            close: config.think_close_tag.clone(),
+        })
+    }
+
+    /// Remove every reasoning block from a complete reply
+    pub fn strip(&self, content: &str) -> String {
+        let mut stripper = TagStripper::new(self.clone());
+        let mut cleaned = stripper.push(content);
+        cleaned.push_str(&stripper.finish());
+        cleaned
+    }
+}
+
+/// Removes reasoning blocks from a streamed reply, holding back text while inside a
+/// block or while a chunk ends in what may be the start of a tag
+#[derive(Debug)]
+pub struct TagStripper {
+    tags: ThinkTags,
+    pending: String,
+    inside: bool,
+    /// Drop the whitespace that usually follows a closing tag
+    trim_next: bool,
+}
+
+impl TagStripper {
+    pub fn new(tags: ThinkTags) -> Self {
+        Self {
+            tags,
+            pending: String::new(),
+            inside: false,
+            trim_next: false,
+        }
+    }
+
+    /// Feed the next chunk and return the text that is safe to emit
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.pending.push_str(chunk);
+        let mut out = String::new();
+
+        loop {
+            if self.inside {
+                match self.pending.find(&self.tags.close) {
+                    Some(index) => {
+                        self.pending.drain(..index + self.tags.close.len());
+                        self.inside = false;
+                        self.trim_next = true;
+                    }
+                    None => {
+                        // Only a possible start of the closing tag is worth keeping
+                        let keep = partial_suffix_len(&self.pending, &self.tags.close);
+                        self.pending.drain(..self.pending.len() - keep);
+                        break;
+                    }
+                }
+            } else {
+                let (emit, rest) = match self.pending.find(&self.tags.open) {
+                    Some(index) => (index, index + self.tags.open.len()),
+                    None => {
+                        let keep = partial_suffix_len(&self.pending, &self.tags.open);
+                        let emit = self.pending.len() - keep;
+                        (emit, emit)
+                    }
+                };
+                self.emit(&mut out, emit);
+                let found = rest > emit;
+                self.pending.drain(..rest);
+                if !found {
+                    break;
+                }
+                self.inside = true;
+            }
+        }
+
+        out
+    }
+
+    /// Flush what is left once the reply is complete; an unclosed block is dropped
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        if !self.inside {
+            let len = self.pending.len();
+            self.emit(&mut out, len);
+        }
+        self.pending.clear();
+        out
+    }
+
+    fn emit(&mut self, out: &mut String, len: usize) {
+        let mut text = &self.pending[..len];
+        if self.trim_next {
+            text = text.trim_start();
+            self.trim_next = text.is_empty();
+        }
+        out.push_str(text);
+    }
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of `tag`
+fn partial_suffix_len(text: &str, tag: &str) -> usize {
+    (1..tag.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| tag.is_char_boundary(len) && text.ends_with(&tag[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> ThinkTags {
+        ThinkTags {
+            open: "<think>".to_string(),
+            close: "</think>".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_strip_removes_reasoning_blocks() {
+        let tags = tags();
+        assert_eq!(
+            tags.strip("<think>\nLet me see...\n</think>\n\nThe answer is 4."),
+            "The answer is 4."
+        );
+        assert_eq!(tags.strip("No reasoning here"), "No reasoning here");
+        assert_eq!(tags.strip("a <think>x</think>b"), "a b");
+        assert_eq!(tags.strip("<think>never closed"), "");
+        assert_eq!(tags.strip("1 < 2"), "1 < 2");
+    }
+
+    #[test]
+    fn test_streamed_tags_split_across_chunks() {
+        let mut stripper = TagStripper::new(tags());
+        let chunks = ["<th", "ink>hmm", "</thi", "nk>", "\n\nHel", "lo <", "b>"];
+        let mut out: String = chunks.iter().map(|chunk| stripper.push(chunk)).collect();
+        out.push_str(&stripper.finish());
+        assert_eq!(out, "Hello <b>");
+    }
+}
@@ -0,0 +1,214 @@
+use crate::config::LimitsConfig;
+use crate::models::{ChatMessage, OllamaOptions};
+use thiserror::Error;
+
+/// A conversation that is malformed or exceeds the configured size limits
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationError {
+    #[error("messages must be a non-empty array")]
+    NoMessages,
+    #[error("Too many messages: {count} exceeds the limit of {limit}")]
+    TooManyMessages { count: usize, limit: usize },
+    #[error("Message {index} is too long: {chars} characters exceeds the limit of {limit}")]
+    MessageTooLong {
+        index: usize,
+        chars: usize,
+        limit: usize,
+    },
+    #[error("Conversation is too long: {chars} characters exceeds the limit of {limit}")]
+    ConversationTooLong { chars: usize, limit: usize },
+    #[error("Prompt is too long: {chars} characters exceeds the limit of {limit}")]
+    PromptTooLong { chars: usize, limit: usize },
+    #[error("model `{model}` is not available; expected one of: {allowed}")]
+    ModelNotAllowed { model: String, allowed: String },
+    #[error("num_ctx {requested} exceeds the limit of {limit}")]
+    ContextTooLarge { requested: u32, limit: u32 },
+    #[error("Too many requests: {count} exceeds the limit of {limit}")]
+    TooManyRequests { count: usize, limit: usize },
+}
+
+/// Check that messages are well-formed and within the configured limits
+pub fn validate_messages(
+    messages: &[ChatMessage],
+    limits: &LimitsConfig,
+) -> Result<(), ValidationError> {
+    if messages.is_empty() {
+        return Err(ValidationError::NoMessages);
+    }
+    if messages.len() > limits.max_messages {
+        return Err(ValidationError::TooManyMessages {
+            count: messages.len(),
+            limit: limits.max_messages,
+        });
+    }
+
+    let mut total = 0;
+    for (index, message) in messages.iter().enumerate() {
+        let chars = message.content.chars().count();
+        if chars > limits.max_message_chars {
+            return Err(ValidationError::MessageTooLong {
+                index,
+                chars,
+                limit: limits.max_message_chars,
+            });
+        }
+        total += chars;
+    }
+
+    if total > limits.max_total_chars {
+        return Err(ValidationError::ConversationTooLong {
+            chars: total,
+            limit: limits.max_total_chars,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check a requested model against `ollama.allowed_models`; an empty list allows any
+pub fn validate_model(model: &str, allowed: &[String]) -> Result<(), ValidationError> {
+    if allowed.is_empty() || allowed.iter().any(|m| m == model) {
+        return Ok(());
+    }
+    Err(ValidationError::ModelNotAllowed {
+        model: model.to_string(),
+        allowed: allowed.join(", "),
+    })
+}
+
+/// Check per-request generation options against the configured limits
+pub fn validate_options(
+    options: Option<&OllamaOptions>,
+    limits: &LimitsConfig,
+) -> Result<(), ValidationError> {
+    let requested = options.and_then(|o| o.num_ctx);
+    match requested {
+        Some(requested) if limits.max_num_ctx > 0 && requested > limits.max_num_ctx => {
+            Err(ValidationError::ContextTooLarge {
+                requested,
+                limit: limits.max_num_ctx,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check a raw prompt against the per-message length limit
+pub fn validate_prompt(prompt: &str, limits: &LimitsConfig) -> Result<(), ValidationError> {
+    let chars = prompt.chars().count();
+    if chars > limits.max_message_chars {
+        return Err(ValidationError::PromptTooLong {
+            chars,
+            limit: limits.max_message_chars,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+
+    fn message(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: Role::User,
+            content: content.to_string(),
+            tool_calls: None,
+            images: None,
+        }
+    }
+
+    #[test]
+    fn test_oversized_requests_are_rejected() {
+        let limits = LimitsConfig {
+            max_messages: 3,
+            max_message_chars: 10,
+            max_total_chars: 15,
+            ..Default::default()
+        };
+
+        assert!(validate_messages(&[message("hello"), message("world")], &limits).is_ok());
+
+        let too_many = vec![message("hi"); 4];
+        assert_eq!(
+            validate_messages(&too_many, &limits),
+            Err(ValidationError::TooManyMessages { count: 4, limit: 3 })
+        );
+
+        let too_long = [message("hi"), message("this is far too long")];
+        assert_eq!(
+            validate_messages(&too_long, &limits),
+            Err(ValidationError::MessageTooLong {
+                index: 1,
+                chars: 20,
+                limit: 10
+            })
+        );
+
+        let too_much = [message("0123456789"), message("0123456789")];
+        assert_eq!(
+            validate_messages(&too_much, &limits),
+            Err(ValidationError::ConversationTooLong {
+                chars: 20,
+                limit: 15
+            })
+        );
+
+        assert_eq!(
+            validate_messages(&[], &limits),
+            Err(ValidationError::NoMessages)
+        );
+
+        assert!(validate_prompt("short", &limits).is_ok());
+        assert_eq!(
+            validate_prompt("this is far too long", &limits),
+            Err(ValidationError::PromptTooLong {
+                chars: 20,
+                limit: 10
+            })
+        );
+    }
+
+    #[test]
+    fn test_models_outside_the_allowlist_are_rejected() {
+        let allowed = vec!["llama3".to_string(), "mistral".to_string()];
+        assert!(validate_model("llama3", &allowed).is_ok());
+        assert_eq!(
+            validate_model("lama3", &allowed).unwrap_err().to_string(),
+            "model `lama3` is not available; expected one of: llama3, mistral"
+        );
+
+        // No allowlist keeps accepting any model
+        assert!(validate_model("anything", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_oversized_context_windows_are_rejected() {
+        let limits = LimitsConfig {
+            max_num_ctx: 8192,
+            ..Default::default()
+        };
+        let options = |num_ctx| OllamaOptions {
+            num_ctx: Some(num_ctx),
+            ..Default::default()
+        };
+
+        assert!(validate_options(None, &limits).is_ok());
+        assert!(validate_options(Some(&options(8192)), &limits).is_ok());
+        assert_eq!(
+            validate_options(Some(&options(65536)), &limits),
+            Err(ValidationError::ContextTooLarge {
+                requested: 65536,
+                limit: 8192,
+            })
+        );
+
+        // 0 lifts the limit
+        let unlimited = LimitsConfig {
+            max_num_ctx: 0,
+            ..Default::default()
+        };
+        assert!(validate_options(Some(&options(1 << 20)), &unlimited).is_ok());
+    }
+}